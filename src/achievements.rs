@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::BoxedFuture;
+use serde::{Deserialize, Serialize};
+use crate::console::ConsoleCommandsExt;
+use crate::enemy::EnemyKind;
+use crate::manager::{GameState, RunResult};
+use crate::settings::{config_dir, Difficulty};
+use crate::stats::{RunStats, StatsReportLabel};
+
+const DEFINITIONS_PATH: &str = "achievements/default.achievements.ron";
+const PROGRESS_FILE: &str    = "achievements.toml";
+
+/// An achievement, as defined in a `.achievements.ron` file
+#[derive(Debug, Clone, Deserialize)]
+pub struct Achievement {
+    /// Stable name progress is saved under
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub condition: Condition,
+}
+
+/// What has to happen for an [`Achievement`] to unlock, checked against [`RunStats`]
+#[derive(Debug, Clone, Deserialize)]
+pub enum Condition {
+    /// Kills over every run, of one kind such as `enemy_grunt` or of any kind
+    TotalKills { count: u32, #[serde(default)] kind: Option<String> },
+    /// Waves finished in one run without taking damage. A campaign level's enemies and its boss
+    /// fight are a wave each, as counted by [`RunStats::flawless_waves`].
+    FlawlessWaves(u32),
+    Combo(u32),
+    Score(i32),
+    /// Accuracy over a whole run of at least `shots` shots
+    Accuracy { min: f32, shots: u32 },
+    /// Complete the level, on `difficulty` or harder if given
+    Complete { #[serde(default)] difficulty: Option<Difficulty> },
+}
+
+impl Condition {
+    /// True if the condition holds. Conditions over a whole run are only met once it has `finished`.
+    pub fn met(&self, stats: &RunStats, run: &RunResult, finished: bool, progress: &AchievementProgress) -> bool {
+        match self {
+            Condition::TotalKills { count, kind } => {
+                let kills = |kills: &BTreeMap<String, u32>| match kind {
+                    Some(kind) => kills.get(kind).copied().unwrap_or(0),
+                    None => kills.values().sum(),
+                };
+                // Kills are added to the saved progress when the run finishes
+                let run_kills = if finished { 0 } else { kills(&stats.kills) };
+                kills(&progress.kills) + run_kills >= *count
+            }
+            Condition::FlawlessWaves(count) => stats.flawless_waves >= *count,
+            Condition::Combo(count) => stats.max_combo >= *count,
+            Condition::Score(score) => run.score >= *score,
+            Condition::Accuracy { min, shots } => finished && stats.shots >= *shots && stats.accuracy() >= *min,
+            Condition::Complete { difficulty } => finished && run.completed && difficulty.map_or(true, |difficulty| {
+                let rank = |difficulty: Difficulty| Difficulty::ALL.iter().position(|d| *d == difficulty);
+                rank(stats.difficulty) >= rank(difficulty)
+            }),
+        }
+    }
+}
+
+/// Achievement definitions, loaded from a `.achievements.ron` file
+#[derive(Debug, TypeUuid)]
+#[uuid = "b5d0e8a3-41f2-4c6e-9d7a-2e8f5c1b3a94"]
+pub struct AchievementList {
+    pub achievements: Vec<Achievement>,
+}
+
+impl AchievementList {
+    fn from_definitions(achievements: Vec<Achievement>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut ids = BTreeSet::new();
+        for achievement in achievements.iter() {
+            if !ids.insert(achievement.id.as_str()) {
+                errors.push(format!("{}: id is used more than once", achievement.id));
+            }
+            if let Condition::TotalKills { kind: Some(kind), .. } = &achievement.condition {
+                if !kind.starts_with("boss_") && !EnemyKind::ALL.iter().any(|enemy| enemy.name() == kind) {
+                    errors.push(format!("{}: unknown kill kind '{}'", achievement.id, kind));
+                }
+            }
+        }
+        if errors.is_empty() { Ok(Self { achievements }) } else { Err(errors) }
+    }
+}
+
+#[derive(Default)]
+pub struct AchievementLoader;
+
+impl AssetLoader for AchievementLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let definitions: Vec<Achievement> = ron::de::from_bytes(bytes)?;
+            match AchievementList::from_definitions(definitions) {
+                Ok(list) => {
+                    load_context.set_default_asset(LoadedAsset::new(list));
+                    Ok(())
+                }
+                Err(errors) => Err(anyhow::anyhow!("Invalid achievements in {}:\n  {}", load_context.path().display(), errors.join("\n  "))),
+            }
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["achievements.ron"]
+    }
+}
+
+/// Achievements unlocked and totals kept across runs, persisted to `achievements.toml` in [`config_dir`]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AchievementProgress {
+    #[serde(default)]
+    pub unlocked: BTreeSet<String>,
+    /// Kills over every finished run, by the same names as [`RunStats::kills`]
+    #[serde(default)]
+    pub kills: BTreeMap<String, u32>,
+}
+
+impl AchievementProgress {
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(PROGRESS_FILE))
+    }
+
+    /// Loads progress from the config directory. Missing or unreadable progress starts afresh.
+    pub fn load() -> AchievementProgress {
+        let source = match AchievementProgress::path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(source) => source,
+            None => return AchievementProgress::default(),
+        };
+        toml::from_str(&source).unwrap_or_else(|err| {
+            warn!("Achievement progress is invalid, starting afresh: {}", err);
+            AchievementProgress::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = AchievementProgress::path().ok_or_else(|| String::from("no config directory"))?;
+        let source = toml::to_string_pretty(self).map_err(|err| err.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        fs::write(&path, source).map_err(|err| err.to_string())
+    }
+}
+
+/// Loaded definitions and the player's progress through them
+#[derive(Default)]
+pub struct Achievements {
+    handle: Handle<AchievementList>,
+    pub definitions: Vec<Achievement>,
+    pub progress: AchievementProgress,
+    /// True when progress has changed since it was last saved
+    dirty: bool,
+}
+
+impl Achievements {
+    /// Unlocks every achievement whose condition is now met, returning those newly unlocked
+    pub fn update(&mut self, stats: &RunStats, run: &RunResult, finished: bool) -> Vec<Achievement> {
+        let progress = &self.progress;
+        let unlocked: Vec<Achievement> = self.definitions.iter()
+            .filter(|achievement| !progress.unlocked.contains(&achievement.id))
+            .filter(|achievement| achievement.condition.met(stats, run, finished, progress))
+            .cloned()
+            .collect();
+        for achievement in unlocked.iter() {
+            self.progress.unlocked.insert(achievement.id.clone());
+        }
+        self.dirty |= !unlocked.is_empty();
+        unlocked
+    }
+
+    /// Adds a finished run's kills to the totals kept across runs
+    pub fn add_run(&mut self, stats: &RunStats) {
+        for (kind, count) in stats.kills.iter() {
+            *self.progress.kills.entry(kind.clone()).or_default() += count;
+        }
+        self.dirty = true;
+    }
+}
+
+/// Sent when an achievement is unlocked
+pub struct AchievementUnlockedEvent {
+    pub id: String,
+    pub name: String,
+}
+
+fn achievement_load_sys(mut achievements: ResMut<Achievements>, asset_server: Res<AssetServer>) {
+    achievements.handle = asset_server.load(DEFINITIONS_PATH);
+    achievements.progress = AchievementProgress::load();
+}
+
+fn achievement_reload_sys(
+    mut achievements: ResMut<Achievements>,
+    mut list_events: EventReader<AssetEvent<AchievementList>>,
+    lists: Res<Assets<AchievementList>>) {
+    for event in list_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } if *handle == achievements.handle => {}
+            _ => continue,
+        }
+        if let Some(list) = lists.get(&achievements.handle) {
+            achievements.definitions = list.achievements.clone();
+            info!("Loaded {} achievements", achievements.definitions.len());
+        }
+    }
+}
+
+fn send_unlocks(unlocked: Vec<Achievement>, unlocked_events: &mut EventWriter<AchievementUnlockedEvent>) {
+    for achievement in unlocked {
+        info!("Achievement unlocked: {}", achievement.name);
+        unlocked_events.send(AchievementUnlockedEvent { id: achievement.id, name: achievement.name });
+    }
+}
+
+/// Unlocks achievements as the run's stats change
+fn achievement_check_sys(
+    mut achievements: ResMut<Achievements>,
+    stats: Res<RunStats>,
+    run: Res<RunResult>,
+    mut unlocked_events: EventWriter<AchievementUnlockedEvent>) {
+    if !stats.is_changed() && !run.is_changed() { return }
+    let unlocked = achievements.update(&stats, &run, false);
+    send_unlocks(unlocked, &mut unlocked_events);
+}
+
+/// Checks conditions over the whole run once its stats are final, and keeps its kills
+fn achievement_run_end_sys(
+    mut achievements: ResMut<Achievements>,
+    stats: Res<RunStats>,
+    run: Res<RunResult>,
+    mut unlocked_events: EventWriter<AchievementUnlockedEvent>) {
+    achievements.add_run(&stats);
+    let unlocked = achievements.update(&stats, &run, true);
+    send_unlocks(unlocked, &mut unlocked_events);
+}
+
+fn achievement_save_sys(mut achievements: ResMut<Achievements>) {
+    if !achievements.dirty { return }
+    achievements.dirty = false;
+    if let Err(err) = achievements.progress.save() {
+        warn!("Could not save achievement progress: {}", err);
+    }
+}
+
+fn achievements_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    match args.get(0).copied() {
+        None | Some("list") => {
+            let achievements = world.get_resource::<Achievements>().ok_or("No achievements")?;
+            let lines: Vec<String> = achievements.definitions.iter()
+                .map(|achievement| {
+                    let mark = if achievements.progress.unlocked.contains(&achievement.id) { "x" } else { " " };
+                    format!("[{}] {}: {}", mark, achievement.name, achievement.description)
+                })
+                .collect();
+            Ok(lines.join("\n"))
+        }
+        Some("reset") => {
+            let mut achievements = world.get_resource_mut::<Achievements>().ok_or("No achievements")?;
+            achievements.progress = AchievementProgress::default();
+            achievements.dirty = true;
+            Ok(String::from("Achievement progress cleared"))
+        }
+        _ => Err(String::from("Expected list or reset")),
+    }
+}
+
+pub struct AchievementPlugin;
+
+impl Plugin for AchievementPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_asset::<AchievementList>()
+            .init_asset_loader::<AchievementLoader>()
+            .init_resource::<Achievements>()
+            .add_event::<AchievementUnlockedEvent>()
+            .add_startup_system(achievement_load_sys)
+            .add_system(achievement_reload_sys)
+            .add_system(achievement_save_sys)
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(achievement_check_sys))
+            .add_system_set(SystemSet::on_enter(GameState::GameOver)
+                .with_system(achievement_run_end_sys.after(StatsReportLabel)))
+            .add_console_command("achievements [list|reset]", "List achievements or clear progress", achievements_command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::boss::BossDefeatedEvent;
+    use crate::common::{DeathEvent, GameTime, Victim};
+    use crate::enemy::Enemy;
+    use crate::manager::CurrentLevel;
+    use crate::projectile::ProjectileHitEvent;
+    use crate::stats::stats_collect_sys;
+    use super::*;
+
+    /// Orders the stats collector before the unlock check in [`CheckWorld`]
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+    struct CollectLabel;
+
+    /// Definitions used by [`CheckWorld`], independent of the shipped data file
+    const CHECK_DEFINITIONS: &str = r#"[
+        (id: "kills", name: "Kills", description: "", condition: TotalKills(count: 3)),
+        (id: "grunts", name: "Grunts", description: "", condition: TotalKills(count: 2, kind: Some("enemy_grunt"))),
+        (id: "combo", name: "Combo", description: "", condition: Combo(3)),
+        (id: "flawless", name: "Flawless", description: "", condition: FlawlessWaves(1)),
+        (id: "flawless_2", name: "Flawless 2", description: "", condition: FlawlessWaves(2)),
+        (id: "insane", name: "Insane", description: "", condition: Complete(difficulty: Some(Insane))),
+    ]"#;
+
+    /// A world of its own running the stats collector and unlock check on synthetic gameplay
+    /// events, using [`CHECK_DEFINITIONS`]. Progress isn't saved.
+    struct CheckWorld {
+        world: World,
+        stage: SystemStage,
+        player: Entity,
+    }
+
+    impl CheckWorld {
+        fn new() -> Result<Self, String> {
+            let definitions: Vec<Achievement> = ron::de::from_str(CHECK_DEFINITIONS).map_err(|err| err.to_string())?;
+            let definitions = AchievementList::from_definitions(definitions).map_err(|errors| errors.join(", "))?.achievements;
+
+            let mut world = World::new();
+            world.insert_resource(Achievements { definitions, ..Default::default() });
+            world.insert_resource(RunStats::new(Difficulty::Insane));
+            world.insert_resource(RunResult::default());
+            world.insert_resource(GameTime::fixed(std::time::Duration::from_millis(100)));
+            world.insert_resource(CurrentLevel { handle: Default::default(), wave: 1, spawned: true, boss_spawned: false, pending: false });
+            world.insert_resource(Events::<ProjectileHitEvent>::default());
+            world.insert_resource(Events::<DeathEvent>::default());
+            world.insert_resource(Events::<BossDefeatedEvent>::default());
+            world.insert_resource(Events::<AchievementUnlockedEvent>::default());
+            let stage = SystemStage::single_threaded()
+                .with_system(stats_collect_sys.label(CollectLabel))
+                .with_system(achievement_check_sys.after(CollectLabel));
+            let player = world.spawn().insert(crate::player::Player::default()).id();
+            Ok(Self { world, stage, player })
+        }
+
+        /// Runs one frame of wave `wave` in which `kills` are destroyed and the player takes `damage`,
+        /// returning the ids unlocked
+        fn step(&mut self, kills: &[EnemyKind], damage: i32, wave: u32) -> Vec<String> {
+            let world = &mut self.world;
+            for kind in kills {
+                let entity = world.spawn().insert(Enemy { kind: *kind, ..Default::default() }).id();
+                world.get_resource_mut::<Events<DeathEvent>>().unwrap().send(DeathEvent { entity, victim: Victim::Enemy(*kind), position: Vec3::ZERO, life_lost: false });
+            }
+            if damage > 0 {
+                world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().send(ProjectileHitEvent {
+                    projectile: self.player, other: self.player, origin: None, owner: Default::default(), damage, position: Vec3::ZERO,
+                });
+            }
+            world.get_resource_mut::<CurrentLevel>().unwrap().wave = wave;
+            self.stage.run(world);
+            world.get_resource_mut::<Events<DeathEvent>>().unwrap().update();
+            world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().update();
+
+            let mut events = world.get_resource_mut::<Events<AchievementUnlockedEvent>>().unwrap();
+            let ids = events.drain().map(|event| event.id).collect();
+            ids
+        }
+
+        /// Completes the run, returning the ids unlocked and the kills then kept across runs
+        fn complete(&mut self) -> (Vec<String>, u32) {
+            let world = &mut self.world;
+            world.get_resource_mut::<RunStats>().unwrap().finish_wave();
+            world.get_resource_mut::<RunResult>().unwrap().completed = true;
+            world.resource_scope(|world, mut achievements: Mut<Achievements>| {
+                let stats = world.get_resource::<RunStats>().unwrap();
+                achievements.add_run(stats);
+                let ids = achievements.update(stats, world.get_resource::<RunResult>().unwrap(), true).into_iter().map(|achievement| achievement.id).collect();
+                (ids, achievements.progress.kills.values().sum())
+            })
+        }
+    }
+
+    #[test]
+    fn unlocks_from_gameplay_events() {
+        let mut check = CheckWorld::new().unwrap();
+        assert_eq!(check.step(&[EnemyKind::Grunt, EnemyKind::Grunt], 0, 1), ["grunts"]);
+        assert_eq!(check.step(&[EnemyKind::Sniper], 0, 1), ["kills", "combo"]);
+        assert!(check.step(&[], 10, 1).is_empty(), "wave 1 was hit so isn't flawless");
+        assert!(check.step(&[], 0, 2).is_empty());
+        assert_eq!(check.step(&[], 0, 3), ["flawless"]);
+        assert!(check.step(&[], 5, 3).is_empty(), "wave 3 was hit so isn't flawless");
+
+        let (ids, kills) = check.complete();
+        assert_eq!(ids, ["insane"]);
+        assert_eq!(kills, 3);
+    }
+}
@@ -0,0 +1,328 @@
+use bevy::asset::{AssetLoader, LoadContext, LoadState, LoadedAsset};
+use bevy::ecs::entity::Entities;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::{BoxedFuture, HashMap, HashSet};
+use serde::Deserialize;
+use crate::boss::{Boss, HitZone};
+use crate::common::{DeathEvent, GameTime, Velocity};
+use crate::enemy::Enemy;
+use crate::manager::GameState;
+use crate::player::Player;
+use crate::projectile::Projectile;
+
+const MANIFEST_PATH: &str = "sprites/manifest.sprites.ron";
+
+/// Animation clips an entity's art can provide. Missing clips fall back to [`Clip::Idle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum Clip {
+    Idle,
+    Move,
+    Fire,
+    Death,
+}
+
+/// Frames `first..=last` of the sprite sheet, played at `fps`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClipSpec {
+    pub first: usize,
+    pub last: usize,
+    pub fps: f32,
+    #[serde(default)]
+    pub looping: bool,
+}
+
+/// A sprite sheet split into equally sized tiles
+#[derive(Debug, Deserialize)]
+pub struct SheetSpec {
+    pub texture: String,
+    pub tile_size: (f32, f32),
+    pub columns: usize,
+    pub rows: usize,
+    pub clips: HashMap<Clip, ClipSpec>,
+}
+
+/// Sprite sheets for each kind of entity, keyed by the names given by [`art_key`]
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "b1d4c7e2-8a3f-4e6b-9c15-2f7d0e8a4b63"]
+pub struct SpriteManifest {
+    pub sheets: HashMap<String, SheetSpec>,
+}
+
+#[derive(Default)]
+pub struct SpriteManifestLoader;
+
+impl AssetLoader for SpriteManifestLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let manifest: SpriteManifest = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(manifest));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["sprites.ron"]
+    }
+}
+
+/// Atlases built from the manifest, and the art given to each entity
+struct SpriteArt {
+    manifest: Handle<SpriteManifest>,
+    sheets: HashMap<String, Sheet>,
+    /// Art key and size of every entity with art, so a death animation can still be played
+    /// once the entity is gone
+    entities: HashMap<Entity, (String, Option<Vec2>)>,
+}
+
+struct Sheet {
+    texture: Handle<Image>,
+    atlas: Handle<TextureAtlas>,
+    clips: HashMap<Clip, ClipSpec>,
+}
+
+impl Sheet {
+    fn clip(&self, clip: Clip) -> Option<&ClipSpec> {
+        self.clips.get(&clip).or_else(|| self.clips.get(&Clip::Idle))
+    }
+}
+
+/// Plays a [`Clip`] on a [`TextureAtlasSprite`]
+#[derive(Component)]
+pub struct Animation {
+    key: String,
+    clip: Clip,
+    /// Seconds since the clip started
+    elapsed: f32,
+    finished: bool,
+    despawn_on_finish: bool,
+}
+
+impl Animation {
+    fn new(key: String, clip: Clip) -> Self {
+        Self { key, clip, elapsed: 0., finished: false, despawn_on_finish: false }
+    }
+
+    fn play(&mut self, clip: Clip) {
+        if self.clip == clip && !self.finished { return }
+        self.clip = clip;
+        self.elapsed = 0.;
+        self.finished = false;
+    }
+}
+
+/// Name of the manifest entry used for an entity
+fn art_key(
+    player: Option<&Player>,
+    enemy: Option<&Enemy>,
+    projectile: Option<&Projectile>,
+    zone: Option<&HitZone>,
+    bosses: &Query<&Boss>) -> Option<String> {
+    if player.is_some() {
+        return Some(String::from("player"))
+    }
+    if let Some(enemy) = enemy {
+        return Some(String::from(enemy.kind.name()))
+    }
+    if let Some(projectile) = projectile {
+        return Some(String::from(if projectile.owner.is_player() { "projectile_player" } else { "projectile_enemy" }))
+    }
+    if let Some(zone) = zone {
+        let boss = bosses.get(zone.boss).ok()?;
+        return Some(format!("{:?}_{}", boss.kind, if zone.critical { "core" } else { "armor" }).to_lowercase())
+    }
+    None
+}
+
+fn art_load_sys(mut cmd: Commands, asset_server: Res<AssetServer>) {
+    cmd.insert_resource(SpriteArt {
+        manifest: asset_server.load(MANIFEST_PATH),
+        sheets: Default::default(),
+        entities: Default::default(),
+    });
+}
+
+/// Builds texture atlases whenever the manifest is loaded or changed
+fn art_manifest_sys(
+    mut art: ResMut<SpriteArt>,
+    mut manifest_events: EventReader<AssetEvent<SpriteManifest>>,
+    manifests: Res<Assets<SpriteManifest>>,
+    mut atlases: ResMut<Assets<TextureAtlas>>,
+    asset_server: Res<AssetServer>) {
+    for event in manifest_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } if *handle == art.manifest => {}
+            _ => continue,
+        }
+        let manifest = match manifests.get(&art.manifest) {
+            Some(manifest) => manifest,
+            None => continue,
+        };
+
+        art.sheets = manifest.sheets.iter().map(|(key, spec)| {
+            let texture: Handle<Image> = asset_server.load(spec.texture.as_str());
+            let (width, height) = spec.tile_size;
+            let atlas = TextureAtlas::from_grid(texture.clone(), Vec2::new(width, height), spec.columns, spec.rows);
+            (key.clone(), Sheet { texture, atlas: atlases.add(atlas), clips: spec.clips.clone() })
+        }).collect();
+        info!("Loaded sprite manifest with {} sheets", art.sheets.len());
+    }
+}
+
+/// Gives entities their art once the sprite sheet is loaded. The art is a child entity, and the
+/// entity's own colored [`Sprite`] is hidden so it only shows when art is missing.
+fn art_attach_sys(
+    mut cmd: Commands,
+    mut art: ResMut<SpriteArt>,
+    mut entities: Query<(Entity, &Sprite, &mut Visibility, Option<&Player>, Option<&Enemy>, Option<&Projectile>, Option<&HitZone>),
+        Or<(With<Player>, With<Enemy>, With<Projectile>, With<HitZone>)>>,
+    bosses: Query<&Boss>,
+    asset_server: Res<AssetServer>) {
+    let art = &mut *art;
+    for (entity, sprite, mut visibility, player, enemy, projectile, zone) in entities.iter_mut() {
+        if art.entities.contains_key(&entity) { continue }
+
+        let key = match art_key(player, enemy, projectile, zone, &bosses) {
+            Some(key) => key,
+            None => continue,
+        };
+        let sheet = match art.sheets.get(&key) {
+            Some(sheet) if asset_server.get_load_state(&sheet.texture) == LoadState::Loaded => sheet,
+            _ => continue,
+        };
+
+        visibility.is_visible = false;
+        cmd.entity(entity).with_children(|parent| {
+            parent.spawn_bundle(SpriteSheetBundle {
+                sprite: TextureAtlasSprite { custom_size: sprite.custom_size, ..Default::default() },
+                texture_atlas: sheet.atlas.clone(),
+                ..Default::default()
+            }).insert(Animation::new(key.clone(), Clip::Idle));
+        });
+        art.entities.insert(entity, (key, sprite.custom_size));
+    }
+}
+
+/// Picks the clip for each entity's art from what the entity is doing
+fn animation_clip_sys(
+    mut animations: Query<(&Parent, &mut Animation)>,
+    parents: Query<(Option<&Velocity>, Option<&Enemy>, Option<&HitZone>)>,
+    fired: Query<&Projectile, Added<Projectile>>) {
+    let firing: HashSet<Entity> = fired.iter().filter_map(|projectile| projectile.origin).collect();
+
+    for (parent, mut animation) in animations.iter_mut() {
+        if animation.clip == Clip::Fire && !animation.finished { continue }
+
+        let (velocity, enemy, zone) = match parents.get(parent.0) {
+            Ok(components) => components,
+            Err(_) => continue,
+        };
+        let clip = if firing.contains(&parent.0) || zone.map_or(false, |zone| firing.contains(&zone.boss)) {
+            Clip::Fire
+        } else if enemy.is_some() || velocity.map_or(false, |velocity| velocity.0 != Vec2::ZERO) {
+            Clip::Move
+        } else {
+            Clip::Idle
+        };
+        animation.play(clip);
+    }
+}
+
+/// Steps animations through their frames
+fn animation_play_sys(
+    mut cmd: Commands,
+    art: Res<SpriteArt>,
+    mut animations: Query<(Entity, &mut Animation, &mut TextureAtlasSprite)>,
+    time: Res<GameTime>) {
+    for (entity, mut animation, mut sprite) in animations.iter_mut() {
+        let clip = match art.sheets.get(&animation.key).and_then(|sheet| sheet.clip(animation.clip)) {
+            Some(clip) => clip,
+            None => continue,
+        };
+        if animation.finished { continue }
+
+        animation.elapsed += time.delta_seconds();
+        let frames = clip.last.saturating_sub(clip.first) + 1;
+        let mut frame = (animation.elapsed * clip.fps) as usize;
+        if frame >= frames {
+            if clip.looping {
+                frame %= frames;
+            } else {
+                frame = frames - 1;
+                animation.finished = true;
+                if animation.despawn_on_finish {
+                    cmd.entity(entity).despawn();
+                }
+            }
+        }
+        sprite.index = clip.first + frame;
+    }
+}
+
+/// Plays the death clip of anything with art where it died
+fn death_animation_sys(
+    mut cmd: Commands,
+    mut art: ResMut<SpriteArt>,
+    mut death_events: EventReader<DeathEvent>,
+    entities: &Entities) {
+    for death in death_events.iter().filter(|death| !death.life_lost) {
+        let (key, size) = match art.entities.get(&death.entity) {
+            Some(entry) => entry.clone(),
+            None => continue,
+        };
+        let sheet = match art.sheets.get(&key) {
+            Some(sheet) if sheet.clips.contains_key(&Clip::Death) => sheet,
+            _ => continue,
+        };
+
+        let mut animation = Animation::new(key, Clip::Death);
+        animation.despawn_on_finish = true;
+        cmd.spawn_bundle(SpriteSheetBundle {
+            sprite: TextureAtlasSprite { custom_size: size, ..Default::default() },
+            texture_atlas: sheet.atlas.clone(),
+            transform: Transform::from_translation(death.position),
+            ..Default::default()
+        }).insert(animation);
+    }
+
+    // Forget entities once they are gone. This happens after the death events are handled, as
+    // an entity may be de-spawned before its death event is read.
+    art.entities.retain(|entity, _| entities.contains(*entity));
+}
+
+/// Removes art whose entity was de-spawned without its children
+fn art_orphan_sys(mut cmd: Commands, animations: Query<(Entity, &Parent), With<Animation>>, entities: &Entities) {
+    for (entity, parent) in animations.iter() {
+        if !entities.contains(parent.0) {
+            cmd.entity(entity).despawn();
+        }
+    }
+}
+
+/// Removes death animations still playing when a run ends
+fn animation_cleanup_sys(mut cmd: Commands, animations: Query<Entity, (With<Animation>, Without<Parent>)>) {
+    for entity in animations.iter() {
+        cmd.entity(entity).despawn();
+    }
+}
+
+pub struct ArtPlugin;
+
+impl Plugin for ArtPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_asset::<SpriteManifest>()
+            .init_asset_loader::<SpriteManifestLoader>()
+            .add_startup_system(art_load_sys)
+            .add_system(art_manifest_sys)
+            // Runs after Update has applied its de-spawns and before the despawn queue, so the art
+            // is never added to an entity which no longer exists
+            .add_system_to_stage(CoreStage::PostUpdate, art_attach_sys)
+            .add_system_to_stage(CoreStage::PostUpdate, art_orphan_sys)
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(animation_clip_sys)
+                .with_system(animation_play_sys)
+                .with_system(death_animation_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(animation_cleanup_sys));
+    }
+}
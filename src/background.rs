@@ -0,0 +1,137 @@
+use bevy::prelude::*;
+use rand::prelude::*;
+use crate::common::{GameTime, Velocity};
+use crate::level::{BackgroundLayer, Level};
+use crate::manager::{CurrentLevel, GameState};
+use crate::player::Player;
+
+/// Depth of the furthest layer. Each nearer layer is drawn slightly in front of the last.
+const BACKGROUND_Z: f32     = -100.;
+/// Stars are spread this far past the sides of the window so parallax never shows an edge
+const SIDE_MARGIN: f32      = 100.;
+/// How quickly layers follow the player's horizontal position
+const PARALLAX_FOLLOW: f32  = 4.;
+
+/// Parent of a background layer's stars or tiles, offset against the player's position
+#[derive(Component)]
+struct Layer {
+    parallax: f32,
+}
+
+/// A star or texture tile scrolling down the screen, wrapping back to the top after `wrap_height`
+#[derive(Component)]
+struct Scroll {
+    speed: f32,
+    wrap_height: f32,
+}
+
+fn spawn_layer(parent: &mut ChildBuilder, layer: &BackgroundLayer, width: f32, height: f32, asset_server: &AssetServer, rng: &mut impl Rng) {
+    match layer {
+        BackgroundLayer::Stars { count, size, brightness, speed, .. } => {
+            let half_width = width / 2. + SIDE_MARGIN;
+            for _ in 0..*count {
+                let position = Vec2::new(rng.gen_range(-half_width..half_width), rng.gen_range(-height / 2.0..height / 2.0));
+                parent.spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::rgb(*brightness, *brightness, *brightness),
+                        custom_size: Some(Vec2::splat(*size)),
+                        ..Default::default()
+                    },
+                    transform: Transform::from_xyz(position.x, position.y, 0.),
+                    ..Default::default()
+                }).insert(Scroll { speed: *speed, wrap_height: height });
+            }
+        }
+        BackgroundLayer::Texture { path, opacity, speed, .. } => {
+            let texture: Handle<Image> = asset_server.load(path.as_str());
+            // Two tiles, one above the other, so one always covers the screen while they scroll
+            for i in 0..2 {
+                parent.spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::rgba(1., 1., 1., *opacity),
+                        custom_size: Some(Vec2::new(width + SIDE_MARGIN * 2., height)),
+                        ..Default::default()
+                    },
+                    texture: texture.clone(),
+                    transform: Transform::from_xyz(0., i as f32 * height, 0.),
+                    ..Default::default()
+                }).insert(Scroll { speed: *speed, wrap_height: height * 2. });
+            }
+        }
+    }
+}
+
+/// Spawns the level's background layers once the level has loaded
+fn background_spawn_sys(
+    mut cmd: Commands,
+    layers: Query<(), With<Layer>>,
+    level: Res<CurrentLevel>,
+    levels: Res<Assets<Level>>,
+    windows: Res<Windows>,
+    asset_server: Res<AssetServer>) {
+    if !layers.is_empty() { return }
+    let level = match levels.get(&level.handle) {
+        Some(level) => level,
+        None => return,
+    };
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+
+    let mut rng = rand::thread_rng();
+    for (i, layer) in level.background.iter().enumerate() {
+        let parallax = match layer {
+            BackgroundLayer::Stars { parallax, .. } | BackgroundLayer::Texture { parallax, .. } => *parallax,
+        };
+        cmd.spawn_bundle((Transform::from_xyz(0., 0., BACKGROUND_Z + i as f32), GlobalTransform::default()))
+            .insert(Layer { parallax })
+            .with_children(|parent| spawn_layer(parent, layer, window.width(), window.height(), &asset_server, &mut rng));
+    }
+}
+
+/// Scrolls stars and tiles down the screen, wrapping them back to the top
+fn background_scroll_sys(mut scrolling: Query<(&Scroll, &mut Transform)>, time: Res<GameTime>) {
+    for (scroll, mut transform) in scrolling.iter_mut() {
+        transform.translation.y -= scroll.speed * time.delta_seconds();
+        if transform.translation.y < -scroll.wrap_height / 2. {
+            transform.translation.y += scroll.wrap_height;
+        }
+    }
+}
+
+/// Shifts layers against the player's position, nearer layers further, and leans them into the player's movement
+fn background_parallax_sys(
+    mut layers: Query<(&Layer, &mut Transform)>,
+    players: Query<(&Transform, &Velocity), (With<Player>, Without<Layer>)>,
+    time: Res<GameTime>) {
+    let (player_x, player_speed) = match players.iter().next() {
+        Some((transform, velocity)) => (transform.translation.x, velocity.0.x),
+        None => return,
+    };
+    let follow = (PARALLAX_FOLLOW * time.delta_seconds()).min(1.);
+
+    for (layer, mut transform) in layers.iter_mut() {
+        let target = -(player_x + player_speed * 0.1) * layer.parallax;
+        transform.translation.x += (target - transform.translation.x) * follow;
+    }
+}
+
+fn background_cleanup_sys(mut cmd: Commands, layers: Query<Entity, With<Layer>>) {
+    for entity in layers.iter() {
+        cmd.entity(entity).despawn_recursive();
+    }
+}
+
+pub struct BackgroundPlugin;
+
+impl Plugin for BackgroundPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(background_spawn_sys)
+                .with_system(background_scroll_sys)
+                .with_system(background_parallax_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(background_cleanup_sys));
+    }
+}
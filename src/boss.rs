@@ -0,0 +1,319 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+use crate::common::*;
+use crate::enemy::{FirePattern, nearest_player};
+use crate::player::Player;
+use crate::manager::GameState;
+use crate::difficulty::DifficultyScaling;
+use crate::projectile::*;
+use crate::debug::TimedSystemExt;
+
+const BOSS_PROJECTILE_DAMAGE: i32 = 15;
+const BOSS_HIT_SCORE: i32         = 10;
+const BOSS_DEFEAT_SCORE: i32      = 500;
+
+/// The different bosses which can end a level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BossKind {
+    Dreadnought,
+}
+
+impl BossKind {
+    pub const ALL: [BossKind; 1] = [BossKind::Dreadnought];
+
+    pub fn hit_zones(&self) -> Vec<HitZoneSpec> {
+        match self {
+            BossKind::Dreadnought => vec![
+                HitZoneSpec { offset: Vec2::new(0., 0.),    size: Vec2::new(60., 40.), health: 300, color: Color::PURPLE,     critical: true },
+                HitZoneSpec { offset: Vec2::new(-70., 0.),  size: Vec2::new(50., 25.), health: 150, color: Color::FUCHSIA,    critical: false },
+                HitZoneSpec { offset: Vec2::new(70., 0.),   size: Vec2::new(50., 25.), health: 150, color: Color::FUCHSIA,    critical: false },
+                HitZoneSpec { offset: Vec2::new(0., -35.),  size: Vec2::new(20., 20.), health: 100, color: Color::ORANGE_RED, critical: false },
+            ],
+        }
+    }
+
+    /// Phases in the order they are played. The first phase should have a threshold of `1.0`.
+    pub fn phases(&self) -> Vec<BossPhase> {
+        match self {
+            BossKind::Dreadnought => vec![
+                BossPhase {
+                    health_threshold: 1.0,
+                    movement: BossMovement::Sweep { speed: 0.8, width: 250. },
+                    fire_pattern: FirePattern::Fan { count: 3, spread: 0.5 },
+                    fire_rate: 0.03,
+                },
+                BossPhase {
+                    health_threshold: 0.6,
+                    movement: BossMovement::Sweep { speed: 1.4, width: 300. },
+                    fire_pattern: FirePattern::Spiral { count: 8, step: 0.25 },
+                    fire_rate: 0.05,
+                },
+                BossPhase {
+                    health_threshold: 0.25,
+                    movement: BossMovement::FigureEight { speed: 1.2, width: 300., height: 80. },
+                    fire_pattern: FirePattern::Aimed { lead: true },
+                    fire_rate: 0.08,
+                },
+            ],
+        }
+    }
+}
+
+/// A part of a boss with its own [`CollisionBox`] and [`Health`]
+pub struct HitZoneSpec {
+    /// Position relative to the centre of the boss
+    pub offset: Vec2,
+    pub size: Vec2,
+    pub health: i32,
+    pub color: Color,
+    /// The boss is defeated once all of its critical zones are destroyed
+    pub critical: bool,
+}
+
+pub struct BossPhase {
+    /// The phase starts once the boss' remaining health fraction drops to this value
+    pub health_threshold: f32,
+    pub movement: BossMovement,
+    pub fire_pattern: FirePattern,
+    /// Chance of firing a volley every 1/60th of a second. See [`fire_chance`].
+    pub fire_rate: f32,
+}
+
+pub enum BossMovement {
+    Hover,
+    /// Moves side to side `width` units either side of the spawn point
+    Sweep { speed: f32, width: f32 },
+    FigureEight { speed: f32, width: f32, height: f32 },
+}
+
+impl BossMovement {
+    /// Offset from the spawn point after `elapsed` seconds
+    fn offset(&self, elapsed: f32) -> Vec2 {
+        match *self {
+            BossMovement::Hover => Vec2::ZERO,
+            BossMovement::Sweep { speed, width } => Vec2::new(width * (elapsed * speed).sin(), 0.),
+            BossMovement::FigureEight { speed, width, height } => {
+                Vec2::new(width * (elapsed * speed).sin(), height * (2. * elapsed * speed).sin() / 2.)
+            }
+        }
+    }
+
+    /// Elapsed seconds at which the horizontal offset is closest to `x`, so a boss switching to
+    /// this movement carries on from where it is
+    fn elapsed_at(&self, x: f32) -> f32 {
+        match *self {
+            BossMovement::Hover => 0.,
+            BossMovement::Sweep { speed, width } | BossMovement::FigureEight { speed, width, .. } => {
+                if speed == 0. || width == 0. { return 0. }
+                (x / width).clamp(-1., 1.).asin() / speed
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Boss {
+    pub kind: BossKind,
+    /// Index into [`BossKind::phases`]
+    pub phase: usize,
+    /// Total health remaining across all hit zones
+    pub health: i32,
+    pub max_health: i32,
+    /// Centre of the current phase's movement. Starts at the spawn position and moves when a
+    /// new phase is re-based onto the boss' position.
+    spawn_point: Vec2,
+    /// Seconds into the current phase's movement
+    elapsed: f32,
+    fire_angle: f32,
+}
+
+impl Boss {
+    pub fn health_fraction(&self) -> f32 {
+        if self.max_health <= 0 { return 0. }
+        self.health.max(0) as f32 / self.max_health as f32
+    }
+}
+
+/// Marks a child entity of a [`Boss`] which can be damaged
+#[derive(Component)]
+pub struct HitZone {
+    pub boss: Entity,
+    pub critical: bool,
+}
+
+pub struct BossPhaseEvent {
+    pub boss: Entity,
+    pub phase: usize,
+}
+
+pub struct BossDefeatedEvent {
+    pub boss: Entity,
+    pub kind: BossKind,
+    pub position: Vec3,
+}
+
+/// Spawns a boss centred on `position`, with each hit zone as a child entity.
+pub fn spawn_boss(cmd: &mut Commands, kind: BossKind, position: Vec2, difficulty: &DifficultyScaling) -> Entity {
+    let mut zones = kind.hit_zones();
+    for zone in zones.iter_mut() {
+        zone.health = difficulty.scale_health(zone.health);
+    }
+    let max_health = zones.iter().map(|zone| zone.health).sum();
+
+    let mut boss = cmd.spawn_bundle((Transform::from_xyz(position.x, position.y, 0.), GlobalTransform::default()));
+    let boss_entity = boss.id();
+    boss.insert(Boss {
+        kind,
+        phase: 0,
+        health: max_health,
+        max_health,
+        spawn_point: position,
+        elapsed: 0.,
+        fire_angle: 0.,
+    }).with_children(|parent| {
+        for zone in zones {
+            parent.spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: zone.color,
+                    custom_size: Some(zone.size),
+                    ..Default::default()
+                },
+                transform: Transform::from_xyz(zone.offset.x, zone.offset.y, 0.1),
+                ..Default::default()
+            })
+                .insert(CollisionBox { size: zone.size })
+                .insert(Team::Enemy)
+                .insert(Health::new(zone.health))
+                .insert(HitZone { boss: boss_entity, critical: zone.critical });
+        }
+    });
+
+    info!("Spawned boss {:?} entity {}", kind, boss_entity.id());
+    boss_entity
+}
+
+fn boss_move_sys(mut bosses: Query<(&mut Boss, &mut Transform)>, time: Res<GameTime>) {
+    for (mut boss, mut transform) in bosses.iter_mut() {
+        boss.elapsed += time.delta_seconds();
+        let phases = boss.kind.phases();
+        let position = boss.spawn_point + phases[boss.phase].movement.offset(boss.elapsed);
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
+
+/// Bosses fire their current phase's [`FirePattern`] by random choice and interval
+fn boss_shoot_sys(
+    mut cmd: Commands,
+    mut bosses: Query<(Entity, &mut Boss, &GlobalTransform)>,
+    players: Query<(&GlobalTransform, &Velocity), With<Player>>,
+    difficulty: Res<DifficultyScaling>,
+    time: Res<GameTime>) {
+    for (entity, mut boss, transform) in bosses.iter_mut() {
+        let phases = boss.kind.phases();
+        let phase = &phases[boss.phase];
+        if fire_chance(phase.fire_rate * difficulty.fire_rate(), time.delta_seconds()) <= rand::random::<f32>() { continue }
+
+        let projectile = Projectile {
+            damage: difficulty.scale_damage(BOSS_PROJECTILE_DAMAGE),
+            origin: Some(entity),
+            ..Default::default()
+        };
+
+        let position = transform.translation.truncate();
+        let target = nearest_player(position, players.iter());
+        for direction in phase.fire_pattern.volley(&mut boss.fire_angle, position, target, projectile.speed()) {
+            cmd.spawn_bundle(ProjectileBundle {
+                projectile: Projectile { direction, ..projectile.clone() },
+                sprite: SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::ORANGE_RED,
+                        custom_size: Some(Vec2::new(15.0, 15.0)),
+                        ..Default::default()
+                    },
+                    transform: Transform::from_xyz(position.x, position.y, transform.translation.z),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// Reduces hit zone health when hit, increasing the score of the player who fired
+fn boss_hit_sys(
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    mut players: Query<&mut Player>,
+    mut zone_healths: Query<&mut Health, With<HitZone>>,
+    difficulty: Res<DifficultyScaling>) {
+    for &ProjectileHitEvent{other, owner, damage, ..} in hit_events.iter() {
+        if let Ok(mut zone_health) = zone_healths.get_mut(other) {
+            zone_health.health = zone_health.health - damage;
+            if let Some(mut player) = owner.player.and_then(|index| players.iter_mut().find(|player| player.index == index)) {
+                player.score += difficulty.scale_score(BOSS_HIT_SCORE);
+            }
+        }
+    }
+}
+
+/// Totals the health of each boss' hit zones, switching phase when a health threshold is crossed
+/// and removing the boss once its critical zones are destroyed.
+fn boss_health_sys(
+    mut despawn_queue: ResMut<DespawnQueue>,
+    mut bosses: Query<(Entity, &mut Boss, &GlobalTransform)>,
+    zones: Query<(&HitZone, &Health)>,
+    mut players: Query<&mut Player>,
+    mut phase_events: EventWriter<BossPhaseEvent>,
+    mut defeated_events: EventWriter<BossDefeatedEvent>,
+    difficulty: Res<DifficultyScaling>) {
+    for (entity, mut boss, transform) in bosses.iter_mut() {
+        let alive_zones: Vec<&HitZone> = zones.iter()
+            .filter(|(zone, health)| zone.boss == entity && health.health > 0)
+            .map(|(zone, _)| zone)
+            .collect();
+        boss.health = zones.iter()
+            .filter(|(zone, _)| zone.boss == entity)
+            .map(|(_, health)| health.health.max(0))
+            .sum();
+
+        if !alive_zones.iter().any(|zone| zone.critical) {
+            info!("Boss {:?} entity {} defeated", boss.kind, entity.id());
+            for mut player in players.iter_mut() {
+                player.score += difficulty.scale_score(BOSS_DEFEAT_SCORE);
+            }
+            defeated_events.send(BossDefeatedEvent { boss: entity, kind: boss.kind, position: transform.translation });
+            despawn_queue.despawn(entity);
+            continue
+        }
+
+        let phases = boss.kind.phases();
+        let previous = boss.phase;
+        while boss.phase + 1 < phases.len() && boss.health_fraction() <= phases[boss.phase + 1].health_threshold {
+            boss.phase += 1;
+            info!("Boss {:?} entity {} entering phase {}", boss.kind, entity.id(), boss.phase);
+            phase_events.send(BossPhaseEvent { boss: entity, phase: boss.phase });
+        }
+
+        // Re-base the new movement on the current position so the boss doesn't jump
+        if boss.phase != previous {
+            let position = boss.spawn_point + phases[previous].movement.offset(boss.elapsed);
+            let movement = &phases[boss.phase].movement;
+            boss.elapsed = movement.elapsed_at(position.x - boss.spawn_point.x);
+            boss.spawn_point = position - movement.offset(boss.elapsed);
+        }
+    }
+}
+
+pub struct BossPlugin;
+
+impl Plugin for BossPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(boss_move_sys.timed())
+                .with_system(boss_shoot_sys.timed())
+                .with_system(boss_hit_sys.timed())
+                .with_system(boss_health_sys.timed()))
+            .add_event::<BossPhaseEvent>()
+            .add_event::<BossDefeatedEvent>();
+    }
+}
@@ -1,53 +1,235 @@
+use std::time::Duration;
 use bevy::prelude::*;
 use bevy::sprite::collide_aabb::*;
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
+use rand::prelude::*;
+use crate::enemy::{Enemy, EnemyKind};
+use crate::manager::GameState;
+use crate::player::Player;
+use crate::projectile::ProjectileModifiers;
+use crate::debug::TimedSystemExt;
 
 const DEFAULT_INIT_HEALTH: i32 = 100;
 const DEFAULT_AMMO_COUNT: i32 = 10;
 const DEFAULT_FIRE_RATE: f32 = 0.01;
+const DEFAULT_RELOAD_TIME: f32 = 1.5;
+const DEFAULT_PROJECTILE_DAMAGE: i32 = 10;
+/// How fast gameplay runs during hit-stop
+const HIT_STOP_TIME_SCALE: f32 = 0.05;
+/// Frame rate fire rates are tuned for
+const FIRE_RATE_FPS: f32 = 60.;
 
 /* Health Component */
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Health {
-    pub health: i32
+    pub health: i32,
+    pub max_health: i32,
 }
 
-impl Default for Health {
-    fn default() -> Self {
+impl Health {
+    pub fn new(health: i32) -> Self {
         Self {
-            health: DEFAULT_INIT_HEALTH,
+            health,
+            max_health: health,
         }
     }
+
+    pub fn fraction(&self) -> f32 {
+        if self.max_health <= 0 { return 0. }
+        self.health.max(0) as f32 / self.max_health as f32
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new(DEFAULT_INIT_HEALTH)
+    }
+}
+
+/// Extra lives. When [`Health`] runs out a life is used to restore it instead of de-spawning the entity.
+#[derive(Component, Clone)]
+pub struct Lives {
+    pub remaining: u32,
 }
 
-pub fn health_event_sys(mut cmd: Commands, health_entities: Query<(Entity, &Health)>) {
-    for (entity, health) in health_entities.iter(){
+/// What a [`DeathEvent`] was sent for. Recorded as it happens, since the entity may have been
+/// de-spawned by the time the event is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Victim {
+    Player,
+    Enemy(EnemyKind),
+    Other,
+}
+
+/// Sent when an entity's [`Health`] runs out, whether it is de-spawned or loses a life
+pub struct DeathEvent {
+    pub entity: Entity,
+    pub victim: Victim,
+    pub position: Vec3,
+    /// True if the entity used one of its [`Lives`] and was not de-spawned
+    pub life_lost: bool,
+}
+
+pub fn health_event_sys(
+    mut despawn_queue: ResMut<DespawnQueue>,
+    mut health_entities: Query<(Entity, &mut Health, &GlobalTransform, Option<&mut Lives>, Option<&Enemy>, Option<&Player>)>,
+    mut death_events: EventWriter<DeathEvent>) {
+    for (entity, mut health, transform, lives, enemy, player) in health_entities.iter_mut(){
         if health.health <= 0 {
-            info!("De-spawning Entity {}: Health is {}", entity.id(), health.health);
-            cmd.entity(entity).despawn();
+            let life_lost = lives.as_ref().map_or(false, |lives| lives.remaining > 0);
+            let victim = match (enemy, player) {
+                (Some(enemy), _) => Victim::Enemy(enemy.kind),
+                (_, Some(_))     => Victim::Player,
+                _                => Victim::Other,
+            };
+            death_events.send(DeathEvent { entity, victim, position: transform.translation, life_lost });
+            match lives {
+                Some(mut lives) if lives.remaining > 0 => {
+                    lives.remaining -= 1;
+                    health.health = health.max_health;
+                    info!("Entity {} lost a life: {} remaining", entity.id(), lives.remaining);
+                }
+                _ => {
+                    info!("De-spawning Entity {}: Health is {}", entity.id(), health.health);
+                    despawn_queue.despawn(entity);
+                }
+            }
+        }
+    }
+}
+
+/* Despawning */
+
+/// Entities to remove, with their children, at the end of the frame. Any system can queue an
+/// entity, even one already queued or removed, so systems don't need to agree on who removes what.
+#[derive(Default)]
+pub struct DespawnQueue(HashSet<Entity>);
+
+impl DespawnQueue {
+    pub fn despawn(&mut self, entity: Entity) {
+        self.0.insert(entity);
+    }
+
+    /// True if `entity` will be removed at the end of the frame
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.0.contains(&entity)
+    }
+}
+
+pub(crate) fn despawn_queue_sys(world: &mut World) {
+    let queued = match world.get_resource_mut::<DespawnQueue>() {
+        Some(mut queue) => std::mem::take(&mut queue.0),
+        None => return,
+    };
+    for entity in queued {
+        if world.get_entity(entity).is_some() {
+            world.entity_mut(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Side an entity fights for. Projectiles carry the team of whoever fired them.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Team {
+    Player,
+    Enemy,
+}
+
+/// Whether projectiles may hit entities on their own team
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FriendlyFire {
+    /// Projectiles only hit the other team
+    Off,
+    /// Players' projectiles also hit other players, for players playing against each other
+    Versus,
+    /// Every projectile hits everything except whoever fired it
+    All,
+}
+
+impl Default for FriendlyFire {
+    fn default() -> Self { FriendlyFire::Off }
+}
+
+impl FriendlyFire {
+    /// True if a projectile of team `attacker` may hit an entity on team `target`. Entities
+    /// without a team can always be hit.
+    pub fn allows(&self, attacker: Team, target: Option<Team>) -> bool {
+        match target {
+            Some(target) if target == attacker => match self {
+                FriendlyFire::Off    => false,
+                FriendlyFire::Versus => attacker == Team::Player,
+                FriendlyFire::All    => true,
+            },
+            _ => true,
         }
     }
 }
 
 /* Shooter (turret) Component */
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Shooter {
     pub ammo_count: i32,
+    /// Ammo restored by a reload
+    pub magazine_size: i32,
+    /// Chance of firing every 1/60th of a second of game time. See [`fire_chance`].
     pub fire_rate: f32,
+    /// Damage dealt by each projectile fired
+    pub damage: i32,
+    /// Name of the prefab spawned for each projectile, if not the shooter's built-in projectile
+    pub projectile: Option<String>,
+    /// Modifiers copied onto every [`Projectile`](crate::projectile::Projectile) this shooter fires
+    pub modifiers: ProjectileModifiers,
+    /// Ticks while reloading. See [`Shooter::start_reload`].
+    pub reload_timer: Timer,
+    pub reloading: bool,
 }
 
 impl Default for Shooter {
     fn default() -> Self {
         Self {
             ammo_count: DEFAULT_AMMO_COUNT,
+            magazine_size: DEFAULT_AMMO_COUNT,
             fire_rate: DEFAULT_FIRE_RATE,
+            damage: DEFAULT_PROJECTILE_DAMAGE,
+            projectile: None,
+            modifiers: Default::default(),
+            reload_timer: Timer::from_seconds(DEFAULT_RELOAD_TIME, false),
+            reloading: false,
+        }
+    }
+}
+
+impl Shooter {
+    pub fn start_reload(&mut self) {
+        if self.reloading || self.ammo_count >= self.magazine_size { return }
+        self.reload_timer.reset();
+        self.reloading = true;
+    }
+
+    /// Fraction of the current reload completed, or 1 if not reloading
+    pub fn reload_progress(&self) -> f32 {
+        if self.reloading { self.reload_timer.percent() } else { 1.0 }
+    }
+}
+
+/// Chance of firing this frame for a `fire_rate` tuned at 60 frames per second, so the rate of
+/// fire follows [`GameTime`] rather than the real frame rate, and nothing fires while paused.
+pub fn fire_chance(fire_rate: f32, delta_seconds: f32) -> f32 {
+    1.0 - (1.0 - fire_rate.clamp(0.0, 1.0)).powf(delta_seconds * FIRE_RATE_FPS)
+}
+
+pub(crate) fn shooter_reload_sys(mut shooters: Query<&mut Shooter>, time: Res<GameTime>) {
+    for mut shooter in shooters.iter_mut().filter(|shooter| shooter.reloading) {
+        if shooter.reload_timer.tick(time.delta()).just_finished() {
+            shooter.ammo_count = shooter.magazine_size;
+            shooter.reloading = false;
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Direction {
     UP,
     DOWN,
@@ -64,11 +246,33 @@ impl Direction {
             Direction::RIGHT => { Direction::LEFT }
         }
     }
+
+    /// Unit vector pointing in this direction
+    pub fn vec(&self) -> Vec2 {
+        match self {
+            Direction::UP    => { Vec2::Y }
+            Direction::DOWN  => { -Vec2::Y }
+            Direction::LEFT  => { -Vec2::X }
+            Direction::RIGHT => { Vec2::X }
+        }
+    }
+}
+
+/// Rotates `vec` anti-clockwise by `angle` radians
+pub fn rotate(vec: Vec2, angle: f32) -> Vec2 {
+    let (sin, cos) = angle.sin_cos();
+    Vec2::new(vec.x * cos - vec.y * sin, vec.x * sin + vec.y * cos)
 }
 
+/* Velocity Component */
+
+/// Current movement of an entity in units per second. Used by shooters to lead their targets.
+#[derive(Component, Default, Clone, Copy)]
+pub struct Velocity(pub Vec2);
+
 /* Collision box Component */
 
-#[derive(Component)]
+#[derive(Component, Clone, Copy)]
 pub struct CollisionBox {
     pub size: Vec2
 }
@@ -79,7 +283,7 @@ pub struct CollisionEvent {
     pub collision: Collision,
 }
 
-fn collision_sys(colliders: Query<(Entity, &Transform, &CollisionBox)>, mut hit_event_writer: EventWriter<CollisionEvent>) {
+pub(crate) fn collision_sys(colliders: Query<(Entity, &GlobalTransform, &CollisionBox)>, mut hit_event_writer: EventWriter<CollisionEvent>) {
     let mut hits: HashMap<Entity, Vec<Entity>> = HashMap::default(); // Tracks which entities have already collided to avoid duplicate/ghost collisions
     for (entity, transform, shape) in colliders.iter() {
         for (hit_entity, hit_transform, hit_shape) in colliders.iter(){
@@ -113,12 +317,93 @@ fn collision_debug_sys(mut collision_events: EventReader<CollisionEvent>) {
     }
 }
 
+/* Gameplay clock */
+
+/// Gameplay systems use this instead of [`Time`] so gameplay can be slowed down or stopped.
+/// Follows [`Time`] scaled by [`GameTime::scale`], and slows almost to a stop during hit-stop.
+pub struct GameTime {
+    pub scale: f32,
+    /// Real seconds of hit-stop remaining
+    hit_stop: f32,
+    delta: Duration,
+}
+
+impl Default for GameTime {
+    fn default() -> Self {
+        Self { scale: 1.0, hit_stop: 0., delta: Duration::ZERO }
+    }
+}
+
+impl GameTime {
+    /// A clock which advances by exactly `step` every frame, for fixed-step simulation
+    pub fn fixed(step: Duration) -> Self {
+        Self { scale: 1.0, hit_stop: 0., delta: step }
+    }
+
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    /// Slows gameplay down for `seconds` of real time. Overlapping hit-stops don't add up.
+    pub fn hit_stop(&mut self, seconds: f32) {
+        self.hit_stop = self.hit_stop.max(seconds);
+    }
+
+    pub fn clear_hit_stop(&mut self) {
+        self.hit_stop = 0.;
+    }
+}
+
+fn game_time_sys(mut game_time: ResMut<GameTime>, time: Res<Time>) {
+    let mut scale = game_time.scale.max(0.);
+    if game_time.hit_stop > 0. {
+        game_time.hit_stop -= time.delta_seconds();
+        scale *= HIT_STOP_TIME_SCALE;
+    }
+    game_time.delta = time.delta().mul_f32(scale);
+}
+
+/* Seeded random numbers */
+
+/// Random numbers for level generation and enemy placement, reseeded at the start of every run
+/// so a seed always produces the same sequence of waves
+#[derive(Clone)]
+pub struct GameRng {
+    pub seed: u64,
+    pub rng: StdRng,
+}
+
+impl GameRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self { seed, rng: StdRng::seed_from_u64(seed) }
+    }
+}
+
+impl Default for GameRng {
+    fn default() -> Self {
+        GameRng::seeded(thread_rng().gen())
+    }
+}
+
 pub struct GameCommonPlugin;
 
 impl Plugin for GameCommonPlugin {
     fn build(&self, app: &mut App) {
-        app .add_system(collision_sys)
-            .add_event::<CollisionEvent>();
+        app .init_resource::<GameTime>()
+            .init_resource::<DespawnQueue>()
+            .init_resource::<FriendlyFire>()
+            .init_resource::<GameRng>()
+            .add_system_to_stage(CoreStage::PreUpdate, game_time_sys)
+            .add_system_to_stage(CoreStage::Last, despawn_queue_sys.exclusive_system())
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(collision_sys.timed())
+                .with_system(shooter_reload_sys.timed()))
+            .add_event::<CollisionEvent>()
+            .add_event::<DeathEvent>();
 
         #[cfg(debug_assertions)]
         app.add_system(collision_debug_sys);
@@ -0,0 +1,454 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use rand::prelude::*;
+use crate::boss::Boss;
+use crate::common::{DespawnQueue, FriendlyFire, GameTime, Health};
+use crate::difficulty::DifficultyScaling;
+use crate::enemy::{new_enemy, Enemy, EnemyKind, EnemyPlugin};
+use crate::interface::HudFonts;
+use crate::manager::{GameState, RunMode};
+use crate::player::{GodMode, Player};
+use crate::prefab::Prefabs;
+
+const TOGGLE_KEY: KeyCode  = KeyCode::Grave;
+const MAX_LOG_LINES: usize = 200;
+const VISIBLE_LINES: usize = 12;
+
+/// Runs a console command with its arguments, returning the text to show or an error
+pub type CommandHandler = fn(&mut World, &[&str]) -> Result<String, String>;
+
+struct ConsoleCommand {
+    usage: String,
+    help: String,
+    handler: CommandHandler,
+}
+
+/// Every command the console knows, by name. Plugins add their own with
+/// [`ConsoleCommandsExt::add_console_command`].
+#[derive(Default)]
+pub struct ConsoleCommands(HashMap<String, ConsoleCommand>);
+
+pub trait ConsoleCommandsExt {
+    /// Registers a console command. `usage` is shown by `help`, for example `"spawn <kind> [count]"`.
+    fn add_console_command(&mut self, usage: &str, help: &str, handler: CommandHandler) -> &mut Self;
+}
+
+impl ConsoleCommandsExt for App {
+    fn add_console_command(&mut self, usage: &str, help: &str, handler: CommandHandler) -> &mut Self {
+        let name = usage.split_whitespace().next().unwrap_or_default().to_string();
+        let command = ConsoleCommand { usage: usage.to_string(), help: help.to_string(), handler };
+        self.world.get_resource_or_insert_with(ConsoleCommands::default).0.insert(name, command);
+        self
+    }
+}
+
+/// Console state. Lines passed to [`Console::submit`] are run at the start of the next update,
+/// so commands can be issued without the console being open, or in a headless app.
+#[derive(Default)]
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    pub log: Vec<String>,
+    pending: Vec<String>,
+}
+
+impl Console {
+    pub fn submit(&mut self, line: &str) {
+        self.pending.push(line.to_string());
+    }
+
+    fn print(&mut self, line: String) {
+        self.log.push(line);
+        if self.log.len() > MAX_LOG_LINES {
+            let excess = self.log.len() - MAX_LOG_LINES;
+            self.log.drain(..excess);
+        }
+    }
+}
+
+/// Runs one command line immediately, echoing it and its result to the console log
+pub fn run_command(world: &mut World, line: &str) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(String::new()),
+    };
+    let handler = world.get_resource::<ConsoleCommands>()
+        .and_then(|commands| commands.0.get(name))
+        .map(|command| command.handler);
+
+    let result = match handler {
+        Some(handler) => handler(world, args),
+        None => Err(format!("Unknown command '{}'. Try 'help'.", name)),
+    };
+
+    let mut console = world.get_resource_or_insert_with(Console::default);
+    console.print(format!("> {}", line));
+    match &result {
+        Ok(output) => if !output.is_empty() { console.print(output.clone()) },
+        Err(err) => console.print(format!("error: {}", err)),
+    }
+    result
+}
+
+fn console_run_sys(world: &mut World) {
+    let pending = match world.get_resource_mut::<Console>() {
+        Some(mut console) => std::mem::take(&mut console.pending),
+        None => return,
+    };
+    for line in pending {
+        let _ = run_command(world, &line);
+    }
+}
+
+pub(crate) fn parse<T: std::str::FromStr>(args: &[&str], index: usize, name: &str) -> Result<T, String> {
+    let arg = args.get(index).ok_or_else(|| format!("Missing {}", name))?;
+    arg.parse().map_err(|_| format!("Invalid {} '{}'", name, arg))
+}
+
+fn help_command(world: &mut World, _: &[&str]) -> Result<String, String> {
+    let commands = world.get_resource::<ConsoleCommands>().ok_or("No commands registered")?;
+    let mut lines: Vec<String> = commands.0.values()
+        .map(|command| format!("{:<24} {}", command.usage, command.help))
+        .collect();
+    lines.sort();
+    Ok(lines.join("\n"))
+}
+
+fn spawn_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let name = args.get(0).ok_or("Missing enemy kind")?;
+    let kind = EnemyKind::ALL.iter()
+        .find(|kind| kind.name() == *name || kind.name() == format!("enemy_{}", name))
+        .copied()
+        .ok_or_else(|| format!("Unknown enemy kind '{}'", name))?;
+    let count: u32 = if args.len() > 1 { parse(args, 1, "count")? } else { 1 };
+
+    let (width, height) = world.get_resource::<Windows>()
+        .and_then(|windows| windows.get_primary())
+        .map(|window| (window.width() / 2., window.height() / 2.))
+        .ok_or("No window")?;
+    let fire_rate = world.get_resource::<EnemyPlugin>().map_or(0., |enemies| enemies.fire_rate);
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..count {
+        let position = Vec2::new(rng.gen_range(-width..width), rng.gen_range(0.0..height * 0.8));
+        let bundle = {
+            let prefabs = world.get_resource::<Prefabs>().ok_or("Prefabs not loaded")?;
+            let difficulty = world.get_resource::<DifficultyScaling>().ok_or("No difficulty")?;
+            new_enemy(kind, position, fire_rate, prefabs, difficulty)
+        };
+        world.spawn().insert_bundle(bundle);
+    }
+    Ok(format!("Spawned {} {}", count, kind.name()))
+}
+
+fn health_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let health: i32 = parse(args, 0, "health")?;
+    let mut players = world.query_filtered::<&mut Health, With<Player>>();
+    for mut player_health in players.iter_mut(world) {
+        player_health.max_health = player_health.max_health.max(health);
+        player_health.health = health;
+    }
+    Ok(format!("Player health set to {}", health))
+}
+
+fn score_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let score: i32 = parse(args, 0, "score")?;
+    let mut players = world.query::<&mut Player>();
+    for mut player in players.iter_mut(world) {
+        player.score = score;
+    }
+    Ok(format!("Player score set to {}", score))
+}
+
+fn credits_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let credits: u32 = parse(args, 0, "credits")?;
+    let mut players = world.query::<&mut Player>();
+    for mut player in players.iter_mut(world) {
+        player.credits = credits;
+    }
+    Ok(format!("Player credits set to {}", credits))
+}
+
+fn god_command(world: &mut World, _: &[&str]) -> Result<String, String> {
+    let players: Vec<(Entity, bool)> = world.query::<(Entity, Option<&GodMode>, &Player)>()
+        .iter(world)
+        .map(|(entity, god_mode, _)| (entity, god_mode.is_some()))
+        .collect();
+    let enable = !players.iter().any(|(_, god_mode)| *god_mode);
+    for (entity, _) in players {
+        if enable {
+            world.entity_mut(entity).insert(GodMode);
+        } else {
+            world.entity_mut(entity).remove::<GodMode>();
+        }
+    }
+    Ok(format!("God mode {}", if enable { "on" } else { "off" }))
+}
+
+/// Removes every enemy, or the boss if it has spawned, without awarding score. They are queued
+/// on the [`DespawnQueue`] like any other removal.
+fn skip_command(world: &mut World, _: &[&str]) -> Result<String, String> {
+    let skipped: Vec<Entity> = world.query_filtered::<Entity, Or<(With<Enemy>, With<Boss>)>>().iter(world).collect();
+    let mut despawn_queue = world.get_resource_mut::<DespawnQueue>().ok_or("No despawn queue")?;
+    for &entity in &skipped {
+        despawn_queue.despawn(entity);
+    }
+    Ok(format!("Skipped wave, removing {} entities", skipped.len()))
+}
+
+fn timescale_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let scale: f32 = parse(args, 0, "scale")?;
+    if scale < 0. { return Err(String::from("Scale can't be negative")) }
+    world.get_resource_mut::<GameTime>().ok_or("No game time")?.scale = scale;
+    Ok(format!("Time scale set to {}", scale))
+}
+
+fn friendly_fire_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let friendly_fire = match args.get(0).copied() {
+        Some("off")    => FriendlyFire::Off,
+        Some("versus") => FriendlyFire::Versus,
+        Some("all")    => FriendlyFire::All,
+        _ => return Err(String::from("Expected off, versus or all")),
+    };
+    world.insert_resource(friendly_fire);
+    Ok(format!("Friendly fire set to {:?}", friendly_fire))
+}
+
+/// Starts an endless run, with a random seed unless one is given
+fn endless_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let mode = if args.is_empty() { RunMode::endless() } else { RunMode::Endless { seed: parse(args, 0, "seed")? } };
+    world.get_resource_mut::<State<GameState>>().ok_or("No game state")?
+        .replace(GameState::Playing)
+        .map_err(|error| format!("{:?}", error))?;
+    world.insert_resource(mode);
+    Ok(format!("Started {:?}", mode))
+}
+
+/// Lists the components of the entity with the given id
+fn dump_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    let id: u32 = parse(args, 0, "entity id")?;
+    let entity = world.query::<Entity>().iter(world)
+        .find(|entity| entity.id() == id)
+        .ok_or_else(|| format!("No entity {}", id))?;
+
+    let components = world.components();
+    let mut names: Vec<&str> = world.entity(entity).archetype().components()
+        .filter_map(|component| components.get_info(component))
+        .map(|info| info.name())
+        .collect();
+    names.sort_unstable();
+    Ok(format!("Entity {}:\n  {}", id, names.join("\n  ")))
+}
+
+/// Root of the console panel
+#[derive(Component)]
+struct ConsolePanel;
+
+#[derive(Component)]
+struct ConsoleText;
+
+/// Opens and closes the console, and types into it while open. Keys are then released so
+/// nothing else sees them.
+fn console_input_sys(
+    mut console: ResMut<Console>,
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>) {
+    if keyboard_input.just_pressed(TOGGLE_KEY) {
+        keyboard_input.reset(TOGGLE_KEY);
+        console.open = !console.open;
+    }
+    if !console.open {
+        characters.iter().for_each(drop);
+        return
+    }
+
+    for event in characters.iter() {
+        if !event.char.is_control() && event.char != '`' {
+            console.input.push(event.char);
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::Back) {
+        console.input.pop();
+    }
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        let line = std::mem::take(&mut console.input);
+        console.submit(&line);
+    }
+
+    let pressed: Vec<KeyCode> = keyboard_input.get_pressed().chain(keyboard_input.get_just_released()).copied().collect();
+    for key in pressed {
+        keyboard_input.reset(key);
+    }
+}
+
+fn console_ui_sys(
+    mut cmd: Commands,
+    console: Res<Console>,
+    fonts: Option<Res<HudFonts>>,
+    panels: Query<Entity, With<ConsolePanel>>,
+    mut texts: Query<&mut Text, With<ConsoleText>>) {
+    if !console.is_changed() { return }
+
+    if !console.open {
+        for entity in panels.iter() {
+            cmd.entity(entity).despawn_recursive();
+        }
+        return
+    }
+
+    let start = console.log.len().saturating_sub(VISIBLE_LINES);
+    let value = format!("{}\n> {}_", console.log[start..].join("\n"), console.input);
+    if !panels.is_empty() {
+        for mut text in texts.iter_mut() {
+            text.sections[0].value = value.clone();
+        }
+        return
+    }
+
+    let fonts = match fonts {
+        Some(fonts) => fonts,
+        None => return,
+    };
+    cmd.spawn_bundle(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect { top: Val::Px(0.), left: Val::Px(0.), ..Default::default() },
+            size: Size::new(Val::Percent(100.), Val::Percent(40.)),
+            align_items: AlignItems::FlexEnd,
+            padding: Rect::all(Val::Px(8.)),
+            ..Default::default()
+        },
+        color: UiColor(Color::rgba(0., 0., 0., 0.8)),
+        ..Default::default()
+    }).insert(ConsolePanel).with_children(|parent| {
+        parent.spawn_bundle(TextBundle {
+            text: Text::with_section(value, TextStyle { font: fonts.regular.clone(), font_size: 16., color: Color::WHITE }, Default::default()),
+            ..Default::default()
+        }).insert(ConsoleText);
+    });
+}
+
+pub struct ConsolePlugin;
+
+impl Plugin for ConsolePlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<Console>()
+            .init_resource::<ConsoleCommands>()
+            .add_console_command("help", "List commands", help_command)
+            .add_console_command("spawn <kind> [count]", "Spawn enemies, such as 'spawn sniper 3'", spawn_command)
+            .add_console_command("health <value>", "Set player health", health_command)
+            .add_console_command("score <value>", "Set player score", score_command)
+            .add_console_command("credits <value>", "Set player credits", credits_command)
+            .add_console_command("god", "Toggle player invulnerability", god_command)
+            .add_console_command("skip", "Skip the current wave", skip_command)
+            .add_console_command("timescale <scale>", "Set gameplay speed, 1 is normal", timescale_command)
+            .add_console_command("friendlyfire <off|versus|all>", "Set which projectiles hit their own team", friendly_fire_command)
+            .add_console_command("endless [seed]", "Start an endless run, replaying a seed if given", endless_command)
+            .add_console_command("dump <entity id>", "List an entity's components", dump_command)
+            .add_system(console_run_sys.exclusive_system());
+
+        #[cfg(debug_assertions)]
+        app .add_system_to_stage(CoreStage::PreUpdate, console_input_sys.after(bevy::input::InputSystem))
+            .add_system(console_ui_sys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::window::WindowId;
+    use crate::common::despawn_queue_sys;
+    use super::*;
+
+    /// An app with the console's commands and a player, but no window or renderer
+    fn app() -> (App, Entity) {
+        let mut app = App::new();
+        app.add_plugin(ConsolePlugin);
+        let mut windows = Windows::default();
+        windows.add(Window::new(WindowId::primary(), &WindowDescriptor::default(), 800, 600, 1.0, None));
+        app .insert_resource(windows)
+            .insert_resource(GameTime::default())
+            .insert_resource(Prefabs::default())
+            .insert_resource(DifficultyScaling::default())
+            .init_resource::<DespawnQueue>();
+        let player = app.world.spawn().insert(Player::default()).insert(Health::new(100)).id();
+        (app, player)
+    }
+
+    #[test]
+    fn spawn() {
+        let (mut app, _) = app();
+        assert!(run_command(&mut app.world, "spawn sniper 3").is_ok());
+        assert!(run_command(&mut app.world, "spawn enemy_grunt").is_ok());
+        assert!(run_command(&mut app.world, "spawn dragon").is_err());
+        assert!(run_command(&mut app.world, "spawn grunt many").is_err());
+
+        let kinds: Vec<EnemyKind> = app.world.query::<&Enemy>().iter(&app.world).map(|enemy| enemy.kind).collect();
+        assert_eq!(kinds.len(), 4);
+        assert_eq!(kinds.iter().filter(|kind| **kind == EnemyKind::Sniper).count(), 3);
+    }
+
+    #[test]
+    fn health() {
+        let (mut app, player) = app();
+        assert!(run_command(&mut app.world, "health 250").is_ok());
+        let health = app.world.get::<Health>(player).unwrap();
+        assert_eq!((health.health, health.max_health), (250, 250));
+
+        assert!(run_command(&mut app.world, "health 10").is_ok());
+        let health = app.world.get::<Health>(player).unwrap();
+        assert_eq!((health.health, health.max_health), (10, 250));
+
+        assert!(run_command(&mut app.world, "health").is_err());
+    }
+
+    #[test]
+    fn god() {
+        let (mut app, player) = app();
+        assert!(run_command(&mut app.world, "god").is_ok());
+        assert!(app.world.get::<GodMode>(player).is_some());
+        assert!(run_command(&mut app.world, "god").is_ok());
+        assert!(app.world.get::<GodMode>(player).is_none());
+    }
+
+    #[test]
+    fn skip() {
+        let (mut app, player) = app();
+        assert!(run_command(&mut app.world, "spawn grunt 3").is_ok());
+        let enemies: Vec<Entity> = app.world.query_filtered::<Entity, With<Enemy>>().iter(&app.world).collect();
+        assert_eq!(enemies.len(), 3);
+
+        assert!(run_command(&mut app.world, "skip").is_ok());
+        let queue = app.world.get_resource::<DespawnQueue>().unwrap();
+        assert!(enemies.iter().all(|enemy| queue.contains(*enemy)));
+        assert!(!queue.contains(player));
+        assert!(enemies.iter().all(|enemy| app.world.get_entity(*enemy).is_some()));
+
+        despawn_queue_sys(&mut app.world);
+        assert!(app.world.query::<&Enemy>().iter(&app.world).next().is_none());
+        assert!(app.world.get_entity(player).is_some());
+    }
+
+    #[test]
+    fn timescale() {
+        let (mut app, _) = app();
+        assert!(run_command(&mut app.world, "timescale 0.5").is_ok());
+        assert_eq!(app.world.get_resource::<GameTime>().unwrap().scale, 0.5);
+        assert!(run_command(&mut app.world, "timescale -1").is_err());
+        assert!(run_command(&mut app.world, "timescale fast").is_err());
+        assert_eq!(app.world.get_resource::<GameTime>().unwrap().scale, 0.5);
+    }
+
+    #[test]
+    fn submitted_lines_run_on_update() {
+        let (mut app, _) = app();
+        app.world.get_resource_mut::<Console>().unwrap().submit("timescale 2");
+        app.world.get_resource_mut::<Console>().unwrap().submit("nonsense");
+        assert_eq!(app.world.get_resource::<GameTime>().unwrap().scale, 1.0);
+
+        console_run_sys(&mut app.world);
+        assert_eq!(app.world.get_resource::<GameTime>().unwrap().scale, 2.0);
+        let log = &app.world.get_resource::<Console>().unwrap().log;
+        assert!(log.iter().any(|line| line.starts_with("error: Unknown command 'nonsense'")), "{:?}", log);
+    }
+}
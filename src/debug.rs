@@ -0,0 +1,360 @@
+use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics, EntityCountDiagnosticsPlugin, FrameTimeDiagnosticsPlugin};
+use bevy::ecs::archetype::{Archetype, ArchetypeComponentId};
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::query::Access;
+use bevy::ecs::system::{IntoSystem, System, SystemId};
+use bevy::prelude::*;
+use bevy::utils::{AHasher, HashMap, HashSet};
+use crate::boss::HitZone;
+use crate::common::{CollisionBox, CollisionEvent, Velocity};
+use crate::console::ConsoleCommandsExt;
+use crate::enemy::{nearest_player, Enemy, FirePattern};
+use crate::interface::HudFonts;
+use crate::player::Player;
+use crate::projectile::Projectile;
+
+const TOGGLE_KEY: KeyCode       = KeyCode::F3;
+/// Drawn above everything else in the world
+const OVERLAY_Z: f32            = 50.;
+const LINE_WIDTH: f32           = 1.;
+const CONTACT_LINE_WIDTH: f32   = 3.;
+const CONTACT_COLOR: Color      = Color::YELLOW;
+const TARGET_COLOR: Color       = Color::rgba(1., 0.5, 0., 0.5);
+/// Length of a projectile's velocity vector per unit of speed
+const VELOCITY_SCALE: f32       = 0.1;
+/// Slowest timed systems listed in the overlay
+const SLOWEST_SYSTEMS: usize    = 8;
+/// Upper bits of the diagnostic ids for timed systems. The lower bits are a hash of the system name.
+const SYSTEM_TIMING_ID: u128    = 0x5a0f_2c1e_7b3d_4e8a << 64;
+
+/// Timed stages, with the diagnostic each is recorded under
+const STAGE_TIMINGS: [(&str, CoreStage, DiagnosticId); 3] = [
+    ("pre_update", CoreStage::PreUpdate, DiagnosticId::from_u128(0x5a0f_2c1e_7b3d_4e8a_9f60_1d2c_3b4a_0001)),
+    ("update", CoreStage::Update, DiagnosticId::from_u128(0x5a0f_2c1e_7b3d_4e8a_9f60_1d2c_3b4a_0002)),
+    ("post_update", CoreStage::PostUpdate, DiagnosticId::from_u128(0x5a0f_2c1e_7b3d_4e8a_9f60_1d2c_3b4a_0003)),
+];
+
+/// Whether the debug overlay is shown
+#[derive(Default)]
+pub struct DebugOverlay {
+    pub enabled: bool,
+}
+
+/// What a collision box belongs to, which decides its outline color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Layer {
+    Player,
+    Enemy,
+    BossZone,
+    PlayerProjectile,
+    EnemyProjectile,
+    Other,
+}
+
+impl Layer {
+    fn color(&self) -> Color {
+        match self {
+            Layer::Player           => Color::CYAN,
+            Layer::Enemy            => Color::ORANGE,
+            Layer::BossZone         => Color::PINK,
+            Layer::PlayerProjectile => Color::LIME_GREEN,
+            Layer::EnemyProjectile  => Color::RED,
+            Layer::Other            => Color::GRAY,
+        }
+    }
+}
+
+struct Line {
+    start: Vec2,
+    end: Vec2,
+    width: f32,
+    color: Color,
+}
+
+fn outline(lines: &mut Vec<Line>, centre: Vec2, size: Vec2, width: f32, color: Color) {
+    let half = size / 2.;
+    let corners = [
+        centre + Vec2::new(-half.x, -half.y),
+        centre + Vec2::new(half.x, -half.y),
+        centre + Vec2::new(half.x, half.y),
+        centre + Vec2::new(-half.x, half.y),
+    ];
+    for i in 0..4 {
+        lines.push(Line { start: corners[i], end: corners[(i + 1) % 4], width, color });
+    }
+}
+
+/// A sprite reused to draw one overlay line each frame
+#[derive(Component)]
+struct DebugLine;
+
+#[derive(Component)]
+struct DebugText;
+
+/// When each stage last started, for [`STAGE_TIMINGS`]
+#[derive(Default)]
+struct StageStarts(HashMap<&'static str, Instant>);
+
+/// Run times of the systems wrapped with [`TimedSystemExt::timed`]
+#[derive(Default)]
+pub struct SystemTimings {
+    /// Milliseconds spent in each timed system since the last frame, shared with every timed system
+    pending: Arc<Mutex<Vec<(Cow<'static, str>, f64)>>>,
+    /// Diagnostic each system's timings are recorded under, by short system name
+    ids: HashMap<String, DiagnosticId>,
+}
+
+/// A system which records how long each run takes in [`SystemTimings`]
+pub struct Timed<S> {
+    system: S,
+    pending: Option<Arc<Mutex<Vec<(Cow<'static, str>, f64)>>>>,
+}
+
+impl<S: System<In = (), Out = ()>> System for Timed<S> {
+    type In = ();
+    type Out = ();
+
+    fn name(&self) -> Cow<'static, str> { self.system.name() }
+    fn id(&self) -> SystemId { self.system.id() }
+    fn new_archetype(&mut self, archetype: &Archetype) { self.system.new_archetype(archetype) }
+    fn component_access(&self) -> &Access<ComponentId> { self.system.component_access() }
+    fn archetype_component_access(&self) -> &Access<ArchetypeComponentId> { self.system.archetype_component_access() }
+    fn is_send(&self) -> bool { self.system.is_send() }
+
+    unsafe fn run_unsafe(&mut self, input: (), world: &World) {
+        let start = Instant::now();
+        self.system.run_unsafe(input, world);
+        if let Some(pending) = &self.pending {
+            pending.lock().unwrap().push((self.system.name(), start.elapsed().as_secs_f64() * 1000.));
+        }
+    }
+
+    fn apply_buffers(&mut self, world: &mut World) { self.system.apply_buffers(world) }
+
+    fn initialize(&mut self, world: &mut World) {
+        self.pending = Some(world.get_resource_or_insert_with(SystemTimings::default).pending.clone());
+        self.system.initialize(world);
+    }
+
+    fn check_change_tick(&mut self, change_tick: u32) { self.system.check_change_tick(change_tick) }
+}
+
+pub trait TimedSystemExt<Params>: IntoSystem<(), (), Params> {
+    /// Wraps the system so its run time is shown in the debug overlay
+    fn timed(self) -> Timed<Self::System> {
+        Timed { system: IntoSystem::into_system(self), pending: None }
+    }
+}
+
+impl<Params, S: IntoSystem<(), (), Params>> TimedSystemExt<Params> for S {}
+
+/// Records the frame's system timings as diagnostics, registering one for each new system
+fn system_timing_sys(world: &mut World) {
+    world.resource_scope(|world, mut timings: Mut<SystemTimings>| {
+        let measurements = std::mem::take(&mut *timings.pending.lock().unwrap());
+        let mut diagnostics = world.get_resource_mut::<Diagnostics>().unwrap();
+
+        let mut totals: HashMap<String, f64> = HashMap::default();
+        for (name, ms) in measurements {
+            let short = name.rsplit("::").next().unwrap_or(&name).to_string();
+            *totals.entry(short).or_default() += ms;
+        }
+        for (name, ms) in totals {
+            let id = *timings.ids.entry(name.clone()).or_insert_with(|| {
+                let mut hasher = AHasher::default();
+                name.hash(&mut hasher);
+                let id = DiagnosticId::from_u128(SYSTEM_TIMING_ID | hasher.finish() as u128);
+                diagnostics.add(Diagnostic::new(id, name.clone(), 20).with_suffix("ms"));
+                id
+            });
+            diagnostics.add_measurement(id, ms);
+        }
+    });
+}
+
+fn debug_toggle_sys(mut overlay: ResMut<DebugOverlay>, keyboard_input: Res<Input<KeyCode>>) {
+    if keyboard_input.just_pressed(TOGGLE_KEY) {
+        overlay.enabled = !overlay.enabled;
+    }
+}
+
+/// Draws collision boxes colored by layer, contacts, projectile velocities and enemy aim
+fn debug_draw_sys(
+    mut cmd: Commands,
+    overlay: Res<DebugOverlay>,
+    colliders: Query<(Entity, &GlobalTransform, &CollisionBox, Option<&Player>, Option<&Enemy>, Option<&HitZone>, Option<&Projectile>)>,
+    players: Query<(&GlobalTransform, &Velocity), With<Player>>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut pool: Query<(&mut Transform, &mut Sprite, &mut Visibility), With<DebugLine>>) {
+    let mut lines = Vec::new();
+
+    if overlay.enabled {
+        let contacts: HashSet<Entity> = collision_events.iter().flat_map(|event| [event.a, event.b]).collect();
+
+        for (entity, transform, collision_box, player, enemy, zone, projectile) in colliders.iter() {
+            let position = transform.translation.truncate();
+            let layer = match (player, enemy, zone, projectile) {
+                (Some(_), ..) => Layer::Player,
+                (_, Some(_), ..) => Layer::Enemy,
+                (_, _, Some(_), _) => Layer::BossZone,
+                (.., Some(projectile)) if projectile.owner.is_player() => Layer::PlayerProjectile,
+                (.., Some(_)) => Layer::EnemyProjectile,
+                _ => Layer::Other,
+            };
+
+            if contacts.contains(&entity) {
+                outline(&mut lines, position, collision_box.size, CONTACT_LINE_WIDTH, CONTACT_COLOR);
+            } else {
+                outline(&mut lines, position, collision_box.size, LINE_WIDTH, layer.color());
+            }
+
+            if let Some(projectile) = projectile {
+                let end = position + projectile.direction * projectile.speed() * VELOCITY_SCALE;
+                lines.push(Line { start: position, end, width: LINE_WIDTH, color: layer.color() });
+            }
+
+            if let Some(enemy) = enemy {
+                let aims = matches!(enemy.fire_pattern, FirePattern::Aimed { .. } | FirePattern::Fan { .. });
+                if let Some((target, _)) = nearest_player(position, players.iter()).filter(|_| aims) {
+                    lines.push(Line { start: position, end: target, width: LINE_WIDTH, color: TARGET_COLOR });
+                }
+            }
+        }
+    } else {
+        collision_events.iter().for_each(drop);
+    }
+
+    // Reuse the pooled line sprites, hiding any left over and spawning more if needed
+    let mut pool = pool.iter_mut();
+    for line in lines.iter() {
+        let delta = line.end - line.start;
+        let midpoint = (line.start + line.end) / 2.;
+        let transform = Transform {
+            translation: midpoint.extend(OVERLAY_Z),
+            rotation: Quat::from_rotation_z(delta.y.atan2(delta.x)),
+            ..Default::default()
+        };
+        let size = Some(Vec2::new(delta.length().max(line.width), line.width));
+
+        match pool.next() {
+            Some((mut line_transform, mut sprite, mut visibility)) => {
+                *line_transform = transform;
+                sprite.color = line.color;
+                sprite.custom_size = size;
+                visibility.is_visible = true;
+            }
+            None => {
+                cmd.spawn_bundle(SpriteBundle {
+                    sprite: Sprite { color: line.color, custom_size: size, ..Default::default() },
+                    transform,
+                    ..Default::default()
+                }).insert(DebugLine);
+            }
+        }
+    }
+    for (_, _, mut visibility) in pool {
+        visibility.is_visible = false;
+    }
+}
+
+/// Shows FPS, entity counts, stage timings and the slowest timed systems
+fn debug_text_sys(
+    mut cmd: Commands,
+    overlay: Res<DebugOverlay>,
+    diagnostics: Res<Diagnostics>,
+    timings: Res<SystemTimings>,
+    fonts: Option<Res<HudFonts>>,
+    mut texts: Query<(Entity, &mut Text), With<DebugText>>,
+    players: Query<(), With<Player>>,
+    enemies: Query<(), With<Enemy>>,
+    projectiles: Query<(), With<Projectile>>) {
+    if !overlay.enabled {
+        for (entity, _) in texts.iter() {
+            cmd.entity(entity).despawn();
+        }
+        return
+    }
+
+    let average = |id: DiagnosticId| diagnostics.get(id).and_then(|diagnostic| diagnostic.average()).unwrap_or(0.);
+    let mut value = format!(
+        "FPS {:.0} ({:.1} ms)\nentities {:.0}: {} players, {} enemies, {} projectiles",
+        average(FrameTimeDiagnosticsPlugin::FPS),
+        average(FrameTimeDiagnosticsPlugin::FRAME_TIME) * 1000.,
+        average(EntityCountDiagnosticsPlugin::ENTITY_COUNT),
+        players.iter().count(),
+        enemies.iter().count(),
+        projectiles.iter().count());
+    for (name, _, id) in STAGE_TIMINGS.iter() {
+        value.push_str(&format!("\n{} {:.2} ms", name, average(*id)));
+    }
+    let mut systems: Vec<(&String, f64)> = timings.ids.iter().map(|(name, id)| (name, average(*id))).collect();
+    systems.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    for (name, ms) in systems.into_iter().take(SLOWEST_SYSTEMS) {
+        value.push_str(&format!("\n  {} {:.3} ms", name, ms));
+    }
+
+    match texts.iter_mut().next() {
+        Some((_, mut text)) => text.sections[0].value = value,
+        None => if let Some(fonts) = fonts {
+            cmd.spawn_bundle(TextBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: Rect { top: Val::Px(90.), left: Val::Px(15.), ..Default::default() },
+                    ..Default::default()
+                },
+                text: Text::with_section(value, TextStyle { font: fonts.regular.clone(), font_size: 14., color: Color::YELLOW }, Default::default()),
+                ..Default::default()
+            }).insert(DebugText);
+        }
+    }
+}
+
+fn stage_start_sys(name: &'static str) -> impl FnMut(&mut World) + Send + Sync + 'static {
+    move |world: &mut World| {
+        world.get_resource_or_insert_with(StageStarts::default).0.insert(name, Instant::now());
+    }
+}
+
+fn stage_end_sys(name: &'static str, id: DiagnosticId) -> impl FnMut(&mut World) + Send + Sync + 'static {
+    move |world: &mut World| {
+        let start = world.get_resource::<StageStarts>().and_then(|starts| starts.0.get(name).copied());
+        if let (Some(start), Some(mut diagnostics)) = (start, world.get_resource_mut::<Diagnostics>()) {
+            diagnostics.add_measurement(id, start.elapsed().as_secs_f64() * 1000.);
+        }
+    }
+}
+
+fn debug_command(world: &mut World, _: &[&str]) -> Result<String, String> {
+    let mut overlay = world.get_resource_mut::<DebugOverlay>().ok_or("No debug overlay")?;
+    overlay.enabled = !overlay.enabled;
+    Ok(format!("Debug overlay {}", if overlay.enabled { "on" } else { "off" }))
+}
+
+/// Debug overlay, toggled with F3 or the `debug` console command
+pub struct DebugOverlayPlugin;
+
+impl Plugin for DebugOverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<DebugOverlay>()
+            .init_resource::<StageStarts>()
+            .init_resource::<SystemTimings>()
+            .add_plugin(FrameTimeDiagnosticsPlugin::default())
+            .add_plugin(EntityCountDiagnosticsPlugin::default())
+            .add_console_command("debug", "Toggle the debug overlay", debug_command)
+            .add_system(debug_toggle_sys)
+            .add_system(debug_draw_sys)
+            .add_system(debug_text_sys)
+            .add_system_to_stage(CoreStage::Last, system_timing_sys.exclusive_system().at_end());
+
+        for (name, stage, id) in STAGE_TIMINGS {
+            app.add_system_to_stage(stage, stage_start_sys(name).exclusive_system().at_start())
+                .add_system_to_stage(stage, stage_end_sys(name, id).exclusive_system().at_end());
+            app.world.get_resource_or_insert_with(Diagnostics::default)
+                .add(Diagnostic::new(id, name, 20).with_suffix("ms"));
+        }
+    }
+}
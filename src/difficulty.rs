@@ -0,0 +1,163 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use crate::common::{DeathEvent, GameTime, Victim};
+use crate::enemy::Enemy;
+use crate::boss::HitZone;
+use crate::manager::{CurrentLevel, GameState};
+use crate::projectile::{Projectile, ProjectileHitEvent};
+use crate::settings::{Difficulty, Settings};
+
+/// Largest adjustment adaptive difficulty can make to the preset multipliers
+const MAX_ADAPTIVE_OFFSET: f32 = 0.3;
+const DEATH_NUDGE: f32         = -0.1;
+const WAVE_NUDGE: f32          = 0.05;
+const HIGH_ACCURACY: f32       = 0.6;
+const LOW_ACCURACY: f32        = 0.25;
+const FAST_WAVE_SECS: f32      = 20.;
+const SLOW_WAVE_SECS: f32      = 60.;
+
+/// Multipliers applied to gameplay values for a difficulty preset
+#[derive(Debug, Clone, Copy)]
+pub struct DifficultyPreset {
+    pub enemy_health: f32,
+    pub enemy_speed: f32,
+    pub fire_rate: f32,
+    pub projectile_damage: f32,
+    pub score: f32,
+}
+
+impl Difficulty {
+    pub fn preset(&self) -> DifficultyPreset {
+        match self {
+            Difficulty::Easy   => DifficultyPreset { enemy_health: 0.75, enemy_speed: 0.8, fire_rate: 0.6, projectile_damage: 0.6,  score: 0.75 },
+            Difficulty::Normal => DifficultyPreset { enemy_health: 1.0,  enemy_speed: 1.0, fire_rate: 1.0, projectile_damage: 1.0,  score: 1.0 },
+            Difficulty::Hard   => DifficultyPreset { enemy_health: 1.3,  enemy_speed: 1.2, fire_rate: 1.4, projectile_damage: 1.25, score: 1.5 },
+            Difficulty::Insane => DifficultyPreset { enemy_health: 1.75, enemy_speed: 1.4, fire_rate: 2.0, projectile_damage: 1.6,  score: 2.0 },
+        }
+    }
+}
+
+/// The difficulty currently in effect. Spawn and shoot systems read their multipliers from here.
+#[derive(Clone)]
+pub struct DifficultyScaling {
+    pub difficulty: Difficulty,
+    pub preset: DifficultyPreset,
+    /// Offset added to every multiplier except score by adaptive difficulty, within ±[`MAX_ADAPTIVE_OFFSET`]
+    pub adaptive_offset: f32,
+    performance: Performance,
+}
+
+/// Player performance since the start of the current wave
+#[derive(Clone, Default)]
+struct Performance {
+    shots: u32,
+    /// Player projectiles which hit at least once, so piercing and explosive shots count a single hit
+    hit_projectiles: HashSet<Entity>,
+    wave: u32,
+    wave_time: f32,
+}
+
+impl Default for DifficultyScaling {
+    fn default() -> Self {
+        DifficultyScaling::new(Difficulty::Normal)
+    }
+}
+
+impl DifficultyScaling {
+    pub fn new(difficulty: Difficulty) -> Self {
+        Self {
+            difficulty,
+            preset: difficulty.preset(),
+            adaptive_offset: 0.,
+            performance: Default::default(),
+        }
+    }
+
+    fn adapt(&self, value: f32) -> f32 {
+        value * (1.0 + self.adaptive_offset)
+    }
+
+    pub fn enemy_health(&self) -> f32 { self.adapt(self.preset.enemy_health) }
+    pub fn enemy_speed(&self) -> f32 { self.adapt(self.preset.enemy_speed) }
+    pub fn fire_rate(&self) -> f32 { self.adapt(self.preset.fire_rate) }
+    pub fn projectile_damage(&self) -> f32 { self.adapt(self.preset.projectile_damage) }
+    pub fn score(&self) -> f32 { self.preset.score }
+
+    pub fn scale_health(&self, health: i32) -> i32 {
+        ((health as f32 * self.enemy_health()).round() as i32).max(1)
+    }
+
+    pub fn scale_damage(&self, damage: i32) -> i32 {
+        (damage as f32 * self.projectile_damage()).round() as i32
+    }
+
+    pub fn scale_score(&self, score: i32) -> i32 {
+        (score as f32 * self.score()).round() as i32
+    }
+
+    fn nudge(&mut self, amount: f32) {
+        self.adaptive_offset = (self.adaptive_offset + amount).clamp(-MAX_ADAPTIVE_OFFSET, MAX_ADAPTIVE_OFFSET);
+        debug!("Adaptive difficulty offset now {}", self.adaptive_offset);
+    }
+}
+
+/// Resets scaling to the preset chosen in [`Settings`], clearing any adaptive adjustment
+fn difficulty_reset_sys(mut scaling: ResMut<DifficultyScaling>, settings: Res<Settings>) {
+    *scaling = DifficultyScaling::new(settings.difficulty);
+}
+
+fn difficulty_settings_sys(scaling: ResMut<DifficultyScaling>, settings: Res<Settings>) {
+    if settings.is_changed() {
+        difficulty_reset_sys(scaling, settings);
+    }
+}
+
+/// Nudges difficulty from player deaths, accuracy and time taken per wave when adaptive difficulty is enabled
+fn adaptive_difficulty_sys(
+    mut scaling: ResMut<DifficultyScaling>,
+    settings: Res<Settings>,
+    level: Res<CurrentLevel>,
+    time: Res<GameTime>,
+    mut death_events: EventReader<DeathEvent>,
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    fired: Query<&Projectile, Added<Projectile>>,
+    targets: Query<Entity, Or<(With<Enemy>, With<HitZone>)>>) {
+    if !settings.adaptive_difficulty { return }
+
+    let scaling = &mut *scaling;
+    scaling.performance.wave_time += time.delta_seconds();
+    scaling.performance.shots += fired.iter().filter(|projectile| projectile.owner.is_player()).count() as u32;
+    let hits = hit_events.iter().filter(|hit| hit.owner.is_player() && targets.get(hit.other).is_ok());
+    scaling.performance.hit_projectiles.extend(hits.map(|hit| hit.projectile));
+
+    for death in death_events.iter() {
+        if death.victim == Victim::Player {
+            scaling.nudge(DEATH_NUDGE);
+        }
+    }
+
+    if level.wave != scaling.performance.wave {
+        let performance = std::mem::take(&mut scaling.performance);
+        scaling.performance.wave = level.wave;
+        if performance.wave == 0 { return }
+
+        let accuracy = if performance.shots > 0 { performance.hit_projectiles.len() as f32 / performance.shots as f32 } else { 0. };
+        if accuracy > HIGH_ACCURACY { scaling.nudge(WAVE_NUDGE) }
+        if accuracy < LOW_ACCURACY { scaling.nudge(-WAVE_NUDGE) }
+        if performance.wave_time < FAST_WAVE_SECS { scaling.nudge(WAVE_NUDGE) }
+        if performance.wave_time > SLOW_WAVE_SECS { scaling.nudge(-WAVE_NUDGE) }
+    }
+}
+
+pub struct DifficultyPlugin;
+
+impl Plugin for DifficultyPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<DifficultyScaling>()
+            .add_system(difficulty_settings_sys)
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(difficulty_reset_sys))
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(adaptive_difficulty_sys));
+    }
+}
@@ -0,0 +1,167 @@
+use bevy::prelude::*;
+use bevy::render::camera::CAMERA_2D;
+use bevy::utils::HashMap;
+use rand::prelude::*;
+use crate::boss::BossDefeatedEvent;
+use crate::common::{DeathEvent, GameTime, Victim};
+use crate::manager::GameState;
+use crate::player::Player;
+use crate::projectile::ProjectileHitEvent;
+use crate::settings::Settings;
+
+/// Camera offset in units at full trauma
+const MAX_SHAKE_OFFSET: f32   = 18.;
+const MAX_SHAKE_ANGLE: f32    = 0.04;
+/// Trauma removed per second
+const TRAUMA_DECAY: f32       = 1.5;
+const PLAYER_HIT_TRAUMA: f32  = 0.3;
+const PLAYER_DEATH_TRAUMA: f32 = 0.6;
+const ENEMY_DEATH_TRAUMA: f32 = 0.15;
+const BOSS_DEATH_TRAUMA: f32  = 1.0;
+const ENEMY_HIT_STOP: f32     = 0.04;
+const PLAYER_HIT_STOP: f32    = 0.1;
+const BOSS_HIT_STOP: f32      = 0.35;
+const FLASH_SECS: f32         = 0.08;
+const FLASH_COLOR: Color      = Color::WHITE;
+/// Sprite sheet art is drawn untinted, so it flashes by tinting instead
+const FLASH_TINT: Color       = Color::rgb(1.0, 0.35, 0.35);
+
+/// Trauma based screen shake. Shake strength is the square of trauma, which decays over time.
+#[derive(Default)]
+pub struct ScreenShake {
+    trauma: f32,
+    /// Offset currently applied to the camera, removed again before the next one is applied
+    applied: Vec3,
+    applied_angle: f32,
+}
+
+impl ScreenShake {
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).min(1.0);
+    }
+}
+
+/// Sprites currently flashing, with their flash timer and own color. Kept outside the entities
+/// so a flash never has to be inserted on an entity that is being de-spawned.
+#[derive(Default)]
+struct DamageFlashes(HashMap<Entity, (Timer, Color)>);
+
+/// Shakes the 2D camera while there is trauma left
+fn screen_shake_sys(
+    mut shake: ResMut<ScreenShake>,
+    mut cameras: Query<(&Camera, &mut Transform)>,
+    settings: Res<Settings>,
+    time: Res<Time>) {
+    let shake = &mut *shake;
+    if !settings.accessibility.screen_shake { shake.trauma = 0. }
+    shake.trauma = (shake.trauma - TRAUMA_DECAY * time.delta_seconds()).max(0.);
+
+    let mut rng = rand::thread_rng();
+    let strength = shake.trauma * shake.trauma;
+    let offset = Vec3::new(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0), 0.) * MAX_SHAKE_OFFSET * strength;
+    let angle = rng.gen_range(-1.0..=1.0) * MAX_SHAKE_ANGLE * strength;
+
+    for (camera, mut transform) in cameras.iter_mut() {
+        if camera.name.as_deref() != Some(CAMERA_2D) { continue }
+        transform.translation += offset - shake.applied;
+        transform.rotation = Quat::from_rotation_z(angle - shake.applied_angle) * transform.rotation;
+    }
+    shake.applied = offset;
+    shake.applied_angle = angle;
+}
+
+/// Adds shake, hit-stop and flashes for damage and deaths
+fn feedback_event_sys(
+    mut shake: ResMut<ScreenShake>,
+    mut flashes: ResMut<DamageFlashes>,
+    mut game_time: ResMut<GameTime>,
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    mut death_events: EventReader<DeathEvent>,
+    mut boss_defeated_events: EventReader<BossDefeatedEvent>,
+    sprites: Query<&Sprite>,
+    players: Query<Entity, With<Player>>,
+    settings: Res<Settings>) {
+    let accessibility = &settings.accessibility;
+    let is_player = |entity: Entity| players.get(entity).is_ok();
+
+    for hit in hit_events.iter() {
+        if is_player(hit.other) && accessibility.screen_shake {
+            shake.add_trauma(PLAYER_HIT_TRAUMA);
+        }
+        if !accessibility.damage_flash { continue }
+        match flashes.0.get_mut(&hit.other) {
+            Some((timer, _)) => timer.reset(),
+            None => if let Ok(sprite) = sprites.get(hit.other) {
+                flashes.0.insert(hit.other, (Timer::from_seconds(FLASH_SECS, false), sprite.color));
+            }
+        }
+    }
+
+    for death in death_events.iter() {
+        let (trauma, hit_stop) = match death.victim {
+            Victim::Player   => (PLAYER_DEATH_TRAUMA, PLAYER_HIT_STOP),
+            Victim::Enemy(_) => (ENEMY_DEATH_TRAUMA, ENEMY_HIT_STOP),
+            Victim::Other    => continue,
+        };
+        if accessibility.screen_shake { shake.add_trauma(trauma) }
+        if accessibility.hit_stop { game_time.hit_stop(hit_stop) }
+    }
+
+    for _ in boss_defeated_events.iter() {
+        if accessibility.screen_shake { shake.add_trauma(BOSS_DEATH_TRAUMA) }
+        if accessibility.hit_stop { game_time.hit_stop(BOSS_HIT_STOP) }
+    }
+}
+
+/// Shows the flash color until the flash runs out, then restores the sprite's color
+fn damage_flash_sys(
+    mut flashes: ResMut<DamageFlashes>,
+    mut sprites: Query<(&mut Sprite, Option<&Children>)>,
+    mut art: Query<&mut TextureAtlasSprite>,
+    time: Res<Time>) {
+    flashes.0.retain(|&entity, (timer, color)| {
+        let (mut sprite, children) = match sprites.get_mut(entity) {
+            Ok(sprite) => sprite,
+            Err(_) => return false,
+        };
+        let flashing = !timer.tick(time.delta()).finished();
+        sprite.color = if flashing { FLASH_COLOR } else { *color };
+        for &child in children.iter().flat_map(|children| children.iter()) {
+            if let Ok(mut art_sprite) = art.get_mut(child) {
+                art_sprite.color = if flashing { FLASH_TINT } else { Color::WHITE };
+            }
+        }
+        flashing
+    });
+}
+
+/// Puts the camera back and clears trauma and hit-stop when a run ends
+fn effects_reset_sys(
+    mut shake: ResMut<ScreenShake>,
+    mut flashes: ResMut<DamageFlashes>,
+    mut game_time: ResMut<GameTime>,
+    mut cameras: Query<(&Camera, &mut Transform)>) {
+    for (camera, mut transform) in cameras.iter_mut() {
+        if camera.name.as_deref() != Some(CAMERA_2D) { continue }
+        transform.translation -= shake.applied;
+        transform.rotation = Quat::from_rotation_z(-shake.applied_angle) * transform.rotation;
+    }
+    *shake = Default::default();
+    flashes.0.clear();
+    game_time.clear_hit_stop();
+}
+
+pub struct EffectsPlugin;
+
+impl Plugin for EffectsPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<ScreenShake>()
+            .init_resource::<DamageFlashes>()
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(screen_shake_sys)
+                .with_system(feedback_event_sys)
+                .with_system(damage_flash_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(effects_reset_sys));
+    }
+}
@@ -1,33 +1,166 @@
+use std::cmp::Ordering;
 use bevy::ecs::query::QueryEntityError;
 use bevy::prelude::*;
 use rand::prelude::*;
+use serde::Deserialize;
 use crate::common::{Direction, *};
 use crate::projectile::*;
 use crate::player::*;
+use crate::manager::GameState;
+use crate::difficulty::DifficultyScaling;
+use crate::level::EnemyWave;
+use crate::prefab::{Prefab, Prefabs};
+use crate::debug::TimedSystemExt;
 
 const WINDOW_MARGIN: f32             = 100.;
 const DEFAULT_MOVE_SPEED: f32        = 150.;
-const DEFAULT_PROJECTILE_DAMAGE: i32 = 10;
 const ENEMY_VERT_SPACING: f32        = 50.;
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Enemy {
+    pub kind: EnemyKind,
     move_direction: Direction,
     move_speed: f32,
+    /// Set from the `ai` section of the kind's prefab
+    pub fire_pattern: FirePattern,
+    /// Rotation applied to the next volley, advanced by [`FirePattern::Spiral`]
+    fire_angle: f32,
 }
 
 impl Default for Enemy {
     fn default() -> Self {
         Self {
+            kind: EnemyKind::Grunt,
             move_direction: Direction::RIGHT,
             move_speed: DEFAULT_MOVE_SPEED,
+            fire_pattern: FirePattern::Straight,
+            fire_angle: 0.,
         }
     }
 }
 
+/// The different kinds of [`Enemy`]. Each kind's [`FirePattern`] is given by its prefab.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum EnemyKind {
+    /// Fires straight down
+    Grunt,
+    /// Fires at the nearest player, leading their movement
+    Sniper,
+    /// Fires slow turning homing missiles at the nearest player
+    Bomber,
+    /// Fires radial bursts which rotate every volley
+    Spinner,
+    /// Fires a fan of projectiles centred on the nearest player
+    Gunner,
+}
+
+impl EnemyKind {
+    pub const ALL: [EnemyKind; 5] = [EnemyKind::Grunt, EnemyKind::Sniper, EnemyKind::Bomber, EnemyKind::Spinner, EnemyKind::Gunner];
+
+    /// Name used for the kind's prefab and art, such as `enemy_grunt`
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnemyKind::Grunt   => "enemy_grunt",
+            EnemyKind::Sniper  => "enemy_sniper",
+            EnemyKind::Bomber  => "enemy_bomber",
+            EnemyKind::Spinner => "enemy_spinner",
+            EnemyKind::Gunner  => "enemy_gunner",
+        }
+    }
+}
+
+/// Directions an enemy fires in for each volley
+#[derive(Debug, Clone, Deserialize)]
+pub enum FirePattern {
+    /// A single projectile straight down
+    Straight,
+    /// A single projectile at the nearest player. If `lead` is set the projectile is aimed where the player will be.
+    Aimed { lead: bool },
+    /// `count` projectiles spread evenly in a circle
+    Radial { count: u32 },
+    /// As [`FirePattern::Radial`] but rotated by `step` radians every volley
+    Spiral { count: u32, step: f32 },
+    /// `count` projectiles spread over `spread` radians, centred on the nearest player
+    Fan { count: u32, spread: f32 },
+}
+
+impl FirePattern {
+    /// Directions of the projectiles in the next volley fired from `position`.
+    /// `target` is the position and velocity of the nearest player, if there is one.
+    /// `angle` is the shooter's current rotation, advanced by [`FirePattern::Spiral`].
+    pub fn volley(&self, angle: &mut f32, position: Vec2, target: Option<(Vec2, Vec2)>, projectile_speed: f32) -> Vec<Vec2> {
+        let aim = |lead: bool| match target {
+            Some((target_position, _)) if !lead => (target_position - position).normalize_or_zero(),
+            Some((target_position, target_velocity)) => lead_direction(position, target_position, target_velocity, projectile_speed),
+            None => Direction::DOWN.vec(),
+        };
+
+        match *self {
+            FirePattern::Straight => vec![Direction::DOWN.vec()],
+            FirePattern::Aimed { lead } => vec![aim(lead)],
+            FirePattern::Radial { count } => radial(Direction::DOWN.vec(), count),
+            FirePattern::Spiral { count, step } => {
+                *angle = (*angle + step) % std::f32::consts::TAU;
+                radial(rotate(Direction::DOWN.vec(), *angle), count)
+            }
+            FirePattern::Fan { count, spread } => {
+                let centre = aim(false);
+                if count <= 1 { return vec![centre] }
+                (0..count)
+                    .map(|i| rotate(centre, -spread / 2.0 + spread * i as f32 / (count - 1) as f32))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Position and velocity of the [`Player`] nearest to `position`
+pub fn nearest_player<'a>(position: Vec2, players: impl Iterator<Item = (&'a GlobalTransform, &'a Velocity)>) -> Option<(Vec2, Vec2)> {
+    players
+        .map(|(player_transform, velocity)| (player_transform.translation.truncate(), velocity.0))
+        .min_by(|(a, _), (b, _)| a.distance_squared(position).partial_cmp(&b.distance_squared(position)).unwrap_or(Ordering::Equal))
+}
+
+/// `count` directions spread evenly around a circle, starting at `start`
+fn radial(start: Vec2, count: u32) -> Vec<Vec2> {
+    (0..count)
+        .map(|i| rotate(start, std::f32::consts::TAU * i as f32 / count as f32))
+        .collect()
+}
+
+/// Direction to fire from `from` so a projectile travelling at `speed` meets a target currently
+/// at `to` moving with `velocity`. Falls back to aiming directly at the target if it can't be caught.
+pub fn lead_direction(from: Vec2, to: Vec2, velocity: Vec2, speed: f32) -> Vec2 {
+    let offset = to - from;
+    let a = velocity.length_squared() - speed * speed;
+    let b = 2.0 * offset.dot(velocity);
+    let c = offset.length_squared();
+
+    let time = if a.abs() < f32::EPSILON {
+        if b.abs() < f32::EPSILON { 0.0 } else { -c / b }
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            0.0
+        } else {
+            let root = discriminant.sqrt();
+            let (t1, t2) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+            match (t1 > 0.0, t2 > 0.0) {
+                (true, true) => t1.min(t2),
+                (true, false) => t1,
+                (false, true) => t2,
+                (false, false) => 0.0,
+            }
+        }
+    };
+
+    (offset + velocity * time.max(0.0)).normalize_or_zero()
+}
+
 #[derive(Bundle)]
 pub struct EnemyBundle {
     pub enemy: Enemy,
+    pub team: Team,
     pub health: Health,
     pub shooter: Shooter,
     pub collision_box: CollisionBox,
@@ -40,16 +173,39 @@ impl Default for EnemyBundle {
     fn default() -> Self {
         Self {
             enemy: Default::default(),
+            team: Team::Enemy,
             health: Default::default(),
             shooter: Default::default(),
-            sprite: Default::default(),
+            sprite: SpriteBundle {
+                sprite: Sprite {
+                    color: Color::FUCHSIA,
+                    custom_size: Some(Vec2::new(25.0, 25.0)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
             collision_box: CollisionBox{ size: Vec2::new(15., 15.)}
         }
     }
 }
 
+impl EnemyBundle {
+    /// Replaces component values with any given by `prefab`
+    pub fn with_prefab(mut self, prefab: &Prefab) -> Self {
+        prefab.apply_sprite(&mut self.sprite.sprite);
+        prefab.apply_health(&mut self.health);
+        prefab.apply_collision_box(&mut self.collision_box);
+        prefab.apply_shooter(&mut self.shooter);
+        if let Some(ai) = &prefab.ai {
+            self.enemy.move_speed = ai.move_speed;
+            self.enemy.fire_pattern = ai.fire_pattern.clone();
+        }
+        self
+    }
+}
+
 /// System to move [`Enemy`] horizontally over screen and swap their direction when they reach each side.
-pub fn enemy_move_sys(mut enemy_transforms: Query<(&mut Enemy, &mut Transform), With<Enemy>>, time: Res<Time>, windows: Res<Windows>) {
+pub fn enemy_move_sys(mut enemy_transforms: Query<(&mut Enemy, &mut Transform), With<Enemy>>, time: Res<GameTime>, windows: Res<Windows>, difficulty: Res<DifficultyScaling>) {
     match windows.get_primary() {
         Some(window) => {
             for (mut enemy, mut transform) in enemy_transforms.iter_mut() {
@@ -61,9 +217,10 @@ pub fn enemy_move_sys(mut enemy_transforms: Query<(&mut Enemy, &mut Transform),
                     enemy.move_direction = Direction::RIGHT
                 }
 
+                let move_speed = enemy.move_speed * difficulty.enemy_speed();
                 match &enemy.move_direction {
-                    Direction::LEFT  => { transform.translation.x = transform.translation.x - enemy.move_speed * time.delta_seconds() }
-                    Direction::RIGHT => { transform.translation.x = transform.translation.x + enemy.move_speed * time.delta_seconds() }
+                    Direction::LEFT  => { transform.translation.x = transform.translation.x - move_speed * time.delta_seconds() }
+                    Direction::RIGHT => { transform.translation.x = transform.translation.x + move_speed * time.delta_seconds() }
                     _ => {}
                 }
             }
@@ -72,54 +229,55 @@ pub fn enemy_move_sys(mut enemy_transforms: Query<(&mut Enemy, &mut Transform),
     }
 }
 
-/// Enemies shoot straight down by random choice and interval
-pub fn enemy_shoot_sys(mut cmd: Commands, mut enemy_shooter: Query<(Entity, &mut Shooter, &Transform), With<Enemy>>) {
-    let other_enemies: Vec<Entity> = enemy_shooter.iter().map(|(e, _, _)| { e}).collect();
-    for (entity, shooter, transform) in enemy_shooter.iter_mut() {
-        if shooter.fire_rate > rand::random::<f32>() {
-            cmd.spawn_bundle(ProjectileBundle {
-                projectile: Projectile {
-                    direction: Direction::DOWN,
-                    damage: DEFAULT_PROJECTILE_DAMAGE,
-                    speed_multiplier: Default::default(),
-                    origin: Some(entity.clone()),
-                    whitelist: other_enemies.clone(),
-                },
-                sprite: SpriteBundle {
-                    sprite: Sprite {
-                        color: Color::CRIMSON,
-                        custom_size: Some(Vec2::new(15.0, 15.0)),
-                        ..Default::default()
-                    },
-                    transform: Transform::from_xyz(transform.translation.x, transform.translation.y, transform.translation.z),
-                    ..Default::default()
-                },
+/// Enemies fire their [`FirePattern`] by random choice and interval
+pub fn enemy_shoot_sys(
+    mut cmd: Commands,
+    mut enemy_shooter: Query<(Entity, &mut Enemy, &Shooter, &Transform)>,
+    players: Query<(&GlobalTransform, &Velocity), With<Player>>,
+    prefabs: Res<Prefabs>,
+    difficulty: Res<DifficultyScaling>,
+    time: Res<GameTime>) {
+    for (entity, mut enemy, shooter, transform) in enemy_shooter.iter_mut() {
+        if fire_chance(shooter.fire_rate * difficulty.fire_rate(), time.delta_seconds()) > rand::random::<f32>() {
+            let position = transform.translation.truncate();
+            let target = nearest_player(position, players.iter());
+
+            let projectile = Projectile {
+                damage: difficulty.scale_damage(shooter.damage),
+                origin: Some(entity.clone()),
+                modifiers: shooter.modifiers.clone(),
                 ..Default::default()
-            });
+            };
+
+            let bundle = ProjectileBundle { projectile, ..Default::default() }.with_prefab(&prefabs.projectile(shooter));
+            let pattern = enemy.fire_pattern.clone();
+            for direction in pattern.volley(&mut enemy.fire_angle, position, target, bundle.projectile.speed()) {
+                let mut sprite = bundle.sprite.clone();
+                sprite.transform = Transform::from_xyz(transform.translation.x, transform.translation.y, transform.translation.z);
+                cmd.spawn_bundle(ProjectileBundle {
+                    projectile: Projectile { direction, ..bundle.projectile.clone() },
+                    team: bundle.team,
+                    collision_box: CollisionBox { size: bundle.collision_box.size },
+                    sprite,
+                });
+            }
         }
     }
 }
 
-/// Reduces enemy health and increases player score when hit
+/// Reduces enemy health when hit, increasing the score of the player who fired
 fn enemy_hit_sys(
-    mut hit_events: EventReader<CollisionEvent>,
+    mut hit_events: EventReader<ProjectileHitEvent>,
     mut players: Query<&mut Player>,
     mut enemy_healths: Query<&mut Health, With<Enemy>>,
-    projectiles: Query<&Projectile>) {
-    for &CollisionEvent{a, b, ..} in hit_events.iter() {
-        match enemy_healths.get_mut(b) {
+    difficulty: Res<DifficultyScaling>) {
+    for &ProjectileHitEvent{other, owner, damage, ..} in hit_events.iter() {
+        match enemy_healths.get_mut(other) {
             Ok(mut enemy_health) => {
-                match projectiles.get(a) {
-                    Ok(projectile) => {
-                        match players.get_mut(projectile.origin.unwrap()){
-                            Ok(mut player) => {
-                                player.score += 10;//todo calculate enemy destroy score from enemy stats
-                                enemy_health.health = enemy_health.health - projectile.damage;
-                            }
-                            Err(_) => {/*warn!("Enemy just shot itself.")*/}
-                        }
-                    }
-                    Err(_) => {}
+                enemy_health.health = enemy_health.health - damage;
+                // The player may have been destroyed while the projectile was in flight
+                if let Some(mut player) = owner.player.and_then(|index| players.iter_mut().find(|player| player.index == index)) {
+                    player.score += difficulty.scale_score(10);//todo calculate enemy destroy score from enemy stats
                 }
             }
             Err(_) => {}
@@ -127,43 +285,39 @@ fn enemy_hit_sys(
     }
 }
 
-pub fn enemy_remove_sys(mut cmd: Commands, enemies: Query<(Entity, &Health), With<Enemy>>) {
-    for (entity, health) in enemies.iter() {
-        if health.health <= 0 {
-            info!("Removing enemy Entity {}: Health is {}", entity.id(), health.health);
-            cmd.entity(entity).despawn();
-        }
-    }
+/// An enemy of `kind` from its prefab, with health scaled to the difficulty
+pub fn new_enemy(kind: EnemyKind, position: Vec2, fire_rate: f32, prefabs: &Prefabs, difficulty: &DifficultyScaling) -> EnemyBundle {
+    let mut bundle = EnemyBundle {
+        enemy: Enemy { kind, ..Default::default() },
+        shooter: Shooter { fire_rate, ..Default::default() },
+        ..Default::default()
+    }.with_prefab(&prefabs.get(kind.name()));
+    bundle.health = Health::new(difficulty.scale_health(bundle.health.max_health));
+    bundle.sprite.transform = Transform::from_xyz(position.x, position.y, 0.0);
+    bundle
 }
 
-
-/// Creates [`Enemy`] entities at random positions on top half of screen.
-pub fn enemy_startup_sys(mut cmd: Commands, state: Res<EnemyPlugin>, windows: Res<Windows>) {
+/// Creates the [`Enemy`] entities of `wave` at random positions on top half of screen.
+pub fn spawn_enemies(cmd: &mut Commands, wave: &EnemyWave, rng: &mut impl Rng, windows: &Windows, prefabs: &Prefabs, difficulty: &DifficultyScaling) {
     let width = windows.get_primary().unwrap().width() / 2.0;
     let height = windows.get_primary().unwrap().height() / 2.0;
     let mut vertical_pos = height - ENEMY_VERT_SPACING;
-    let mut rng = rand::thread_rng();
 
-    for _ in 0..state.enemy_count {
+    for _ in 0..wave.count {
         let horizontal_pos = rng.gen_range(-width..width );
+        let kind = match wave.kinds.choose(rng) {
+            Some(kind) => *kind,
+            None => return,
+        };
 
-        cmd.spawn_bundle(EnemyBundle {
-            sprite: SpriteBundle {
-                sprite: Sprite {
-                    color: Color::FUCHSIA,
-                    custom_size: Some(Vec2::new(25.0, 25.0)),
-                    ..Default::default()
-                },
-                transform: Transform::from_xyz(horizontal_pos, vertical_pos, 0.0),
-                ..Default::default()
-            },
-            shooter: Shooter { ammo_count: 10, fire_rate: state.fire_rate },
-            ..Default::default()
-        });
+        let mut bundle = new_enemy(kind, Vec2::new(horizontal_pos, vertical_pos), wave.fire_rate, prefabs, difficulty);
+        bundle.enemy.move_speed *= wave.speed;
+        cmd.spawn_bundle(bundle);
         vertical_pos -= ENEMY_VERT_SPACING;
     }
 }
 
+/// Enemies spawned by levels which don't list their own
 pub struct EnemyPlugin {
     pub enemy_count: i32,
     pub fire_rate: f32,
@@ -178,10 +332,53 @@ impl Plugin for EnemyPlugin {
         };
 
         app .insert_resource(x)
-            .add_startup_system(enemy_startup_sys)
-            .add_system(enemy_move_sys)
-            .add_system(enemy_shoot_sys)
-            .add_system(enemy_remove_sys)
-            .add_system(enemy_hit_sys);
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(enemy_move_sys.timed())
+                .with_system(enemy_shoot_sys.timed())
+                .with_system(enemy_hit_sys.timed()));
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use bevy::sprite::collide_aabb::Collision;
+    use super::*;
+
+    #[test]
+    fn hit_after_shooter_removed() {
+        let mut world = World::new();
+        world.insert_resource(DespawnQueue::default());
+        world.insert_resource(FriendlyFire::default());
+        world.insert_resource(DifficultyScaling::default());
+        world.insert_resource(Events::<CollisionEvent>::default());
+        world.insert_resource(Events::<ProjectileHitEvent>::default());
+
+        let shooter = world.spawn().insert_bundle(PlayerBundle { player: Player { index: 0, ..Default::default() }, ..Default::default() }).id();
+        let other_player = world.spawn().insert_bundle(PlayerBundle { player: Player { index: 1, ..Default::default() }, ..Default::default() }).id();
+        let score = world.get::<Player>(other_player).unwrap().score;
+        let enemy = world.spawn().insert_bundle(EnemyBundle { health: Health::new(100), ..Default::default() }).id();
+        let projectile = world.spawn().insert_bundle(ProjectileBundle {
+            projectile: Projectile { owner: Owner::player(0), origin: Some(shooter), damage: 30, ..Default::default() },
+            team: Team::Player,
+            ..Default::default()
+        }).id();
+
+        world.despawn(shooter);
+        world.get_resource_mut::<DespawnQueue>().unwrap().despawn(shooter);
+        world.get_resource_mut::<Events<CollisionEvent>>().unwrap().send(CollisionEvent { a: projectile, b: enemy, collision: Collision::Inside });
+
+        SystemStage::single_threaded().with_system(projectile_hit_sys).run(&mut world);
+        SystemStage::single_threaded().with_system(enemy_hit_sys).run(&mut world);
+        assert!(world.get_resource::<DespawnQueue>().unwrap().contains(projectile));
+        despawn_queue_sys(&mut world);
+
+        assert_eq!(world.get::<Health>(enemy).unwrap().health, 70);
+        assert_eq!(world.get::<Player>(other_player).unwrap().score, score);
+        assert!(world.get_entity(projectile).is_none());
+
+        // Queueing an entity which is already gone is harmless
+        world.get_resource_mut::<DespawnQueue>().unwrap().despawn(projectile);
+        world.get_resource_mut::<DespawnQueue>().unwrap().despawn(projectile);
+        despawn_queue_sys(&mut world);
+        assert!(world.get_entity(enemy).is_some());
+    }
+}
@@ -1,52 +1,395 @@
+use std::collections::VecDeque;
 use bevy::prelude::*;
-use crate::common::Health;
+use crate::achievements::AchievementUnlockedEvent;
+use crate::boss::Boss;
+use crate::common::{Health, Lives, Shooter};
+use crate::manager::{CurrentLevel, GameState, HighScore, RunMode};
 use crate::player::Player;
+use crate::rewind::{Rewind, MAX_ENERGY, MIN_START_ENERGY};
+
+const MAX_LIFE_ICONS: u32 = 5;
+/// Players with their own health and ammo display
+const HUD_PLAYERS: usize = 2;
+/// Height each further player's health and ammo display is raised by
+const HUD_PLAYER_SPACING: f32 = 80.0;
+/// Fraction of the remaining difference the displayed score closes each second
+const SCORE_COUNT_UP_RATE: f32 = 8.0;
+/// Seconds each achievement toast stays on screen
+const TOAST_DURATION: f32 = 3.0;
+
+/// Displayed score, counting up towards the player's actual score
+#[derive(Component, Default)]
+struct ScoreText {
+    displayed: f32,
+}
 
 #[derive(Component)]
-struct  ScoreText;
+struct HighScoreText;
 
-fn interface_setup_sys(mut cmd: Commands, asset_server: Res<AssetServer>) {
+#[derive(Component)]
+struct WaveText;
 
-    cmd.spawn_bundle(UiCameraBundle::default());
+/// Credits to spend in the shop. Only shown in endless runs, which have a shop.
+#[derive(Component)]
+struct CreditsText;
 
-    cmd.spawn_bundle(TextBundle {
+/// Ammo of the player with the held [`Player::index`]
+#[derive(Component)]
+struct AmmoText(usize);
+
+/// Filled part of the health bar of the player with the held [`Player::index`]
+#[derive(Component)]
+struct HealthBar(usize);
+
+/// Filled part of the reload indicator of the player with the held [`Player::index`]
+#[derive(Component)]
+struct ReloadBar(usize);
+
+/// Part of the display of the player with the held [`Player::index`]. Only shown for the first
+/// player and any other player in the game.
+#[derive(Component)]
+struct PlayerHud(usize);
+
+/// One icon per remaining life. Holds the index of the life it represents.
+#[derive(Component)]
+struct LifeIcon(u32);
+
+/// Filled part of the rewind energy bar
+#[derive(Component)]
+struct RewindBar;
+
+/// Shown while rewinding
+#[derive(Component)]
+struct RewindText;
+
+/// Announces unlocked achievements one at a time, holding the names still to be shown
+#[derive(Component, Default)]
+struct AchievementToast {
+    queue: VecDeque<String>,
+    remaining: f32,
+}
+
+/// Background of the boss health bar
+#[derive(Component)]
+struct BossHealthBarFrame;
+
+/// Filled part of the boss health bar, sized to the boss' remaining health
+#[derive(Component)]
+struct BossHealthBar;
+
+/// The ChargeVector font variants, shared by the HUD and menus
+pub struct HudFonts {
+    pub black: Handle<Font>,
+    pub bold: Handle<Font>,
+    pub regular: Handle<Font>,
+    pub thin: Handle<Font>,
+}
+
+fn text_bundle(value: &str, font: Handle<Font>, font_size: f32, position: Rect<Val>) -> TextBundle {
+    TextBundle {
         style: Style {
-            align_self: AlignSelf::FlexEnd,
             position_type: PositionType::Absolute,
-            position: Rect {
-                bottom: Val::Px(5.0),
-                right: Val::Px(15.0),
-                ..Default::default()
-            },
+            position,
             ..Default::default()
         },
         text: Text::with_section(
-            // Accepts a `String` or any type that converts into a `String`, such as `&str`
-            "Merry\nChristmas!",
+            value,
             TextStyle {
-                font: asset_server.load("fonts/ChargeVector.otf"),
-                font_size: 40.0,
+                font,
+                font_size,
                 color: Color::WHITE,
             },
-            // Note: You can use `Default::default()` in place of the `TextAlignment`
-            TextAlignment {
-                horizontal: HorizontalAlign::Center,
-                ..Default::default()
-            },
+            Default::default(),
         ),
         ..Default::default()
-    }).insert(ScoreText);
+    }
+}
+
+/// An absolutely positioned bar background. The filled part is added as a child.
+fn bar_bundle(position: Rect<Val>, size: Size<Val>) -> NodeBundle {
+    NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position,
+            size,
+            ..Default::default()
+        },
+        color: UiColor(Color::rgba(1.0, 1.0, 1.0, 0.2)),
+        ..Default::default()
+    }
+}
+
+fn bar_fill_bundle(color: Color) -> NodeBundle {
+    NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            ..Default::default()
+        },
+        color: UiColor(color),
+        ..Default::default()
+    }
 }
 
-fn score_update_sys(mut score_text: Query<&mut Text, With<ScoreText>>, player: Query<(&Player, &Health), Or<(Changed<Health>, Changed<Player>)>>) {
-    for mut text in score_text.iter_mut() {
-        //let mut new_text = String::from("");
-        for (player, health) in player.iter() {
-            //new_text.push_str(&format!("Health: {}\n Score: {}", health.health, player.score));
-            text.sections[0].value = format!("Health: {}\n Score: {}", health.health, player.score);
+fn interface_setup_sys(mut cmd: Commands, asset_server: Res<AssetServer>) {
+
+    cmd.spawn_bundle(UiCameraBundle::default());
+
+    let fonts = HudFonts {
+        black: asset_server.load("fonts/ChargeVectorBlack.otf"),
+        bold: asset_server.load("fonts/ChargeVectorBold.otf"),
+        regular: asset_server.load("fonts/ChargeVector.otf"),
+        thin: asset_server.load("fonts/ChargeVectorThin.otf"),
+    };
+
+    // Top left: score and high score
+    cmd.spawn_bundle(text_bundle("0", fonts.black.clone(), 40.0, Rect { top: Val::Px(5.0), left: Val::Px(15.0), ..Default::default() }))
+        .insert(ScoreText::default());
+    cmd.spawn_bundle(text_bundle("HI 0", fonts.thin.clone(), 20.0, Rect { top: Val::Px(50.0), left: Val::Px(15.0), ..Default::default() }))
+        .insert(HighScoreText);
+    cmd.spawn_bundle(TextBundle {
+        visibility: Visibility { is_visible: false },
+        ..text_bundle("", fonts.regular.clone(), 20.0, Rect { top: Val::Px(75.0), left: Val::Px(15.0), ..Default::default() })
+    }).insert(CreditsText);
+
+    // Top right: current wave
+    cmd.spawn_bundle(text_bundle("WAVE 1", fonts.bold.clone(), 30.0, Rect { top: Val::Px(5.0), right: Val::Px(15.0), ..Default::default() }))
+        .insert(WaveText);
+
+    // Bottom left: lives and health
+    for i in 0..MAX_LIFE_ICONS {
+        cmd.spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect { bottom: Val::Px(40.0), left: Val::Px(15.0 + i as f32 * 25.0), ..Default::default() },
+                size: Size::new(Val::Px(18.0), Val::Px(18.0)),
+                ..Default::default()
+            },
+            color: UiColor(Color::BLUE),
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        }).insert(LifeIcon(i));
+    }
+    cmd.spawn_bundle(bar_bundle(Rect { bottom: Val::Px(64.0), left: Val::Px(15.0), ..Default::default() }, Size::new(Val::Px(120.0), Val::Px(6.0))))
+        .with_children(|parent| { parent.spawn_bundle(bar_fill_bundle(Color::CYAN)).insert(RewindBar); });
+
+    // Centre: rewind indicator
+    cmd.spawn_bundle(TextBundle {
+        visibility: Visibility { is_visible: false },
+        ..text_bundle("<< REWIND", fonts.black.clone(), 40.0, Rect { top: Val::Percent(40.0), left: Val::Percent(40.0), ..Default::default() })
+    }).insert(RewindText);
+
+    // Bottom left: health, bottom right: ammo and reload. Further players' are stacked above.
+    for index in 0..HUD_PLAYERS {
+        let offset = index as f32 * HUD_PLAYER_SPACING;
+        let visibility = Visibility { is_visible: index == 0 };
+        cmd.spawn_bundle(NodeBundle {
+            visibility: visibility.clone(),
+            ..bar_bundle(Rect { bottom: Val::Px(15.0 + offset), left: Val::Px(15.0), ..Default::default() }, Size::new(Val::Px(200.0), Val::Px(14.0)))
+        }).insert(PlayerHud(index)).with_children(|parent| {
+            parent.spawn_bundle(NodeBundle { visibility: visibility.clone(), ..bar_fill_bundle(Color::LIME_GREEN) })
+                .insert_bundle((HealthBar(index), PlayerHud(index)));
+        });
+        cmd.spawn_bundle(TextBundle {
+            visibility: visibility.clone(),
+            ..text_bundle("", fonts.regular.clone(), 24.0, Rect { bottom: Val::Px(25.0 + offset), right: Val::Px(15.0), ..Default::default() })
+        }).insert_bundle((AmmoText(index), PlayerHud(index)));
+        cmd.spawn_bundle(NodeBundle {
+            visibility: visibility.clone(),
+            ..bar_bundle(Rect { bottom: Val::Px(15.0 + offset), right: Val::Px(15.0), ..Default::default() }, Size::new(Val::Px(120.0), Val::Px(6.0)))
+        }).insert(PlayerHud(index)).with_children(|parent| {
+            parent.spawn_bundle(NodeBundle { visibility, ..bar_fill_bundle(Color::GOLD) })
+                .insert_bundle((ReloadBar(index), PlayerHud(index)));
+        });
+    }
+
+    // Top centre: boss health, hidden until a boss spawns
+    cmd.spawn_bundle(NodeBundle {
+        visibility: Visibility { is_visible: false },
+        ..bar_bundle(Rect { top: Val::Px(10.0), left: Val::Percent(25.0), ..Default::default() }, Size::new(Val::Percent(50.0), Val::Px(12.0)))
+    }).insert(BossHealthBarFrame).with_children(|parent| {
+        parent.spawn_bundle(NodeBundle {
+            visibility: Visibility { is_visible: false },
+            ..bar_fill_bundle(Color::CRIMSON)
+        }).insert(BossHealthBar);
+    });
+
+    // Top centre, below the boss health bar: achievement toasts
+    cmd.spawn_bundle(TextBundle {
+        visibility: Visibility { is_visible: false },
+        ..text_bundle("", fonts.bold.clone(), 26.0, Rect { top: Val::Px(30.0), left: Val::Percent(30.0), ..Default::default() })
+    }).insert(AchievementToast::default());
+
+    cmd.insert_resource(fonts);
+}
+
+/// Counts the displayed score up towards the player's score, only touching the text when the shown value changes
+fn score_update_sys(mut score_text: Query<(&mut Text, &mut ScoreText)>, players: Query<&Player>, time: Res<Time>) {
+    let score = match players.iter().map(|player| player.score).max() {
+        Some(score) => score as f32,
+        None => return,
+    };
+
+    for (mut text, mut score_text) in score_text.iter_mut() {
+        if score_text.displayed == score { continue }
+
+        let previous = score_text.displayed as i32;
+        let step = (score - score_text.displayed) * (SCORE_COUNT_UP_RATE * time.delta_seconds()).min(1.0);
+        score_text.displayed = if step.abs() < 1.0 { score } else { score_text.displayed + step };
+
+        if score_text.displayed as i32 != previous {
+            text.sections[0].value = format!("{}", score_text.displayed as i32);
+        }
+    }
+}
+
+fn high_score_update_sys(mut high_score_text: Query<&mut Text, With<HighScoreText>>, high_score: Res<HighScore>) {
+    if !high_score.is_changed() { return }
+    for mut text in high_score_text.iter_mut() {
+        text.sections[0].value = format!("HI {}", high_score.0);
+    }
+}
+
+fn credits_update_sys(
+    mut credits_text: Query<(&mut Text, &mut Visibility), With<CreditsText>>,
+    players: Query<&Player, Changed<Player>>,
+    mode: Res<RunMode>) {
+    let credits = players.iter().map(|player| player.credits).max();
+    if credits.is_none() && !mode.is_changed() { return }
+    for (mut text, mut visibility) in credits_text.iter_mut() {
+        visibility.is_visible = *mode != RunMode::Campaign;
+        if let Some(credits) = credits {
+            text.sections[0].value = format!("CR {}", credits);
+        }
+    }
+}
+
+fn wave_update_sys(mut wave_text: Query<&mut Text, With<WaveText>>, level: Option<Res<CurrentLevel>>) {
+    let level = match level {
+        Some(level) if level.is_changed() => level,
+        _ => return,
+    };
+    for mut text in wave_text.iter_mut() {
+        text.sections[0].value = if level.boss_spawned { String::from("BOSS") } else { format!("WAVE {}", level.wave) };
+    }
+}
+
+/// Shows the health and ammo display of every player in the game, and always the first's
+fn player_hud_sys(
+    mut hud: Query<(&mut Visibility, &PlayerHud)>,
+    added: Query<(), Added<Player>>,
+    removed: RemovedComponents<Player>,
+    players: Query<&Player>) {
+    if added.is_empty() && removed.iter().next().is_none() { return }
+    for (mut visibility, hud) in hud.iter_mut() {
+        let shown = hud.0 == 0 || players.iter().any(|player| player.index == hud.0);
+        if visibility.is_visible != shown {
+            visibility.is_visible = shown;
+        }
+    }
+}
+
+fn health_bar_update_sys(mut health_bar: Query<(&mut Style, &HealthBar)>, players: Query<(&Health, &Player), Changed<Health>>) {
+    for (health, player) in players.iter() {
+        for (mut style, _) in health_bar.iter_mut().filter(|(_, bar)| bar.0 == player.index) {
+            style.size.width = Val::Percent(health.fraction() * 100.0);
+        }
+    }
+}
+
+/// Shows the first player's remaining lives
+fn lives_update_sys(mut life_icons: Query<(&mut Visibility, &LifeIcon)>, players: Query<(&Lives, &Player), Changed<Lives>>) {
+    for (lives, _) in players.iter().filter(|(_, player)| player.index == 0) {
+        for (mut visibility, icon) in life_icons.iter_mut() {
+            visibility.is_visible = icon.0 < lives.remaining;
+        }
+    }
+}
+
+fn ammo_update_sys(
+    mut ammo_text: Query<(&mut Text, &AmmoText)>,
+    mut reload_bar: Query<(&mut Style, &ReloadBar)>,
+    players: Query<(&Shooter, &Player), Changed<Shooter>>) {
+    for (shooter, player) in players.iter() {
+        for (mut text, _) in ammo_text.iter_mut().filter(|(_, ammo)| ammo.0 == player.index) {
+            text.sections[0].value = if shooter.reloading {
+                String::from("RELOADING")
+            } else {
+                format!("AMMO {}/{}", shooter.ammo_count, shooter.magazine_size)
+            };
+        }
+        for (mut style, _) in reload_bar.iter_mut().filter(|(_, bar)| bar.0 == player.index) {
+            style.size.width = Val::Percent(shooter.reload_progress() * 100.0);
+        }
+    }
+}
+
+/// Sizes the rewind energy bar, dimmed while there isn't enough to start rewinding, and shows
+/// the rewind indicator while rewinding
+fn rewind_update_sys(
+    rewind: Res<Rewind>,
+    state: Res<State<GameState>>,
+    mut rewind_bar: Query<(&mut Style, &mut UiColor), With<RewindBar>>,
+    mut rewind_text: Query<&mut Visibility, With<RewindText>>) {
+    if rewind.is_changed() {
+        for (mut style, mut color) in rewind_bar.iter_mut() {
+            style.size.width = Val::Percent(rewind.energy / MAX_ENERGY * 100.0);
+            color.0 = if rewind.energy < MIN_START_ENERGY { Color::rgba(0.0, 1.0, 1.0, 0.3) } else { Color::CYAN };
         }
+    }
+    if state.is_changed() {
+        for mut visibility in rewind_text.iter_mut() {
+            visibility.is_visible = *state.current() == GameState::Rewinding;
+        }
+    }
+}
 
-        //text.sections[0].value = new_text;
+/// Shows the boss health bar while a boss is alive, updating it when the boss changes or is removed
+fn boss_health_bar_sys(
+    bosses: Query<&Boss>,
+    changed: Query<(), Changed<Boss>>,
+    removed: RemovedComponents<Boss>,
+    mut bar_visibility: Query<&mut Visibility, Or<(With<BossHealthBar>, With<BossHealthBarFrame>)>>,
+    mut bar_style: Query<&mut Style, With<BossHealthBar>>) {
+    if changed.is_empty() && removed.iter().next().is_none() { return }
+    let boss = bosses.iter().next();
+
+    for mut visibility in bar_visibility.iter_mut() {
+        if visibility.is_visible != boss.is_some() {
+            visibility.is_visible = boss.is_some();
+        }
+    }
+
+    if let Some(boss) = boss {
+        // The boss changes every frame it moves, so only touch the bar when its health does
+        let width = Val::Percent(boss.health_fraction() * 100.0);
+        for mut style in bar_style.iter_mut() {
+            if style.size.width != width {
+                style.size.width = width;
+            }
+        }
+    }
+}
+
+/// Queues a toast for each unlocked achievement and shows them in turn
+fn achievement_toast_sys(
+    mut unlocked_events: EventReader<AchievementUnlockedEvent>,
+    mut toasts: Query<(&mut AchievementToast, &mut Text, &mut Visibility)>,
+    time: Res<Time>) {
+    for (mut toast, mut text, mut visibility) in toasts.iter_mut() {
+        toast.queue.extend(unlocked_events.iter().map(|event| event.name.clone()));
+        if toast.remaining > 0. {
+            toast.remaining -= time.delta_seconds();
+            if toast.remaining > 0. { continue }
+        }
+        match toast.queue.pop_front() {
+            Some(name) => {
+                text.sections[0].value = format!("ACHIEVEMENT UNLOCKED: {}", name.to_uppercase());
+                toast.remaining = TOAST_DURATION;
+                visibility.is_visible = true;
+            }
+            None if visibility.is_visible => visibility.is_visible = false,
+            None => {}
+        }
     }
 }
 
@@ -55,7 +398,17 @@ pub struct InterfacePlugin;
 impl Plugin for InterfacePlugin {
     fn build(&self, app: &mut App) {
         app.add_startup_system(interface_setup_sys)
-            .add_system(score_update_sys);
+            .add_system(score_update_sys)
+            .add_system(high_score_update_sys)
+            .add_system(credits_update_sys)
+            .add_system(wave_update_sys)
+            .add_system(player_hud_sys)
+            .add_system(health_bar_update_sys)
+            .add_system(lives_update_sys)
+            .add_system(ammo_update_sys)
+            .add_system(rewind_update_sys)
+            .add_system(boss_health_bar_sys)
+            .add_system(achievement_toast_sys);
 
     }
-}
\ No newline at end of file
+}
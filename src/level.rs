@@ -0,0 +1,190 @@
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::BoxedFuture;
+use rand::prelude::*;
+use serde::Deserialize;
+use crate::boss::BossKind;
+use crate::enemy::EnemyKind;
+
+const ENDLESS_BASE_COUNT: u32        = 6;
+const ENDLESS_COUNT_STEP: u32        = 2;
+const ENDLESS_MAX_COUNT: u32         = 30;
+const ENDLESS_BASE_FIRE_RATE: f32    = 0.008;
+/// Fire rate and speed grow by these fractions of their base every wave, up to the maximums
+const ENDLESS_FIRE_RATE_STEP: f32    = 0.1;
+const ENDLESS_MAX_FIRE_RATE: f32     = 3.0;
+const ENDLESS_SPEED_STEP: f32        = 0.05;
+const ENDLESS_MAX_SPEED: f32         = 2.0;
+/// Every this many waves ends with a boss
+const ENDLESS_BOSS_INTERVAL: u32     = 5;
+/// The shop opens after every this many waves
+const ENDLESS_SHOP_INTERVAL: u32     = 3;
+
+/// A level, loaded from a `.level.ron` file in `assets/levels`
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "6f6a3a55-3c3f-4b0e-9a64-5a0f3cb2d1e7"]
+pub struct Level {
+    pub name: String,
+    /// Boss fought once every other enemy in the level has been destroyed
+    #[serde(default)]
+    pub boss: Option<BossKind>,
+    /// Background layers, from furthest to nearest. Defaults to a plain starfield.
+    #[serde(default = "default_background")]
+    pub background: Vec<BackgroundLayer>,
+    /// Enemies spawned when the level starts. Defaults to the count and fire rate given to
+    /// [`EnemyPlugin`](crate::enemy::EnemyPlugin).
+    #[serde(default)]
+    pub enemies: Option<EnemyWave>,
+    /// Score needed for each star when the level is completed, lowest first
+    #[serde(default = "default_star_scores")]
+    pub star_scores: [i32; 3],
+}
+
+impl Level {
+    /// Stars earned by a run ending with `score`. Only completed runs earn stars.
+    pub fn stars(&self, score: i32, completed: bool) -> u32 {
+        if !completed { return 0 }
+        self.star_scores.iter().filter(|needed| score >= **needed).count() as u32
+    }
+}
+
+/// Enemies placed at random along the top of the arena
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnemyWave {
+    pub count: u32,
+    /// Kinds picked from at random. Defaults to every kind.
+    #[serde(default = "all_enemy_kinds")]
+    pub kinds: Vec<EnemyKind>,
+    pub fire_rate: f32,
+    /// Multiplier applied to the enemies' move speed
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+}
+
+fn default_speed() -> f32 {
+    1.0
+}
+
+fn all_enemy_kinds() -> Vec<EnemyKind> {
+    EnemyKind::ALL.to_vec()
+}
+
+fn default_star_scores() -> [i32; 3] {
+    [0, 1000, 2500]
+}
+
+/// One layer of the scrolling background
+#[derive(Debug, Clone, Deserialize)]
+pub enum BackgroundLayer {
+    /// Randomly placed stars
+    Stars { count: u32, size: f32, brightness: f32, speed: f32, parallax: f32 },
+    /// A texture tiled vertically, such as a nebula
+    Texture { path: String, opacity: f32, speed: f32, parallax: f32 },
+}
+
+fn default_background() -> Vec<BackgroundLayer> {
+    vec![
+        BackgroundLayer::Stars { count: 80, size: 1.5, brightness: 0.4, speed: 20., parallax: 0.02 },
+        BackgroundLayer::Stars { count: 50, size: 2.0, brightness: 0.7, speed: 45., parallax: 0.05 },
+        BackgroundLayer::Stars { count: 20, size: 3.0, brightness: 1.0, speed: 90., parallax: 0.1 },
+    ]
+}
+
+#[derive(Default)]
+pub struct LevelLoader;
+
+impl AssetLoader for LevelLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let level: Level = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["level.ron"]
+    }
+}
+
+/// One wave of an endless run
+#[derive(Debug, Clone)]
+pub struct EndlessWave {
+    pub enemies: EnemyWave,
+    /// Boss fought once the wave's enemies are destroyed
+    pub boss: Option<BossKind>,
+    /// True if the shop opens once the wave is cleared
+    pub shop: bool,
+}
+
+/// Generates wave `wave` of an endless run, starting at 1. Later waves have more enemies, of
+/// more kinds, which move and fire faster. Uses `rng` alone, so the same seed gives the same waves.
+pub fn endless_wave(wave: u32, rng: &mut impl Rng) -> EndlessWave {
+    let step = wave.saturating_sub(1) as f32;
+    let available = (2 + wave as usize / 2).min(EnemyKind::ALL.len());
+    let kind_count = rng.gen_range(1..=available);
+    let kinds = EnemyKind::ALL[..available].choose_multiple(rng, kind_count).copied().collect();
+
+    EndlessWave {
+        enemies: EnemyWave {
+            count: (ENDLESS_BASE_COUNT + ENDLESS_COUNT_STEP * wave.saturating_sub(1)).min(ENDLESS_MAX_COUNT),
+            kinds,
+            fire_rate: ENDLESS_BASE_FIRE_RATE * (1.0 + ENDLESS_FIRE_RATE_STEP * step).min(ENDLESS_MAX_FIRE_RATE),
+            speed: (1.0 + ENDLESS_SPEED_STEP * step).min(ENDLESS_MAX_SPEED),
+        },
+        boss: if wave % ENDLESS_BOSS_INTERVAL == 0 { BossKind::ALL.choose(rng).copied() } else { None },
+        shop: wave % ENDLESS_SHOP_INTERVAL == 0,
+    }
+}
+
+/// When a campaign level can be played
+#[derive(Debug, Clone, Deserialize)]
+pub enum Unlock {
+    Always,
+    /// Once the level before it is complete. The first level is always unlocked.
+    Previous,
+    /// Once this many stars have been earned over the whole campaign
+    Stars(u32),
+    /// Once the level at this path is complete
+    Level(String),
+}
+
+impl Default for Unlock {
+    fn default() -> Self { Unlock::Previous }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CampaignLevel {
+    /// Asset path of the `.level.ron` file
+    pub path: String,
+    #[serde(default)]
+    pub unlock: Unlock,
+}
+
+/// The campaign's levels in the order they are played, loaded from a `.campaign.ron` file
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "0c7e4d2b-95a1-4f3e-8b6d-71e2a9c5f804"]
+pub struct CampaignDefinition {
+    pub levels: Vec<CampaignLevel>,
+}
+
+#[derive(Default)]
+pub struct CampaignLoader;
+
+impl AssetLoader for CampaignLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let campaign: CampaignDefinition = ron::de::from_bytes(bytes)?;
+            if campaign.levels.is_empty() {
+                return Err(anyhow::anyhow!("Campaign {} has no levels", load_context.path().display()));
+            }
+            load_context.set_default_asset(LoadedAsset::new(campaign));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["campaign.ron"]
+    }
+}
@@ -1,5 +1,34 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
 use bevy::prelude::*;
-use crate::{Enemy, new_player, Player};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{Enemy, Player};
+use crate::boss::{Boss, spawn_boss};
+use crate::enemy::{spawn_enemies, EnemyKind, EnemyPlugin};
+use crate::level::{endless_wave, CampaignDefinition, CampaignLevel, CampaignLoader, EndlessWave, EnemyWave, Level, LevelLoader, Unlock};
+use crate::prefab::Prefabs;
+use crate::projectile::Projectile;
+use crate::common::{DespawnQueue, GameRng};
+use crate::difficulty::DifficultyScaling;
+use crate::settings::config_dir;
+
+const BOSS_VERT_OFFSET: f32 = 150.;
+const CAMPAIGN_PATH: &str   = "levels/campaign.campaign.ron";
+/// Played until the campaign has loaded
+const FIRST_LEVEL_PATH: &str = "levels/01.level.ron";
+/// Name and background of endless runs. Its waves are generated.
+const ENDLESS_LEVEL_PATH: &str = "levels/endless.level.ron";
+const PROGRESS_FILE: &str    = "campaign.toml";
+const BEST_SCORES_FILE: &str = "best_scores.toml";
+
+/// Orders spawning before the checks which depend on what has been spawned
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+enum LevelLabel {
+    Spawn,
+    Boss,
+}
 
 /// Keeps track of game state and loads levels
 
@@ -7,15 +36,482 @@ pub struct ManagerPlugin;
 
 impl Plugin for ManagerPlugin {
     fn build(&self, app: &mut App) {
+        app .add_asset::<Level>()
+            .init_asset_loader::<LevelLoader>()
+            .add_asset::<CampaignDefinition>()
+            .init_asset_loader::<CampaignLoader>()
+            .init_resource::<HighScore>()
+            .init_resource::<BestScores>()
+            .init_resource::<RunResult>()
+            .init_resource::<Campaign>()
+            .init_resource::<RunMode>()
+            .add_state(GameState::MainMenu)
+            .add_startup_system(campaign_load_sys)
+            .add_system(campaign_reload_sys)
+            .add_system_set(SystemSet::on_enter(GameState::Playing)
+                .with_system(level_load_sys))
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(level_spawn_sys.label(LevelLabel::Spawn))
+                .with_system(endless_wave_sys.after(LevelLabel::Spawn))
+                .with_system(boss_spawn_sys.label(LevelLabel::Boss).after(LevelLabel::Spawn))
+                .with_system(high_score_sys)
+                .with_system(watch_state_sys.after(LevelLabel::Boss)))
+            .add_system_set(SystemSet::on_exit(GameState::Shop)
+                .with_system(endless_shop_closed_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(gameplay_cleanup_sys));
+    }
+}
+
+/// Top level state of the game. Gameplay systems only run while [`GameState::Playing`].
+/// [`GameState::Paused`] is pushed on top of `Playing` so the run can be resumed.
+/// [`GameState::Rewinding`] is pushed on top of `Playing` while the player rewinds time.
+/// [`GameState::Shop`] is pushed on top of `Playing` between endless waves.
+/// [`GameState::Online`] runs the two player online simulation instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GameState {
+    MainMenu,
+    LevelSelect,
+    HighScores,
+    Settings,
+    Playing,
+    Paused,
+    Rewinding,
+    Shop,
+    GameOver,
+    Online,
+}
+
+/// Outcome of the current or most recent run, shown on the game over screen
+#[derive(Default)]
+pub struct RunResult {
+    pub score: i32,
+    pub wave: u32,
+    /// True if the level was completed rather than all players being destroyed
+    pub completed: bool,
+    /// Stars earned, out of three. Only completed levels earn stars.
+    pub stars: u32,
+}
+
+/// The level currently being played
+#[derive(Clone)]
+pub struct CurrentLevel {
+    pub handle: Handle<Level>,
+    /// Wave currently being fought, starting at 1. In the campaign the level's enemies are wave 1
+    /// and its boss wave 2. In endless each generated wave, including its boss, is one wave.
+    pub wave: u32,
+    /// True once the level has loaded and its enemies exist
+    pub spawned: bool,
+    /// True once the boss exists, or straight after the enemies for levels without one
+    pub boss_spawned: bool,
+    /// True while the enemies or boss have been queued for spawning but don't exist yet. They
+    /// are only counted as spawned the frame after, so nothing sees the level empty meanwhile.
+    pub pending: bool,
+}
+
+/// Best result on one campaign level
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LevelRecord {
+    pub completed: bool,
+    pub stars: u32,
+    pub best_score: i32,
+}
+
+/// Results on each campaign level, by level path. Persisted to `campaign.toml` in [`config_dir`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CampaignProgress {
+    #[serde(default)]
+    pub levels: BTreeMap<String, LevelRecord>,
+}
+
+impl CampaignProgress {
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(PROGRESS_FILE))
+    }
+
+    /// Loads progress from the config directory. Missing or unreadable progress starts afresh.
+    pub fn load() -> CampaignProgress {
+        let source = match CampaignProgress::path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(source) => source,
+            None => return CampaignProgress::default(),
+        };
+        toml::from_str(&source).unwrap_or_else(|err| {
+            warn!("Campaign progress is invalid, starting afresh: {}", err);
+            CampaignProgress::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = CampaignProgress::path().ok_or_else(|| String::from("no config directory"))?;
+        let source = toml::to_string_pretty(self).map_err(|err| err.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        fs::write(&path, source).map_err(|err| err.to_string())
+    }
+}
+
+/// The campaign's levels, the one selected to play and the player's progress through them
+#[derive(Default)]
+pub struct Campaign {
+    handle: Handle<CampaignDefinition>,
+    pub levels: Vec<CampaignLevel>,
+    /// Kept so every level's name is available to the level select screen
+    pub level_handles: Vec<Handle<Level>>,
+    pub selected: usize,
+    pub progress: CampaignProgress,
+}
+
+impl Campaign {
+    pub fn record(&self, index: usize) -> LevelRecord {
+        self.levels.get(index)
+            .and_then(|level| self.progress.levels.get(&level.path))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn total_stars(&self) -> u32 {
+        self.progress.levels.values().map(|record| record.stars).sum()
+    }
+
+    pub fn is_unlocked(&self, index: usize) -> bool {
+        match self.levels.get(index).map(|level| &level.unlock) {
+            None => false,
+            Some(Unlock::Always) => true,
+            Some(Unlock::Previous) => index == 0 || self.record(index - 1).completed,
+            Some(Unlock::Stars(stars)) => self.total_stars() >= *stars,
+            Some(Unlock::Level(path)) => self.progress.levels.get(path).map_or(false, |record| record.completed),
+        }
+    }
+
+    /// Selects the level to play next, if it is unlocked
+    pub fn select(&mut self, index: usize) -> Result<(), String> {
+        if index >= self.levels.len() { return Err(format!("No level {}", index + 1)) }
+        if !self.is_unlocked(index) { return Err(format!("Level {} is locked", index + 1)) }
+        self.selected = index;
+        Ok(())
+    }
+
+    /// Asset path of the selected level
+    pub fn level_path(&self) -> &str {
+        self.levels.get(self.selected).map_or(FIRST_LEVEL_PATH, |level| level.path.as_str())
+    }
+
+    /// Keeps the best result for the selected level and saves progress
+    pub fn record_run(&mut self, score: i32, completed: bool, stars: u32) {
+        let path = String::from(self.level_path());
+        let record = self.progress.levels.entry(path).or_default();
+        record.completed |= completed;
+        record.stars = record.stars.max(stars);
+        record.best_score = record.best_score.max(score);
+        if let Err(err) = self.progress.save() {
+            warn!("Could not save campaign progress: {}", err);
+        }
+    }
+}
+
+/// Best score reached by any player in the mode being played
+#[derive(Default)]
+pub struct HighScore(pub i32);
+
+/// Best results of each mode, kept apart so endless runs don't compete with the campaign.
+/// Persisted to `best_scores.toml` in [`config_dir`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BestScores {
+    #[serde(default)]
+    pub campaign: i32,
+    #[serde(default)]
+    pub endless: i32,
+    /// Furthest wave reached in endless mode
+    #[serde(default)]
+    pub endless_wave: u32,
+}
+
+impl BestScores {
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(BEST_SCORES_FILE))
+    }
+
+    /// Loads the best scores from the config directory. Missing or unreadable scores start afresh.
+    pub fn load() -> BestScores {
+        let source = match BestScores::path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(source) => source,
+            None => return BestScores::default(),
+        };
+        toml::from_str(&source).unwrap_or_else(|err| {
+            warn!("Best scores are invalid, starting afresh: {}", err);
+            BestScores::default()
+        })
+    }
 
+    pub fn save(&self) -> Result<(), String> {
+        let path = BestScores::path().ok_or_else(|| String::from("no config directory"))?;
+        let source = toml::to_string_pretty(self).map_err(|err| err.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        fs::write(&path, source).map_err(|err| err.to_string())
     }
 }
 
-/// End game and store state when enemies = 0 or players healths = 0
-fn watch_state_sys(mut cmd: Commands, enemies: Query<&Enemy>, players: Query<&Player>){
-    if enemies.is_empty() || players.is_empty() {
-        new_player(&mut cmd);
-        //todo create enemies
+/// Which kind of run is played on entering [`GameState::Playing`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    /// The selected [`Campaign`] level
+    Campaign,
+    /// Generated waves which get harder until every player is destroyed. The same seed always
+    /// generates the same waves.
+    Endless { seed: u64 },
+}
+
+impl Default for RunMode {
+    fn default() -> Self { RunMode::Campaign }
+}
+
+impl RunMode {
+    /// An endless run with a random seed
+    pub fn endless() -> Self {
+        RunMode::Endless { seed: thread_rng().gen() }
+    }
+}
+
+/// The wave being fought in an endless run
+#[derive(Clone)]
+pub struct EndlessRun {
+    pub wave: EndlessWave,
+    /// Set once a wave with a shop is cleared. The next wave is generated but held back until
+    /// the shop closes.
+    pub pending_shop: bool,
+}
+
+fn campaign_load_sys(mut campaign: ResMut<Campaign>, mut best: ResMut<BestScores>, asset_server: Res<AssetServer>) {
+    campaign.handle = asset_server.load(CAMPAIGN_PATH);
+    campaign.progress = CampaignProgress::load();
+    *best = BestScores::load();
+}
+
+fn campaign_reload_sys(
+    mut campaign: ResMut<Campaign>,
+    mut campaign_events: EventReader<AssetEvent<CampaignDefinition>>,
+    definitions: Res<Assets<CampaignDefinition>>,
+    asset_server: Res<AssetServer>) {
+    for event in campaign_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } if *handle == campaign.handle => {}
+            _ => continue,
+        }
+        if let Some(definition) = definitions.get(&campaign.handle) {
+            if definition.levels.is_empty() {
+                warn!("Campaign has no levels, keeping the previous {}", campaign.levels.len());
+                continue
+            }
+            campaign.levels = definition.levels.clone();
+            campaign.level_handles = campaign.levels.iter().map(|level| asset_server.load(level.path.as_str())).collect();
+            campaign.selected = campaign.selected.min(campaign.levels.len() - 1);
+            info!("Loaded campaign of {} levels", campaign.levels.len());
+        }
+    }
+}
+
+/// Loads the level for the run mode, reseeds [`GameRng`] and shows the mode's high score
+fn level_load_sys(
+    mut cmd: Commands,
+    asset_server: Res<AssetServer>,
+    campaign: Res<Campaign>,
+    mode: Res<RunMode>,
+    best: Res<BestScores>,
+    mut high_score: ResMut<HighScore>) {
+    let (path, rng, best_score) = match *mode {
+        RunMode::Campaign => (campaign.level_path(), GameRng::default(), best.campaign),
+        RunMode::Endless { seed } => (ENDLESS_LEVEL_PATH, GameRng::seeded(seed), best.endless),
+    };
+    info!("Starting {:?} with seed {}", *mode, rng.seed);
+    high_score.0 = best_score;
+    cmd.remove_resource::<EndlessRun>();
+    cmd.insert_resource(rng);
+    cmd.insert_resource(RunResult::default());
+    // Replaced rather than overwritten, so the new level counts as added
+    cmd.remove_resource::<CurrentLevel>();
+    cmd.insert_resource(CurrentLevel {
+        handle: asset_server.load(path),
+        wave: 1,
+        spawned: false,
+        boss_spawned: false,
+        pending: false,
+    });
+}
+
+/// Spawns the level's enemies, or the first endless wave, once the level has loaded
+fn level_spawn_sys(
+    mut cmd: Commands,
+    mut level: ResMut<CurrentLevel>,
+    mut rng: ResMut<GameRng>,
+    mode: Res<RunMode>,
+    levels: Res<Assets<Level>>,
+    defaults: Res<EnemyPlugin>,
+    windows: Res<Windows>,
+    prefabs: Res<Prefabs>,
+    difficulty: Res<DifficultyScaling>) {
+    if level.spawned { return }
+    if level.pending {
+        level.pending = false;
+        level.spawned = true;
+        return
+    }
+    let definition = match levels.get(&level.handle) {
+        Some(definition) => definition,
+        None => return,
+    };
+
+    let rng = &mut rng.rng;
+    match (*mode, &definition.enemies) {
+        (RunMode::Endless { .. }, _) => {
+            let wave = endless_wave(level.wave, rng);
+            spawn_enemies(&mut cmd, &wave.enemies, rng, &windows, &prefabs, &difficulty);
+            cmd.insert_resource(EndlessRun { wave, pending_shop: false });
+        }
+        (RunMode::Campaign, Some(wave)) => spawn_enemies(&mut cmd, wave, rng, &windows, &prefabs, &difficulty),
+        (RunMode::Campaign, None) => {
+            let wave = EnemyWave { count: defaults.enemy_count.max(0) as u32, kinds: EnemyKind::ALL.to_vec(), fire_rate: defaults.fire_rate, speed: 1.0 };
+            spawn_enemies(&mut cmd, &wave, rng, &windows, &prefabs, &difficulty);
+        }
+    }
+    info!("Started level '{}'", definition.name);
+    level.pending = true;
+}
+
+/// Moves an endless run on once the current wave is cleared: to its boss if it has one, otherwise
+/// to the next generated wave, held back while the shop is open if the cleared wave has one
+fn endless_wave_sys(
+    mut cmd: Commands,
+    mut level: ResMut<CurrentLevel>,
+    mut rng: ResMut<GameRng>,
+    endless: Option<ResMut<EndlessRun>>,
+    enemies: Query<&Enemy>,
+    bosses: Query<&Boss>,
+    windows: Res<Windows>,
+    prefabs: Res<Prefabs>,
+    difficulty: Res<DifficultyScaling>) {
+    let mut endless = match endless {
+        Some(endless) if level.spawned && !endless.pending_shop => endless,
+        _ => return,
+    };
+    if !enemies.is_empty() || !bosses.is_empty() { return }
+
+    if let Some(boss) = endless.wave.boss.take() {
+        let height = windows.get_primary().unwrap().height() / 2.0;
+        spawn_boss(&mut cmd, boss, Vec2::new(0., height - BOSS_VERT_OFFSET), &difficulty);
+        return
+    }
+
+    let shop = endless.wave.shop;
+    level.wave += 1;
+    endless.wave = endless_wave(level.wave, &mut rng.rng);
+    info!("Endless wave {}: {:?}", level.wave, endless.wave);
+    if shop {
+        endless.pending_shop = true;
+        return
+    }
+    spawn_enemies(&mut cmd, &endless.wave.enemies, &mut rng.rng, &windows, &prefabs, &difficulty);
+}
+
+/// Spawns the endless wave held back while the shop was open
+fn endless_shop_closed_sys(
+    mut cmd: Commands,
+    endless: Option<ResMut<EndlessRun>>,
+    mut rng: ResMut<GameRng>,
+    windows: Res<Windows>,
+    prefabs: Res<Prefabs>,
+    difficulty: Res<DifficultyScaling>) {
+    let mut endless = match endless {
+        Some(endless) if endless.pending_shop => endless,
+        _ => return,
+    };
+    endless.pending_shop = false;
+    spawn_enemies(&mut cmd, &endless.wave.enemies, &mut rng.rng, &windows, &prefabs, &difficulty);
+}
+
+fn high_score_sys(mut high_score: ResMut<HighScore>, mut run: ResMut<RunResult>, players: Query<&Player, Changed<Player>>) {
+    for player in players.iter() {
+        run.score = run.score.max(player.score);
+        if player.score > high_score.0 {
+            high_score.0 = player.score;
+        }
+    }
+}
+
+/// Spawns the level's boss once every other enemy has been destroyed
+fn boss_spawn_sys(
+    mut cmd: Commands,
+    mut level: ResMut<CurrentLevel>,
+    levels: Res<Assets<Level>>,
+    enemies: Query<&Enemy>,
+    bosses: Query<&Boss>,
+    windows: Res<Windows>,
+    difficulty: Res<DifficultyScaling>,
+    mode: Res<RunMode>) {
+    if *mode != RunMode::Campaign { return }
+    if !level.spawned || level.boss_spawned { return }
+    if level.pending {
+        level.pending = false;
+        level.boss_spawned = true;
+        return
+    }
+    if !enemies.is_empty() || !bosses.is_empty() { return }
+
+    match levels.get(&level.handle).and_then(|level| level.boss) {
+        Some(boss) => {
+            let height = windows.get_primary().unwrap().height() / 2.0;
+            spawn_boss(&mut cmd, boss, Vec2::new(0., height - BOSS_VERT_OFFSET), &difficulty);
+            level.wave += 1;
+            level.pending = true;
+        }
+        None => {
+            debug!("Level has no boss");
+            level.boss_spawned = true;
+        }
+    }
+}
+/// End game and store state when the level is complete or players healths = 0. The result is
+/// recorded against the campaign level, or as the endless best. Endless runs never complete.
+fn watch_state_sys(
+    mut state: ResMut<State<GameState>>,
+    mut run: ResMut<RunResult>,
+    mut campaign: ResMut<Campaign>,
+    mut best: ResMut<BestScores>,
+    high_score: Res<HighScore>,
+    mode: Res<RunMode>,
+    level: Res<CurrentLevel>,
+    levels: Res<Assets<Level>>,
+    enemies: Query<&Enemy>,
+    bosses: Query<&Boss>,
+    players: Query<&Player>){
+    let campaign_mode = *mode == RunMode::Campaign;
+    let completed = campaign_mode && level.spawned && level.boss_spawned && enemies.is_empty() && bosses.is_empty();
+    if completed || players.is_empty() {
+        run.wave = level.wave;
+        run.completed = completed;
+        run.stars = levels.get(&level.handle).map_or(0, |level| level.stars(run.score, completed));
+        info!("Run finished: score {}, wave {}, completed {}, {} stars", run.score, run.wave, run.completed, run.stars);
+        if campaign_mode {
+            campaign.record_run(run.score, run.completed, run.stars);
+            best.campaign = best.campaign.max(high_score.0);
+        } else {
+            best.endless = best.endless.max(high_score.0);
+            best.endless_wave = best.endless_wave.max(run.wave);
+        }
+        if let Err(err) = best.save() {
+            warn!("Could not save best scores: {}", err);
+        }
+        let _ = state.set(GameState::GameOver);
+    }
+}
 
+/// Removes every gameplay entity when leaving [`GameState::Playing`]
+fn gameplay_cleanup_sys(
+    mut despawn_queue: ResMut<DespawnQueue>,
+    entities: Query<Entity, Or<(With<Player>, With<Enemy>, With<Projectile>, With<Boss>)>>) {
+    for entity in entities.iter() {
+        despawn_queue.despawn(entity);
     }
 }
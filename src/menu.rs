@@ -0,0 +1,475 @@
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use crate::interface::HudFonts;
+use crate::level::{Level, Unlock};
+use crate::manager::{BestScores, Campaign, GameState, HighScore, RunMode, RunResult};
+use crate::player::Player;
+use crate::settings::{key_name, Action, BINDABLE_KEYS, SettingOption, Settings};
+use crate::shop::{PurchaseRequest, Shop};
+use crate::stats::{RunStats, StatsReportLabel};
+
+const BUTTON_COLOR: Color          = Color::rgba(1.0, 1.0, 1.0, 0.1);
+const SELECTED_BUTTON_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.35);
+const MENU_BACKGROUND: Color       = Color::rgb(0.02, 0.02, 0.08);
+const OVERLAY_BACKGROUND: Color    = Color::rgba(0.0, 0.0, 0.0, 0.7);
+
+/// Root node of the current menu screen, removed when its state is left
+#[derive(Component)]
+struct MenuRoot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuAction {
+    Start,
+    HighScores,
+    Settings,
+    Quit,
+    Resume,
+    Restart,
+    MainMenu,
+    Setting(SettingOption),
+    /// Play the campaign level at this index
+    Level(usize),
+    NextLevel,
+    /// Start an endless run with a random seed
+    Endless,
+    /// Buy the shop upgrade at this index
+    Buy(usize),
+}
+
+#[derive(Component)]
+struct MenuButton {
+    index: usize,
+    action: MenuAction,
+}
+
+/// Index of the highlighted button on the current menu screen
+#[derive(Default)]
+struct MenuSelection(usize);
+
+/// Action waiting for a key press to rebind it, while the settings menu is open
+#[derive(Default)]
+struct AwaitingBinding(Option<Action>);
+
+/// Sent when a menu button is activated by keyboard, gamepad or mouse, or adjusted with left and right.
+/// `step` is `1` for activation and `-1`/`1` for adjustment.
+pub struct MenuActionEvent {
+    pub action: MenuAction,
+    pub step: i32,
+}
+
+/// Spawns a full screen menu with a title, lines of text and a vertical list of buttons
+fn spawn_menu<S: AsRef<str>>(cmd: &mut Commands, fonts: &HudFonts, background: Color, title: &str, lines: &[String], buttons: &[(S, MenuAction)]) {
+    cmd.insert_resource(MenuSelection::default());
+    cmd.spawn_bundle(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            flex_direction: FlexDirection::ColumnReverse,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        color: UiColor(background),
+        ..Default::default()
+    }).insert(MenuRoot).with_children(|parent| {
+        parent.spawn_bundle(TextBundle {
+            style: Style { margin: Rect::all(Val::Px(20.0)), ..Default::default() },
+            text: Text::with_section(title, TextStyle { font: fonts.black.clone(), font_size: 64.0, color: Color::WHITE }, Default::default()),
+            ..Default::default()
+        });
+
+        for line in lines {
+            parent.spawn_bundle(TextBundle {
+                style: Style { margin: Rect::all(Val::Px(4.0)), ..Default::default() },
+                text: Text::with_section(line.as_str(), TextStyle { font: fonts.thin.clone(), font_size: 28.0, color: Color::WHITE }, Default::default()),
+                ..Default::default()
+            });
+        }
+
+        for (index, (label, action)) in buttons.iter().enumerate() {
+            parent.spawn_bundle(ButtonBundle {
+                style: Style {
+                    size: Size::new(Val::Px(320.0), Val::Px(50.0)),
+                    margin: Rect::all(Val::Px(6.0)),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..Default::default()
+                },
+                color: UiColor(if index == 0 { SELECTED_BUTTON_COLOR } else { BUTTON_COLOR }),
+                ..Default::default()
+            }).insert(MenuButton { index, action: *action }).with_children(|button| {
+                button.spawn_bundle(TextBundle {
+                    text: Text::with_section(label.as_ref(), TextStyle { font: fonts.bold.clone(), font_size: 30.0, color: Color::WHITE }, Default::default()),
+                    ..Default::default()
+                });
+            });
+        }
+    });
+}
+
+fn main_menu_setup_sys(mut cmd: Commands, fonts: Res<HudFonts>) {
+    spawn_menu(&mut cmd, &fonts, MENU_BACKGROUND, "ASSAULT", &[], &[
+        ("START", MenuAction::Start),
+        ("ENDLESS", MenuAction::Endless),
+        ("HIGH SCORES", MenuAction::HighScores),
+        ("SETTINGS", MenuAction::Settings),
+        ("QUIT", MenuAction::Quit),
+    ]);
+}
+
+/// Stars out of three, such as `**-`
+fn star_label(stars: u32) -> String {
+    (0..3).map(|star| if star < stars { '*' } else { '-' }).collect()
+}
+
+fn level_select_setup_sys(mut cmd: Commands, fonts: Res<HudFonts>, campaign: Res<Campaign>, levels: Res<Assets<Level>>) {
+    let mut buttons: Vec<(String, MenuAction)> = campaign.levels.iter().enumerate()
+        .map(|(index, level)| {
+            let name = campaign.level_handles.get(index)
+                .and_then(|handle| levels.get(handle))
+                .map_or_else(|| level.path.clone(), |level| level.name.to_uppercase());
+            let label = if !campaign.is_unlocked(index) {
+                match level.unlock {
+                    Unlock::Stars(stars) => format!("{}  LOCKED, {} STARS", index + 1, stars),
+                    _ => format!("{}  LOCKED", index + 1),
+                }
+            } else {
+                let record = campaign.record(index);
+                format!("{} {}  {}  BEST {}", index + 1, name, star_label(record.stars), record.best_score)
+            };
+            (label, MenuAction::Level(index))
+        })
+        .collect();
+    buttons.push((String::from("BACK"), MenuAction::MainMenu));
+
+    let lines = if campaign.levels.is_empty() {
+        vec![String::from("LOADING")]
+    } else {
+        vec![format!("{} STARS", campaign.total_stars())]
+    };
+    spawn_menu(&mut cmd, &fonts, MENU_BACKGROUND, "CAMPAIGN", &lines, &buttons);
+}
+
+fn high_scores_setup_sys(mut cmd: Commands, fonts: Res<HudFonts>, best: Res<BestScores>) {
+    let lines = [
+        format!("CAMPAIGN {}", best.campaign),
+        format!("ENDLESS {}  WAVE {}", best.endless, best.endless_wave),
+    ];
+    spawn_menu(&mut cmd, &fonts, MENU_BACKGROUND, "HIGH SCORES", &lines, &[
+        ("BACK", MenuAction::MainMenu),
+    ]);
+}
+
+fn settings_setup_sys(mut cmd: Commands, fonts: Res<HudFonts>, settings: Res<Settings>) {
+    let mut buttons: Vec<(String, MenuAction)> = SettingOption::all().into_iter()
+        .map(|option| (settings.label(option), MenuAction::Setting(option)))
+        .collect();
+    buttons.push((String::from("BACK"), MenuAction::MainMenu));
+
+    cmd.insert_resource(AwaitingBinding::default());
+    spawn_menu(&mut cmd, &fonts, MENU_BACKGROUND, "SETTINGS", &[String::from("LEFT/RIGHT TO CHANGE")], &buttons);
+}
+
+/// Persists settings when leaving the settings menu
+fn settings_save_sys(settings: Res<Settings>) {
+    if let Err(err) = settings.save() {
+        warn!("Could not save settings: {}", err);
+    }
+}
+
+/// Keeps settings button labels in sync with their values
+fn settings_label_sys(
+    settings: Res<Settings>,
+    awaiting: Option<Res<AwaitingBinding>>,
+    buttons: Query<(&MenuButton, &Children)>,
+    mut texts: Query<&mut Text>) {
+    let awaiting_changed = awaiting.as_ref().map_or(false, |awaiting| awaiting.is_changed());
+    if !settings.is_changed() && !awaiting_changed { return }
+
+    for (button, children) in buttons.iter() {
+        if let MenuAction::Setting(option) = button.action {
+            let label = match (&awaiting, option) {
+                (Some(awaiting), SettingOption::Binding(action)) if awaiting.0 == Some(action) => format!("{} PRESS A KEY, DEL CANCELS", action.name()),
+                _ => settings.label(option),
+            };
+            for child in children.iter() {
+                if let Ok(mut text) = texts.get_mut(*child) {
+                    text.sections[0].value = label.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Binds the next bindable key pressed to the action awaiting a binding, swapping with any action
+/// already on that key. Delete, which can't be bound, cancels so every bindable key can be used.
+fn rebind_sys(mut keyboard_input: ResMut<Input<KeyCode>>, awaiting: Option<ResMut<AwaitingBinding>>, mut settings: ResMut<Settings>) {
+    let mut awaiting = match awaiting {
+        Some(awaiting) => awaiting,
+        None => return,
+    };
+    let action = match awaiting.0 {
+        Some(action) => action,
+        None => return,
+    };
+
+    let key = match keyboard_input.get_just_pressed().next() {
+        Some(key) => *key,
+        None => return,
+    };
+    keyboard_input.reset(key);
+    if key != KeyCode::Delete {
+        if !BINDABLE_KEYS.contains(&key) { return }
+        if let Some(swapped) = settings.bindings.set(action, key) {
+            info!("{} moved to {} to free {}", swapped.name(), key_name(settings.bindings.get(swapped)), key_name(key));
+        }
+    }
+    awaiting.0 = None;
+}
+
+fn pause_setup_sys(mut cmd: Commands, fonts: Res<HudFonts>) {
+    spawn_menu(&mut cmd, &fonts, OVERLAY_BACKGROUND, "PAUSED", &[], &[
+        ("RESUME", MenuAction::Resume),
+        ("RESTART", MenuAction::Restart),
+        ("QUIT", MenuAction::MainMenu),
+    ]);
+}
+
+/// Builds the shop, and rebuilds it after each purchase so prices and credits stay current.
+/// The selection is kept when rebuilding.
+fn shop_menu_sys(
+    mut cmd: Commands,
+    fonts: Res<HudFonts>,
+    shop: Res<Shop>,
+    selection: Option<Res<MenuSelection>>,
+    players: Query<&Player>,
+    menus: Query<Entity, With<MenuRoot>>) {
+    if !shop.is_changed() && !menus.is_empty() { return }
+
+    let mut buttons: Vec<(String, MenuAction)> = shop.upgrades.iter().enumerate()
+        .map(|(index, upgrade)| {
+            let price = shop.price(upgrade).map_or_else(|| String::from("MAX"), |price| format!("{} CR", price));
+            (format!("{} {}/{}  {}", upgrade.name.to_uppercase(), shop.level(upgrade), upgrade.max_level, price), MenuAction::Buy(index))
+        })
+        .collect();
+    buttons.push((String::from("NEXT WAVE"), MenuAction::Resume));
+
+    let credits = players.iter().map(|player| player.credits).max().unwrap_or(0);
+    let selected = match selection {
+        Some(selection) if !menus.is_empty() => selection.0,
+        _ => 0,
+    };
+    for menu in menus.iter() {
+        cmd.entity(menu).despawn_recursive();
+    }
+    spawn_menu(&mut cmd, &fonts, OVERLAY_BACKGROUND, "SHOP", &[format!("{} CREDITS", credits)], &buttons);
+    cmd.insert_resource(MenuSelection(selected));
+}
+
+fn game_over_setup_sys(
+    mut cmd: Commands,
+    fonts: Res<HudFonts>,
+    run: Res<RunResult>,
+    stats: Res<RunStats>,
+    high_score: Res<HighScore>,
+    campaign: Res<Campaign>,
+    mode: Res<RunMode>) {
+    let title = if run.completed { "LEVEL COMPLETE" } else { "GAME OVER" };
+    let time = stats.total_time().round() as u32;
+    let mut lines = vec![
+        format!("SCORE {}", run.score),
+        format!("WAVE {}  TIME {}:{:02}", run.wave, time / 60, time % 60),
+        format!("ACCURACY {:.0}%  ({} / {})", stats.accuracy() * 100., stats.hits, stats.shots),
+        format!("KILLS {}  MAX COMBO {}", stats.total_kills(), stats.max_combo),
+        format!("DAMAGE TAKEN {}", stats.damage_taken),
+    ];
+    if run.completed {
+        lines.insert(1, format!("STARS {}", star_label(run.stars)));
+    }
+    if let RunMode::Endless { seed } = *mode {
+        lines.push(format!("SEED {}", seed));
+    }
+    if run.score >= high_score.0 && run.score > 0 {
+        lines.push(String::from("NEW HIGH SCORE"));
+    }
+
+    let mut buttons = Vec::new();
+    if run.completed && campaign.is_unlocked(campaign.selected + 1) {
+        buttons.push(("NEXT LEVEL", MenuAction::NextLevel));
+    }
+    buttons.push(("RESTART", MenuAction::Restart));
+    if *mode == RunMode::Campaign {
+        buttons.push(("LEVEL SELECT", MenuAction::Start));
+    }
+    buttons.push(("MAIN MENU", MenuAction::MainMenu));
+    spawn_menu(&mut cmd, &fonts, OVERLAY_BACKGROUND, title, &lines, &buttons);
+}
+
+fn menu_cleanup_sys(mut cmd: Commands, menus: Query<Entity, With<MenuRoot>>) {
+    for menu in menus.iter() {
+        cmd.entity(menu).despawn_recursive();
+    }
+}
+
+/// Moves the menu selection with keyboard, gamepad d-pad or mouse hover and activates the selected button
+fn menu_navigation_sys(
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+    gamepads: Res<Gamepads>,
+    selection: Option<ResMut<MenuSelection>>,
+    awaiting: Option<Res<AwaitingBinding>>,
+    buttons: Query<&MenuButton>,
+    interactions: Query<(&MenuButton, &Interaction), Changed<Interaction>>,
+    mut action_events: EventWriter<MenuActionEvent>) {
+    let mut selection = match selection {
+        Some(selection) => selection,
+        None => return,
+    };
+    let count = buttons.iter().count();
+    if count == 0 { return }
+    if awaiting.map_or(false, |awaiting| awaiting.0.is_some()) { return }
+
+    let gamepad_pressed = |button_type: GamepadButtonType| {
+        gamepads.iter().any(|gamepad| gamepad_input.just_pressed(GamepadButton(*gamepad, button_type)))
+    };
+
+    if keyboard_input.just_pressed(KeyCode::Up) || keyboard_input.just_pressed(KeyCode::W) || gamepad_pressed(GamepadButtonType::DPadUp) {
+        selection.0 = (selection.0 + count - 1) % count;
+    }
+    if keyboard_input.just_pressed(KeyCode::Down) || keyboard_input.just_pressed(KeyCode::S) || gamepad_pressed(GamepadButtonType::DPadDown) {
+        selection.0 = (selection.0 + 1) % count;
+    }
+
+    let selected = buttons.iter().find(|button| button.index == selection.0).map(|button| button.action);
+    let mut step = 0;
+    if keyboard_input.just_pressed(KeyCode::Left) || keyboard_input.just_pressed(KeyCode::A) || gamepad_pressed(GamepadButtonType::DPadLeft) { step = -1 }
+    if keyboard_input.just_pressed(KeyCode::Right) || keyboard_input.just_pressed(KeyCode::D) || gamepad_pressed(GamepadButtonType::DPadRight) { step = 1 }
+    if let (Some(action @ MenuAction::Setting(_)), true) = (selected, step != 0) {
+        action_events.send(MenuActionEvent { action, step });
+    }
+
+    let mut activate = false;
+    for (button, interaction) in interactions.iter() {
+        match interaction {
+            Interaction::Clicked => { selection.0 = button.index; activate = true; }
+            Interaction::Hovered => { selection.0 = button.index; }
+            Interaction::None => {}
+        }
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Return) || keyboard_input.just_pressed(KeyCode::Space) || gamepad_pressed(GamepadButtonType::South) {
+        // Stops the same key press activating a button on the next screen
+        keyboard_input.reset(KeyCode::Return);
+        keyboard_input.reset(KeyCode::Space);
+        activate = true;
+    }
+
+    if activate {
+        if let Some(button) = buttons.iter().find(|button| button.index == selection.0) {
+            action_events.send(MenuActionEvent { action: button.action, step: 1 });
+        }
+    }
+}
+
+fn menu_highlight_sys(selection: Option<Res<MenuSelection>>, mut buttons: Query<(&MenuButton, &mut UiColor)>) {
+    let selection = match selection {
+        Some(selection) if selection.is_changed() => selection,
+        _ => return,
+    };
+    for (button, mut color) in buttons.iter_mut() {
+        color.0 = if button.index == selection.0 { SELECTED_BUTTON_COLOR } else { BUTTON_COLOR };
+    }
+}
+
+fn menu_action_sys(
+    mut action_events: EventReader<MenuActionEvent>,
+    mut state: ResMut<State<GameState>>,
+    mut settings: ResMut<Settings>,
+    mut campaign: ResMut<Campaign>,
+    mut mode: ResMut<RunMode>,
+    mut awaiting: Option<ResMut<AwaitingBinding>>,
+    mut purchase_requests: EventWriter<PurchaseRequest>,
+    mut exit: EventWriter<AppExit>) {
+    for &MenuActionEvent{action, step} in action_events.iter() {
+        let result = match action {
+            MenuAction::Start      => state.replace(GameState::LevelSelect),
+            MenuAction::HighScores => state.set(GameState::HighScores),
+            MenuAction::Settings   => state.set(GameState::Settings),
+            MenuAction::Resume     => state.pop(),
+            MenuAction::Restart    => state.replace(GameState::Playing),
+            MenuAction::MainMenu   => state.replace(GameState::MainMenu),
+            MenuAction::Quit       => { exit.send(AppExit); Ok(()) }
+            MenuAction::Setting(SettingOption::Binding(binding)) => {
+                if let Some(awaiting) = awaiting.as_mut() { awaiting.0 = Some(binding) }
+                Ok(())
+            }
+            MenuAction::Setting(option) => { settings.adjust(option, step); Ok(()) }
+            MenuAction::Level(index) => match campaign.select(index) {
+                Ok(()) => { *mode = RunMode::Campaign; state.set(GameState::Playing) }
+                Err(err) => { warn!("{}", err); Ok(()) }
+            },
+            MenuAction::NextLevel => match campaign.select(campaign.selected + 1) {
+                Ok(()) => state.replace(GameState::Playing),
+                Err(err) => { warn!("{}", err); Ok(()) }
+            },
+            MenuAction::Endless => { *mode = RunMode::endless(); state.replace(GameState::Playing) }
+            MenuAction::Buy(index) => { purchase_requests.send(PurchaseRequest(index)); Ok(()) }
+        };
+        if let Err(err) = result {
+            warn!("Menu action {:?} ignored: {:?}", action, err);
+        }
+    }
+}
+
+/// Pauses and resumes the game with the pause binding or the gamepad start button
+fn pause_input_sys(
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+    gamepads: Res<Gamepads>,
+    settings: Res<Settings>,
+    mut state: ResMut<State<GameState>>) {
+    let pause_key = settings.bindings.pause;
+    let pressed = keyboard_input.just_pressed(pause_key)
+        || gamepads.iter().any(|gamepad| gamepad_input.just_pressed(GamepadButton(*gamepad, GamepadButtonType::Start)));
+    if !pressed { return }
+
+    keyboard_input.reset(pause_key);
+    let result = match state.current() {
+        GameState::Playing => state.push(GameState::Paused),
+        GameState::Paused  => state.pop(),
+        GameState::Online  => state.set(GameState::MainMenu),
+        _ => Ok(()),
+    };
+    if let Err(err) = result {
+        warn!("Pause toggle ignored: {:?}", err);
+    }
+}
+
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_event::<MenuActionEvent>()
+            .add_system(menu_navigation_sys)
+            .add_system(menu_highlight_sys)
+            .add_system(menu_action_sys)
+            .add_system(pause_input_sys)
+            .add_system_set(SystemSet::on_update(GameState::Settings)
+                .with_system(settings_label_sys)
+                .with_system(rebind_sys))
+            .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(main_menu_setup_sys))
+            .add_system_set(SystemSet::on_exit(GameState::MainMenu).with_system(menu_cleanup_sys))
+            .add_system_set(SystemSet::on_enter(GameState::LevelSelect).with_system(level_select_setup_sys))
+            .add_system_set(SystemSet::on_exit(GameState::LevelSelect).with_system(menu_cleanup_sys))
+            .add_system_set(SystemSet::on_enter(GameState::HighScores).with_system(high_scores_setup_sys))
+            .add_system_set(SystemSet::on_exit(GameState::HighScores).with_system(menu_cleanup_sys))
+            .add_system_set(SystemSet::on_enter(GameState::Settings).with_system(settings_setup_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Settings).with_system(menu_cleanup_sys).with_system(settings_save_sys))
+            .add_system_set(SystemSet::on_enter(GameState::Paused).with_system(pause_setup_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Paused).with_system(menu_cleanup_sys))
+            .add_system_set(SystemSet::on_update(GameState::Shop).with_system(shop_menu_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Shop).with_system(menu_cleanup_sys))
+            .add_system_set(SystemSet::on_enter(GameState::GameOver).with_system(game_over_setup_sys.after(StatsReportLabel)))
+            .add_system_set(SystemSet::on_exit(GameState::GameOver).with_system(menu_cleanup_sys));
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use bevy::prelude::*;
+use rand::prelude::*;
+use crate::common::{collision_sys, despawn_queue_sys, health_event_sys, shooter_reload_sys, CollisionBox, CollisionEvent, DeathEvent, DespawnQueue, FriendlyFire, GameTime, Health, Lives, Shooter};
+use crate::console::{parse, ConsoleCommandsExt};
+use crate::manager::GameState;
+use crate::player::{player_hit_sys, player_move_sys, player_shoot_sys, Player, PlayerBundle, PlayerInput};
+use crate::prefab::Prefabs;
+use crate::projectile::{projectile_hit_sys, projectile_move_sys, Projectile, ProjectileHitEvent};
+use crate::settings::Settings;
+use crate::snapshot::WorldSnapshot;
+
+/// Length of one simulation frame in seconds
+const SIM_STEP: f32                 = 1. / 60.;
+/// Most frames simulated in one update, so a stall doesn't turn into a burst of catching up
+const MAX_STEPS_PER_UPDATE: u32     = 4;
+/// Frames between reading local input and simulating with it, which hides some latency
+const INPUT_DELAY: usize            = 2;
+/// Furthest the simulation runs ahead of the last input received from the peer
+const MAX_PREDICTION: usize         = 8;
+/// Most inputs sent in one packet
+const MAX_PACKET_INPUTS: usize      = 128;
+const PACKET_HEADER_SIZE: usize     = 8;
+const PLAYERS: usize                = 2;
+const PLAYER_VERT_OFFSET: f32       = 200.;
+/// Projectiles further than this from the centre are removed. Fixed rather than taken from the
+/// window, so both peers agree.
+const ARENA_HALF_SIZE: Vec2         = Vec2::new(800., 600.);
+const LOOPBACK_ADDRESS: &str        = "127.0.0.1:0";
+const LOOPBACK_TIMEOUT: Duration    = Duration::from_secs(30);
+/// Chance each frame of a loopback player changing the buttons they hold
+const LOOPBACK_INPUT_CHANGE: f64    = 0.1;
+/// Chance each round of a loopback instance ticking, so the two drift apart and have to roll back
+const LOOPBACK_TICK_CHANCE: f64     = 0.7;
+
+/// Which of the two online players an entity is, 0 or 1
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetPlayer(pub usize);
+
+/// Frames to simulate, returned by [`RollbackSession::advance`]
+pub struct Advance {
+    /// Frame to restore the state from before simulating, if a prediction was wrong
+    pub rollback: Option<usize>,
+    pub frames: Range<usize>,
+}
+
+/// Exchanges inputs with the peer over UDP and decides what to simulate. The peer's input is
+/// predicted to be unchanged until it arrives. When it arrives and differs from the prediction the
+/// simulation rolls back to the first wrong frame and re-simulates from there.
+///
+/// Every packet holds all the local inputs the peer has not acknowledged, so lost packets need no
+/// special handling.
+pub struct RollbackSession {
+    /// Index of the local player
+    local: usize,
+    socket: UdpSocket,
+    peer: SocketAddr,
+    /// Local input for each frame, starting at frame 0
+    local_inputs: Vec<u8>,
+    /// Peer input for each frame received so far, starting at frame 0
+    remote_inputs: Vec<u8>,
+    /// Peer input each simulated frame used, whether received or predicted
+    used_inputs: Vec<u8>,
+    /// Number of local inputs the peer has received
+    acked: usize,
+    /// Next frame to simulate
+    frame: usize,
+    /// Frames before this were simulated with the peer's real input, so will never roll back
+    confirmed: usize,
+}
+
+impl RollbackSession {
+    pub fn new(local: usize, socket: UdpSocket, peer: SocketAddr) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            local,
+            socket,
+            peer,
+            local_inputs: vec![0; INPUT_DELAY],
+            remote_inputs: Vec::new(),
+            used_inputs: Vec::new(),
+            acked: 0,
+            frame: 0,
+            confirmed: 0,
+        })
+    }
+
+    /// Binds `port` on every interface and plays as player `local` against `peer`
+    pub fn bind(local: usize, port: u16, peer: SocketAddr) -> io::Result<Self> {
+        Self::new(local, UdpSocket::bind(("0.0.0.0", port))?, peer)
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn confirmed(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Reads every packet waiting from the peer
+    pub fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; PACKET_HEADER_SIZE + MAX_PACKET_INPUTS];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) if from == self.peer => self.read_packet(&buffer[..len]),
+                Ok(_) => {}
+                // Reported on some platforms when the peer isn't listening yet
+                Err(error) if error.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Sends the peer every local input it hasn't acknowledged, and acknowledges its inputs
+    pub fn send(&self) -> io::Result<()> {
+        let unacked = &self.local_inputs[self.acked..];
+        let unacked = &unacked[..unacked.len().min(MAX_PACKET_INPUTS)];
+        let mut packet = Vec::with_capacity(PACKET_HEADER_SIZE + unacked.len());
+        packet.extend_from_slice(&(self.acked as u32).to_le_bytes());
+        packet.extend_from_slice(&(self.remote_inputs.len() as u32).to_le_bytes());
+        packet.extend_from_slice(unacked);
+        match self.socket.send_to(&packet, self.peer) {
+            Ok(_) => Ok(()),
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionReset) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Packets are the first frame of the inputs, the number of inputs received from the sender,
+    /// then one byte of input per frame
+    fn read_packet(&mut self, packet: &[u8]) {
+        if packet.len() < PACKET_HEADER_SIZE { return }
+        let read_u32 = |at: usize| u32::from_le_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]]) as usize;
+        let (start, ack) = (read_u32(0), read_u32(4));
+        let inputs = &packet[PACKET_HEADER_SIZE..];
+
+        self.acked = self.acked.max(ack.min(self.local_inputs.len()));
+        // Packets can arrive out of order. Ignore any that would leave a gap.
+        if start > self.remote_inputs.len() { return }
+        let known = self.remote_inputs.len() - start;
+        if known < inputs.len() {
+            self.remote_inputs.extend_from_slice(&inputs[known..]);
+        }
+    }
+
+    /// Records the local input for the next frame, or just checks earlier predictions if `local_input`
+    /// is `None`. Returns the frames to simulate, or `None` if there is nothing to do, such as when
+    /// too far ahead of the peer.
+    pub fn advance(&mut self, local_input: Option<u8>) -> Option<Advance> {
+        let checked = self.frame.min(self.remote_inputs.len());
+        let rollback = (self.confirmed..checked).find(|&frame| self.used_inputs[frame] != self.remote_inputs[frame]);
+
+        let mut end = self.frame;
+        if let Some(input) = local_input {
+            if self.frame < self.remote_inputs.len() + MAX_PREDICTION {
+                self.local_inputs.push(input);
+                end += 1;
+            }
+        }
+
+        let start = rollback.unwrap_or(self.frame);
+        self.frame = end;
+        self.confirmed = end.min(self.remote_inputs.len());
+        if start < end { Some(Advance { rollback, frames: start..end }) } else { None }
+    }
+
+    /// Every player's input for `frame`, predicting the peer's if it hasn't arrived. Must be called
+    /// for the frames of an [`Advance`] in order, as they are simulated.
+    pub fn inputs(&mut self, frame: usize) -> [u8; PLAYERS] {
+        let remote = match self.remote_inputs.get(frame) {
+            Some(input) => *input,
+            None => self.remote_inputs.last().copied().unwrap_or(0),
+        };
+        self.used_inputs.truncate(frame);
+        self.used_inputs.push(remote);
+
+        let mut inputs = [remote; PLAYERS];
+        inputs[self.local] = self.local_inputs[frame];
+        inputs
+    }
+}
+
+/// Removes projectiles which leave the arena
+fn sim_bounds_sys(mut despawn_queue: ResMut<DespawnQueue>, projectiles: Query<(Entity, &Transform), With<Projectile>>) {
+    for (entity, transform) in projectiles.iter() {
+        let position = transform.translation.truncate();
+        if position.x.abs() > ARENA_HALF_SIZE.x || position.y.abs() > ARENA_HALF_SIZE.y {
+            despawn_queue.despawn(entity);
+        }
+    }
+}
+
+/// Collisions are checked with [`GlobalTransform`], which is normally only updated after the
+/// frame. Re-simulated frames need it up to date.
+fn sim_transform_sys(mut colliders: Query<(&Transform, &mut GlobalTransform), (With<CollisionBox>, Without<Parent>)>) {
+    for (transform, mut global_transform) in colliders.iter_mut() {
+        *global_transform = GlobalTransform::from(*transform);
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+enum SimLabel {
+    Reload,
+    Move,
+    Shoot,
+    ProjectileMove,
+    Bounds,
+    Transform,
+    Collision,
+    Hit,
+    Damage,
+}
+
+/// Hash of the simulated state, which is equal on both peers while they are in sync
+fn checksum(world: &mut World) -> u64 {
+    let mut players = world.query::<(&NetPlayer, &Transform, &Shooter, &Health, &Lives)>();
+    let mut players: Vec<_> = players.iter(world)
+        .map(|(player, transform, shooter, health, lives)| (player.0, transform.translation.x.to_bits(), transform.translation.y.to_bits(), shooter.ammo_count, shooter.reloading, health.health, lives.remaining))
+        .collect();
+    // Entity order can differ between peers after a rollback, so sort first
+    players.sort_unstable();
+    let mut projectiles = world.query::<(&Projectile, &Transform)>();
+    let mut projectiles: Vec<_> = projectiles.iter(world)
+        .map(|(projectile, transform)| (transform.translation.x.to_bits(), transform.translation.y.to_bits(), projectile.direction.x.to_bits(), projectile.direction.y.to_bits()))
+        .collect();
+    projectiles.sort_unstable();
+
+    let mut hasher = DefaultHasher::new();
+    players.hash(&mut hasher);
+    projectiles.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Gameplay events sent while simulating one frame
+#[derive(Default)]
+struct FrameEvents {
+    hits: Vec<ProjectileHitEvent>,
+    deaths: Vec<DeathEvent>,
+}
+
+/// Swaps `value` in for the world's resource of its type, returning the one it replaced
+fn replace_resource<T: Send + Sync + 'static>(world: &mut World, value: T) -> Option<T> {
+    let previous = world.remove_resource::<T>();
+    world.insert_resource(value);
+    previous
+}
+
+/// Puts back a resource taken out by [`replace_resource`]
+fn restore_resource<T: Send + Sync + 'static>(world: &mut World, previous: Option<T>) {
+    match previous {
+        Some(previous) => world.insert_resource(previous),
+        None => { world.remove_resource::<T>(); }
+    }
+}
+
+/// Runs the online gameplay systems one fixed step at a time, and rolls them back. Online play is
+/// versus: projectiles hit the other player, and damage and lost lives are part of the rolled
+/// back state.
+///
+/// The simulation sends its events into its own [`Events`] resources, so a mispredicted hit is
+/// never seen by the rest of the game. [`ProjectileHitEvent`]s and [`DeathEvent`]s are passed on
+/// to the world once the frame they happened in is confirmed.
+pub struct Simulation {
+    stage: SystemStage,
+    /// State before each frame which may still be rolled back to, oldest first
+    snapshots: VecDeque<(usize, WorldSnapshot)>,
+    /// Events of each frame not confirmed yet, oldest first
+    events: VecDeque<(usize, FrameEvents)>,
+    /// [`checksum`] of the state after each frame
+    pub checksums: Vec<u64>,
+    pub rollbacks: u32,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        let stage = SystemStage::single_threaded()
+            .with_system(shooter_reload_sys.label(SimLabel::Reload))
+            .with_system(player_move_sys.label(SimLabel::Move).after(SimLabel::Reload))
+            .with_system(player_shoot_sys.label(SimLabel::Shoot).after(SimLabel::Move))
+            .with_system(projectile_move_sys.label(SimLabel::ProjectileMove).after(SimLabel::Shoot))
+            .with_system(sim_bounds_sys.label(SimLabel::Bounds).after(SimLabel::ProjectileMove))
+            .with_system(sim_transform_sys.label(SimLabel::Transform).after(SimLabel::Bounds))
+            .with_system(collision_sys.label(SimLabel::Collision).after(SimLabel::Transform))
+            .with_system(projectile_hit_sys.label(SimLabel::Hit).after(SimLabel::Collision))
+            .with_system(player_hit_sys.label(SimLabel::Damage).after(SimLabel::Hit))
+            .with_system(health_event_sys.after(SimLabel::Damage))
+            .with_system(despawn_queue_sys.exclusive_system().at_end());
+        Self { stage, snapshots: VecDeque::new(), events: VecDeque::new(), checksums: Vec::new(), rollbacks: 0 }
+    }
+}
+
+impl Simulation {
+    /// Rolls back if needed, then simulates the frames of `advance`
+    pub fn run(&mut self, world: &mut World, session: &mut RollbackSession, advance: Advance) {
+        if let Some(frame) = advance.rollback {
+            match self.snapshots.iter().find(|(snapshot_frame, _)| *snapshot_frame == frame) {
+                Some((_, snapshot)) => { snapshot.restore(world); }
+                None => error!("No snapshot of frame {} to roll back to", frame),
+            }
+            self.rollbacks += 1;
+        }
+
+        let game_time = replace_resource(world, GameTime::fixed(Duration::from_secs_f32(SIM_STEP)));
+        let friendly_fire = replace_resource(world, FriendlyFire::Versus);
+        let collisions = replace_resource(world, Events::<CollisionEvent>::default());
+        let hits = replace_resource(world, Events::<ProjectileHitEvent>::default());
+        let deaths = replace_resource(world, Events::<DeathEvent>::default());
+        for frame in advance.frames {
+            self.snapshots.retain(|(snapshot_frame, _)| *snapshot_frame < frame);
+            self.snapshots.push_back((frame, WorldSnapshot::capture(world)));
+
+            let inputs = session.inputs(frame);
+            let mut players = world.query::<(&NetPlayer, &mut PlayerInput)>();
+            for (player, mut input) in players.iter_mut(world) {
+                input.update(inputs[player.0]);
+            }
+            self.stage.run(world);
+
+            self.events.retain(|(event_frame, _)| *event_frame < frame);
+            self.events.push_back((frame, FrameEvents {
+                hits: world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().drain().collect(),
+                deaths: world.get_resource_mut::<Events<DeathEvent>>().unwrap().drain().collect(),
+            }));
+            world.get_resource_mut::<Events<CollisionEvent>>().unwrap().clear();
+
+            self.checksums.truncate(frame);
+            self.checksums.push(checksum(world));
+        }
+        restore_resource(world, game_time);
+        restore_resource(world, friendly_fire);
+        restore_resource(world, collisions);
+        restore_resource(world, hits);
+        restore_resource(world, deaths);
+
+        let confirmed = session.confirmed();
+        self.snapshots.retain(|(snapshot_frame, _)| *snapshot_frame >= confirmed);
+        while self.events.front().map_or(false, |(frame, _)| *frame < confirmed) {
+            let (_, events) = self.events.pop_front().unwrap();
+            if let Some(mut hits) = world.get_resource_mut::<Events<ProjectileHitEvent>>() {
+                for hit in events.hits { hits.send(hit) }
+            }
+            if let Some(mut deaths) = world.get_resource_mut::<Events<DeathEvent>>() {
+                for death in events.deaths { deaths.send(death) }
+            }
+        }
+    }
+}
+
+/// Bundle for online player `index`. The first is at the bottom of the arena and the second at
+/// the top, turned around to face the first.
+fn net_player_bundle(index: usize, prefabs: &Prefabs) -> PlayerBundle {
+    let mut bundle = PlayerBundle::default().with_prefab(&prefabs.get("player"));
+    bundle.player.index = index;
+    bundle.sprite.transform = match index {
+        0 => Transform::from_xyz(0., -PLAYER_VERT_OFFSET, 0.),
+        _ => Transform::from_xyz(0., PLAYER_VERT_OFFSET, 0.).with_rotation(Quat::from_rotation_z(std::f32::consts::PI)),
+    };
+    bundle
+}
+
+/// The online game being played, while in [`GameState::Online`]
+pub struct Netplay {
+    session: RollbackSession,
+    simulation: Simulation,
+    /// Real time not yet simulated
+    accumulator: f32,
+}
+
+impl Netplay {
+    pub fn new(session: RollbackSession) -> Self {
+        Self { session, simulation: Default::default(), accumulator: 0. }
+    }
+}
+
+fn netplay_spawn_sys(mut cmd: Commands, prefabs: Res<Prefabs>) {
+    for index in 0..PLAYERS {
+        cmd.spawn_bundle(net_player_bundle(index, &prefabs)).insert(NetPlayer(index));
+    }
+}
+
+/// Simulates a fixed step for each [`SIM_STEP`] of real time passed, with the local player
+/// controlled by the keyboard
+fn netplay_sys(world: &mut World) {
+    let mut netplay = match world.remove_resource::<Netplay>() {
+        Some(netplay) => netplay,
+        None => return,
+    };
+    let delta = world.get_resource::<Time>().map_or(0., |time| time.delta_seconds());
+    let local_input = match (world.get_resource::<Input<KeyCode>>(), world.get_resource::<Settings>()) {
+        (Some(keyboard_input), Some(settings)) => PlayerInput::from_keyboard(keyboard_input, &settings.bindings),
+        _ => 0,
+    };
+
+    if let Err(error) = netplay.session.receive() {
+        warn!("Failed to receive from peer: {}", error);
+    }
+    netplay.accumulator = (netplay.accumulator + delta).min(SIM_STEP * MAX_STEPS_PER_UPDATE as f32);
+    while netplay.accumulator >= SIM_STEP {
+        netplay.accumulator -= SIM_STEP;
+        if let Some(advance) = netplay.session.advance(Some(local_input)) {
+            netplay.simulation.run(world, &mut netplay.session, advance);
+        }
+    }
+    if let Err(error) = netplay.session.send() {
+        warn!("Failed to send to peer: {}", error);
+    }
+
+    world.insert_resource(netplay);
+}
+
+fn netplay_cleanup_sys(mut cmd: Commands, entities: Query<Entity, Or<(With<Player>, With<Projectile>)>>) {
+    for entity in entities.iter() {
+        cmd.entity(entity).despawn();
+    }
+    cmd.remove_resource::<Netplay>();
+}
+
+/// One peer of the loopback test, with its own world and scripted input
+struct HeadlessInstance {
+    world: World,
+    simulation: Simulation,
+    session: RollbackSession,
+    rng: StdRng,
+    input: u8,
+    /// Confirmed hits and deaths passed on by the simulation
+    hits: usize,
+    deaths: usize,
+}
+
+impl HeadlessInstance {
+    fn new(local: usize, socket: UdpSocket, peer: SocketAddr) -> io::Result<Self> {
+        let mut world = World::new();
+        let prefabs = Prefabs::default();
+        for index in 0..PLAYERS {
+            world.spawn().insert_bundle(net_player_bundle(index, &prefabs)).insert(NetPlayer(index));
+        }
+        world.insert_resource(prefabs);
+        world.insert_resource(DespawnQueue::default());
+        world.insert_resource(Events::<ProjectileHitEvent>::default());
+        world.insert_resource(Events::<DeathEvent>::default());
+
+        Ok(Self {
+            world,
+            simulation: Default::default(),
+            session: RollbackSession::new(local, socket, peer)?,
+            rng: StdRng::seed_from_u64(local as u64),
+            input: 0,
+            hits: 0,
+            deaths: 0,
+        })
+    }
+
+    /// Simulates the next frame, or once `frames` have been simulated only applies corrections
+    fn tick(&mut self, frames: usize) -> io::Result<()> {
+        self.session.receive()?;
+        if self.rng.gen_bool(LOOPBACK_INPUT_CHANGE) {
+            self.input = self.rng.gen::<u8>() & PlayerInput::ALL;
+        }
+        let input = if self.session.frame() < frames { Some(self.input) } else { None };
+        if let Some(advance) = self.session.advance(input) {
+            self.simulation.run(&mut self.world, &mut self.session, advance);
+        }
+        self.hits += self.world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().drain().count();
+        self.deaths += self.world.get_resource_mut::<Events<DeathEvent>>().unwrap().drain().count();
+        self.session.send()
+    }
+}
+
+/// Outcome of a [`loopback_test`] where the peers stayed in sync
+pub struct LoopbackReport {
+    pub frames: usize,
+    pub rollbacks: [u32; PLAYERS],
+    /// Confirmed hits and deaths, which both peers agree on
+    pub hits: usize,
+    pub deaths: usize,
+}
+
+impl fmt::Display for LoopbackReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames in sync, {} and {} rollbacks, {} hits and {} deaths",
+            self.frames, self.rollbacks[0], self.rollbacks[1], self.hits, self.deaths)
+    }
+}
+
+/// Runs two headless peers in this process, talking over local sockets, for `frames` frames with
+/// random input. Fails if their states ever differ, or they pass on different hits or deaths.
+pub fn loopback_test(frames: usize) -> Result<LoopbackReport, String> {
+    let sockets = (UdpSocket::bind(LOOPBACK_ADDRESS), UdpSocket::bind(LOOPBACK_ADDRESS));
+    let (socket_a, socket_b) = match sockets {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(error), _) | (_, Err(error)) => return Err(format!("Failed to bind loopback sockets: {}", error)),
+    };
+    let addresses = (socket_a.local_addr(), socket_b.local_addr());
+    let (address_a, address_b) = match addresses {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(error), _) | (_, Err(error)) => return Err(error.to_string()),
+    };
+    let mut instances = [
+        HeadlessInstance::new(0, socket_a, address_b).map_err(|error| error.to_string())?,
+        HeadlessInstance::new(1, socket_b, address_a).map_err(|error| error.to_string())?,
+    ];
+
+    let mut rng = thread_rng();
+    let started = Instant::now();
+    while instances.iter().any(|instance| instance.session.confirmed() < frames) {
+        if started.elapsed() > LOOPBACK_TIMEOUT {
+            return Err(format!("Timed out with {} and {} frames confirmed", instances[0].session.confirmed(), instances[1].session.confirmed()));
+        }
+        for instance in instances.iter_mut() {
+            if rng.gen_bool(LOOPBACK_TICK_CHANCE) {
+                instance.tick(frames).map_err(|error| error.to_string())?;
+            }
+        }
+    }
+
+    let (a, b) = (&instances[0].simulation, &instances[1].simulation);
+    if let Some(frame) = (0..frames).find(|&frame| a.checksums[frame] != b.checksums[frame]) {
+        return Err(format!("Desynced at frame {}: {:016x} != {:016x}", frame, a.checksums[frame], b.checksums[frame]));
+    }
+    let (a, b) = (&instances[0], &instances[1]);
+    if (a.hits, a.deaths) != (b.hits, b.deaths) {
+        return Err(format!("Peers saw {} and {} hits, {} and {} deaths", a.hits, b.hits, a.deaths, b.deaths));
+    }
+    Ok(LoopbackReport { frames, rollbacks: [a.simulation.rollbacks, b.simulation.rollbacks], hits: a.hits, deaths: a.deaths })
+}
+
+/// `netplay <player> <port> <peer>` starts an online game, `netplay loopback [frames]` runs the sync test
+fn netplay_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    if args.get(0) == Some(&"loopback") {
+        let frames: usize = if args.len() > 1 { parse(args, 1, "frames")? } else { 600 };
+        return loopback_test(frames).map(|report| report.to_string())
+    }
+
+    if world.get_resource::<State<GameState>>().map_or(false, |state| *state.current() == GameState::Online) {
+        return Err(String::from("Already playing online"))
+    }
+    let local: usize = parse(args, 0, "player")?;
+    if local >= PLAYERS { return Err(format!("Player must be below {}", PLAYERS)) }
+    let port: u16 = parse(args, 1, "port")?;
+    let peer: SocketAddr = parse(args, 2, "peer address")?;
+    let session = RollbackSession::bind(local, port, peer).map_err(|error| format!("Failed to bind port {}: {}", port, error))?;
+
+    world.insert_resource(Netplay::new(session));
+    world.get_resource_mut::<State<GameState>>().ok_or("No game state")?
+        .replace(GameState::Online)
+        .map_err(|error| format!("{:?}", error))?;
+    Ok(format!("Playing online as player {} with {}", local, peer))
+}
+
+pub struct NetplayPlugin;
+
+impl Plugin for NetplayPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_console_command("netplay <player|loopback> [port] [peer]", "Play online, or test rollback sync over loopback", netplay_command)
+            .add_system_set(SystemSet::on_enter(GameState::Online)
+                .with_system(netplay_spawn_sys))
+            .add_system_set(SystemSet::on_update(GameState::Online)
+                .with_system(netplay_sys.exclusive_system()))
+            .add_system_set(SystemSet::on_exit(GameState::Online)
+                .with_system(netplay_cleanup_sys));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_stays_in_sync() {
+        let report = match loopback_test(600) {
+            Ok(report) => report,
+            Err(error) => panic!("{}", error),
+        };
+        assert!(report.hits > 0, "{}", report);
+    }
+}
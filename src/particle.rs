@@ -0,0 +1,190 @@
+use bevy::prelude::*;
+use rand::prelude::*;
+use crate::boss::BossDefeatedEvent;
+use crate::common::{rotate, DeathEvent, GameTime};
+use crate::manager::GameState;
+use crate::projectile::ProjectileHitEvent;
+
+/// Particles are drawn just in front of everything else
+const PARTICLE_Z: f32 = 10.;
+
+/// Particle effect presets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Explosion,
+    BossExplosion,
+    Sparks,
+    Thruster,
+}
+
+/// How the particles of an [`Effect`] are emitted and change over their life
+pub struct EffectPreset {
+    /// Particles per burst, or per second for an emitter
+    pub count: u32,
+    pub lifetime: f32,
+    pub speed: (f32, f32),
+    /// Angle in radians either side of the emit direction
+    pub spread: f32,
+    pub start_color: Color,
+    pub end_color: Color,
+    pub start_size: f32,
+    pub end_size: f32,
+}
+
+impl Effect {
+    pub fn preset(&self) -> EffectPreset {
+        match self {
+            Effect::Explosion => EffectPreset {
+                count: 24, lifetime: 0.6, speed: (40., 180.), spread: std::f32::consts::PI,
+                start_color: Color::rgb(1.0, 0.9, 0.4), end_color: Color::rgba(0.8, 0.1, 0.0, 0.0),
+                start_size: 8., end_size: 2.,
+            },
+            Effect::BossExplosion => EffectPreset {
+                count: 120, lifetime: 1.2, speed: (60., 320.), spread: std::f32::consts::PI,
+                start_color: Color::rgb(1.0, 0.95, 0.6), end_color: Color::rgba(0.7, 0.1, 0.0, 0.0),
+                start_size: 14., end_size: 3.,
+            },
+            Effect::Sparks => EffectPreset {
+                count: 6, lifetime: 0.25, speed: (80., 220.), spread: std::f32::consts::PI,
+                start_color: Color::WHITE, end_color: Color::rgba(1.0, 0.8, 0.2, 0.0),
+                start_size: 3., end_size: 1.,
+            },
+            Effect::Thruster => EffectPreset {
+                count: 60, lifetime: 0.35, speed: (60., 120.), spread: 0.25,
+                start_color: Color::rgb(0.4, 0.7, 1.0), end_color: Color::rgba(0.1, 0.2, 1.0, 0.0),
+                start_size: 6., end_size: 1.,
+            },
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Particle {
+    pub velocity: Vec2,
+    pub age: f32,
+    pub lifetime: f32,
+    pub start_color: Color,
+    pub end_color: Color,
+    pub start_size: f32,
+    pub end_size: f32,
+}
+
+/// Continuously emits an [`Effect`] from the entity's position
+#[derive(Component, Clone)]
+pub struct ParticleEmitter {
+    pub effect: Effect,
+    /// Direction particles are emitted in
+    pub direction: Vec2,
+    /// Offset from the entity's position
+    pub offset: Vec2,
+    pending: f32,
+}
+
+impl ParticleEmitter {
+    pub fn new(effect: Effect, direction: Vec2, offset: Vec2) -> Self {
+        Self { effect, direction, offset, pending: 0. }
+    }
+}
+
+fn spawn_particle(cmd: &mut Commands, preset: &EffectPreset, position: Vec3, direction: Vec2, rng: &mut impl Rng) {
+    let angle = rng.gen_range(-preset.spread..=preset.spread);
+    let speed = rng.gen_range(preset.speed.0..=preset.speed.1);
+
+    cmd.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color: preset.start_color,
+            custom_size: Some(Vec2::splat(preset.start_size)),
+            ..Default::default()
+        },
+        transform: Transform::from_xyz(position.x, position.y, PARTICLE_Z),
+        ..Default::default()
+    }).insert(Particle {
+        velocity: rotate(direction, angle) * speed,
+        age: 0.,
+        lifetime: preset.lifetime,
+        start_color: preset.start_color,
+        end_color: preset.end_color,
+        start_size: preset.start_size,
+        end_size: preset.end_size,
+    });
+}
+
+/// Spawns a single burst of `effect` particles at `position`
+pub fn burst(cmd: &mut Commands, effect: Effect, position: Vec3, direction: Vec2) {
+    let preset = effect.preset();
+    let mut rng = rand::thread_rng();
+    for _ in 0..preset.count {
+        spawn_particle(cmd, &preset, position, direction, &mut rng);
+    }
+}
+
+fn lerp_color(from: Color, to: Color, t: f32) -> Color {
+    let (from, to) = (Vec4::from(from), Vec4::from(to));
+    Color::from(from + (to - from) * t)
+}
+
+/// Moves particles and fades their color and size, removing them at the end of their lifetime
+fn particle_update_sys(mut cmd: Commands, mut particles: Query<(Entity, &mut Particle, &mut Transform, &mut Sprite)>, time: Res<GameTime>) {
+    for (entity, mut particle, mut transform, mut sprite) in particles.iter_mut() {
+        particle.age += time.delta_seconds();
+        if particle.age >= particle.lifetime {
+            cmd.entity(entity).despawn();
+            continue
+        }
+
+        let t = particle.age / particle.lifetime;
+        transform.translation += (particle.velocity * time.delta_seconds()).extend(0.);
+        sprite.color = lerp_color(particle.start_color, particle.end_color, t);
+        sprite.custom_size = Some(Vec2::splat(particle.start_size + (particle.end_size - particle.start_size) * t));
+    }
+}
+
+fn particle_emitter_sys(mut cmd: Commands, mut emitters: Query<(&mut ParticleEmitter, &GlobalTransform)>, time: Res<GameTime>) {
+    let mut rng = rand::thread_rng();
+    for (mut emitter, transform) in emitters.iter_mut() {
+        let preset = emitter.effect.preset();
+        emitter.pending += preset.count as f32 * time.delta_seconds();
+
+        let position = transform.translation + emitter.offset.extend(0.);
+        while emitter.pending >= 1. {
+            emitter.pending -= 1.;
+            spawn_particle(&mut cmd, &preset, position, emitter.direction, &mut rng);
+        }
+    }
+}
+
+/// Explosions on deaths and sparks where projectiles hit
+fn particle_event_sys(
+    mut cmd: Commands,
+    mut death_events: EventReader<DeathEvent>,
+    mut boss_defeated_events: EventReader<BossDefeatedEvent>,
+    mut hit_events: EventReader<ProjectileHitEvent>) {
+    for death in death_events.iter() {
+        burst(&mut cmd, Effect::Explosion, death.position, Vec2::Y);
+    }
+    for defeated in boss_defeated_events.iter() {
+        burst(&mut cmd, Effect::BossExplosion, defeated.position, Vec2::Y);
+    }
+    for hit in hit_events.iter() {
+        burst(&mut cmd, Effect::Sparks, hit.position, Vec2::Y);
+    }
+}
+
+fn particle_cleanup_sys(mut cmd: Commands, particles: Query<Entity, With<Particle>>) {
+    for entity in particles.iter() {
+        cmd.entity(entity).despawn();
+    }
+}
+
+pub struct ParticlePlugin;
+
+impl Plugin for ParticlePlugin {
+    fn build(&self, app: &mut App) {
+        app .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(particle_update_sys)
+                .with_system(particle_emitter_sys)
+                .with_system(particle_event_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(particle_cleanup_sys));
+    }
+}
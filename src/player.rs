@@ -1,30 +1,105 @@
 use bevy::prelude::*;
-use crate::common::{Health, Shooter, Direction};
+use crate::common::{Health, Lives, Shooter, Velocity, GameTime, Team};
 use crate::{CollisionBox, CollisionEvent, Enemy};
-use crate::projectile::{Projectile, ProjectileBundle};
+use crate::projectile::{Owner, Projectile, ProjectileBundle, ProjectileHitEvent};
+use crate::manager::GameState;
+use crate::particle::{Effect, ParticleEmitter};
+use crate::prefab::{Prefab, Prefabs};
+use crate::settings::{KeyBindings, Settings};
+use crate::debug::TimedSystemExt;
 
 const MOVE_SPEED: f32 = 600.;
 const PLAYER_VERT_OFFSET: f32 = 200.;
+const PLAYER_LIVES: u32 = 3;
+const PLAYER_DAMAGE: i32 = 30;
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Player {
-    pub score: i32
+    pub score: i32,
+    /// Which player this is, starting at 0. Projectiles record it so points go to the right
+    /// player even if they are destroyed while the projectile is in flight.
+    pub index: usize,
+    /// Currency collected this run, spent in the [`Shop`](crate::shop::Shop)
+    pub credits: u32,
+    /// Distance per second moved without boosting
+    pub move_speed: f32,
 }
 
 impl Default for Player {
     fn default() -> Self {
         Self {
             score: 0,
+            index: 0,
+            credits: 0,
+            move_speed: MOVE_SPEED,
         }
     }
 }
 
+/// Buttons a player is holding this frame and last frame, as bit flags. Filled from the keyboard
+/// in local play, or from the network when playing online.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerInput {
+    pub current: u8,
+    pub previous: u8,
+}
+
+impl PlayerInput {
+    pub const LEFT: u8   = 1 << 0;
+    pub const RIGHT: u8  = 1 << 1;
+    pub const BOOST: u8  = 1 << 2;
+    pub const SLOW: u8   = 1 << 3;
+    pub const FIRE: u8   = 1 << 4;
+    pub const RELOAD: u8 = 1 << 5;
+    pub const ALL: u8    = (1 << 6) - 1;
+
+    /// The buttons held on the keyboard
+    pub fn from_keyboard(keyboard_input: &Input<KeyCode>, bindings: &KeyBindings) -> u8 {
+        [
+            (bindings.move_left, Self::LEFT),
+            (bindings.move_right, Self::RIGHT),
+            (bindings.boost, Self::BOOST),
+            (bindings.slow, Self::SLOW),
+            (bindings.fire, Self::FIRE),
+            (bindings.reload, Self::RELOAD),
+        ].iter()
+            .filter(|(key, _)| keyboard_input.pressed(*key))
+            .fold(0, |buttons, (_, button)| buttons | button)
+    }
+
+    /// Moves on to the next frame with `buttons` held
+    pub fn update(&mut self, buttons: u8) {
+        self.previous = self.current;
+        self.current = buttons;
+    }
+
+    pub fn pressed(&self, button: u8) -> bool {
+        self.current & button != 0
+    }
+
+    pub fn just_pressed(&self, button: u8) -> bool {
+        self.pressed(button) && self.previous & button == 0
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+struct PlayerInputLabel;
+
+/// Players with this component take no damage
+#[derive(Component, Clone)]
+pub struct GodMode;
+
 #[derive(Bundle)]
 pub struct PlayerBundle {
     pub player: Player,
+    pub team: Team,
     pub health: Health,
     pub shooter: Shooter,
     pub collision_box: CollisionBox,
+    pub velocity: Velocity,
+    pub input: PlayerInput,
+    pub lives: Lives,
+    pub thruster: ParticleEmitter,
 
     #[bundle]
     pub sprite: SpriteBundle,
@@ -34,8 +109,10 @@ impl Default for PlayerBundle {
     fn default() -> Self {
         Self {
             player: Player {
-                score: 10
+                score: 10,
+                ..Default::default()
             },
+            team: Team::Player,
             health: Default::default(),
             sprite: SpriteBundle {
                 sprite: Sprite {
@@ -46,53 +123,88 @@ impl Default for PlayerBundle {
                 transform: Transform::from_xyz(0.0,-PLAYER_VERT_OFFSET,0.0),
                 ..Default::default()
             },
-            shooter: Default::default(),
+            shooter: Shooter { damage: PLAYER_DAMAGE, ..Default::default() },
             collision_box: CollisionBox { size: Vec2::new(50.0, 50.0)},
+            velocity: Default::default(),
+            input: Default::default(),
+            lives: Lives { remaining: PLAYER_LIVES },
+            thruster: ParticleEmitter::new(Effect::Thruster, -Vec2::Y, Vec2::new(0.0, -25.0)),
         }
     }
 }
 
-fn player_move_sys(mut player_transforms: Query<&mut Transform, With<Player>>, keyboard_input: Res<Input<KeyCode>>, time: Res<Time>) {
-    for mut transform in player_transforms.iter_mut() {
+impl PlayerBundle {
+    /// Replaces component values with any given by `prefab`
+    pub fn with_prefab(mut self, prefab: &Prefab) -> Self {
+        prefab.apply_sprite(&mut self.sprite.sprite);
+        prefab.apply_health(&mut self.health);
+        prefab.apply_lives(&mut self.lives);
+        prefab.apply_collision_box(&mut self.collision_box);
+        prefab.apply_shooter(&mut self.shooter);
+        self
+    }
+}
+
+/// Reads the keyboard into every player's [`PlayerInput`]
+fn player_input_sys(mut inputs: Query<&mut PlayerInput, With<Player>>, keyboard_input: Res<Input<KeyCode>>, settings: Res<Settings>) {
+    let buttons = PlayerInput::from_keyboard(&keyboard_input, &settings.bindings);
+    for mut input in inputs.iter_mut() {
+        input.update(buttons);
+    }
+}
+
+pub(crate) fn player_move_sys(mut player_transforms: Query<(&Player, &mut Transform, &mut Velocity, &PlayerInput)>, time: Res<GameTime>) {
+    for (player, mut transform, mut velocity, input) in player_transforms.iter_mut() {
+        velocity.0 = Vec2::ZERO;
 
         let boost =
-            if keyboard_input.pressed(KeyCode::LControl) && keyboard_input.pressed(KeyCode::LShift) {0.5}
-            else if keyboard_input.pressed(KeyCode::LShift) { 2.0 }
+            if input.pressed(PlayerInput::SLOW) && input.pressed(PlayerInput::BOOST) {0.5}
+            else if input.pressed(PlayerInput::BOOST) { 2.0 }
             else { 1.0 };
 
-        if keyboard_input.pressed(KeyCode::A) {
-            transform.translation.x = transform.translation.x - MOVE_SPEED * boost * time.delta_seconds();
+        if input.pressed(PlayerInput::LEFT) {
+            velocity.0.x = velocity.0.x - player.move_speed * boost;
         }
 
-        if keyboard_input.pressed(KeyCode::D) {
-            transform.translation.x = transform.translation.x + MOVE_SPEED * boost * time.delta_seconds();
+        if input.pressed(PlayerInput::RIGHT) {
+            velocity.0.x = velocity.0.x + player.move_speed * boost;
         }
+
+        transform.translation.x = transform.translation.x + velocity.0.x * time.delta_seconds();
     }
 }
 
-fn player_shoot_sys(mut player_shooter: Query<(Entity, &Transform), With<Player>>, keyboard_input: Res<Input<KeyCode>>, mut cmd: Commands) {
-    for (entity, transform) in player_shooter.iter_mut() {
-        if keyboard_input.just_pressed(KeyCode::Space) {
+pub(crate) fn player_shoot_sys(
+    mut player_shooter: Query<(Entity, &Player, &mut Shooter, &Transform, &PlayerInput)>,
+    prefabs: Res<Prefabs>,
+    mut cmd: Commands) {
+    for (entity, player, mut shooter, transform, input) in player_shooter.iter_mut() {
+        if input.just_pressed(PlayerInput::RELOAD) {
+            shooter.start_reload();
+        }
+
+        if input.just_pressed(PlayerInput::FIRE) && !shooter.reloading && shooter.ammo_count > 0 {
+            shooter.ammo_count -= 1;
+            if shooter.ammo_count == 0 {
+                shooter.start_reload();
+            }
+
             info!("Player entity={} shooting", &entity.id());
-            cmd.spawn_bundle(ProjectileBundle {
+            let mut bundle = ProjectileBundle {
                 projectile: Projectile {
-                    direction: Direction::UP,
-                    damage: 30,
-                    speed_multiplier: Default::default(),
+                    // Players fire the way they face, which is up unless they are turned around
+                    direction: (transform.rotation * Vec3::Y).truncate(),
+                    damage: shooter.damage,
                     origin: Some(entity.clone()),
-                    whitelist: Vec::new(),
-                },
-                sprite: SpriteBundle {
-                    sprite: Sprite {
-                        color: Color::GREEN,
-                        custom_size: Some(Vec2::new(15.0, 15.0)),
-                        ..Default::default()
-                    },
-                    transform: Transform::from_xyz(transform.translation.x, transform.translation.y, transform.translation.z),
+                    owner: Owner::player(player.index),
+                    modifiers: shooter.modifiers.clone(),
                     ..Default::default()
                 },
+                team: Team::Player,
                 ..Default::default()
-            });
+            }.with_prefab(&prefabs.projectile(&shooter));
+            bundle.sprite.transform = Transform::from_xyz(transform.translation.x, transform.translation.y, transform.translation.z);
+            cmd.spawn_bundle(bundle);
         };
 
     }
@@ -104,9 +216,9 @@ fn player_score_sys(mut hit_events: EventReader<CollisionEvent>, enemies: Query<
             Ok(_) => {
                 match projectiles.get_component::<Projectile>(a) { // hit by projectile
                     Ok(projectile) => {
-                        match players.get_mut(projectile.origin.unwrap()) { // projectiles origin entity
-                            Ok(mut player) => { player.score = player.score + 10 }
-                            Err(_) => { warn!("Could not apply player score: No player as origin for projectile")}
+                        match projectile.owner.player.and_then(|index| players.iter_mut().find(|player| player.index == index)) {
+                            Some(mut player) => { player.score = player.score + 10 }
+                            None => { debug!("No player to score for projectile") }
                         }
                     }
                     Err(_) => {}
@@ -118,41 +230,39 @@ fn player_score_sys(mut hit_events: EventReader<CollisionEvent>, enemies: Query<
 }
 
 /// Reduces players health when hit by [`Projectile`].
-fn player_hit_sys(
-    mut hit_events: EventReader<CollisionEvent>,
-    mut player_healths: Query<&mut Health, With<Player>>,
-    projectiles: Query<&Projectile>) {
-    for &CollisionEvent{a, b, ..} in hit_events.iter(){
-        match player_healths.get_mut(b) { // Check is player w/ health
+pub(crate) fn player_hit_sys(
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    mut player_healths: Query<&mut Health, (With<Player>, Without<GodMode>)>) {
+    for &ProjectileHitEvent{other, damage, ..} in hit_events.iter(){
+        match player_healths.get_mut(other) { // Check is player w/ health
             Ok(mut player_health) => {
-                match projectiles.get(a) {
-                    Ok(projectile) => {
-                        player_health.health = player_health.health - projectile.damage;
-                    }
-                    Err(_) => {}
-                }
+                player_health.health = player_health.health - damage;
             }
             Err(_) => {}
         }
     }
 }
 
-fn player_startup_sys(mut cmd: Commands) {
-    new_player(&mut cmd);
+fn player_startup_sys(mut cmd: Commands, prefabs: Res<Prefabs>) {
+    spawn_player(&mut cmd, &prefabs);
 }
 
-pub fn new_player(cmd: &mut Commands) {
-    cmd.spawn_bundle(PlayerBundle::default());
+/// Spawns a player from the `player` prefab
+pub fn spawn_player(cmd: &mut Commands, prefabs: &Prefabs) {
+    cmd.spawn_bundle(PlayerBundle::default().with_prefab(&prefabs.get("player")));
 }
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app .add_startup_system(player_startup_sys)
-            .add_system(player_move_sys)
-            .add_system(player_shoot_sys)
-            //.add_system(player_score_sys)
-            .add_system(player_hit_sys);
+        app .add_system_set(SystemSet::on_enter(GameState::Playing)
+                .with_system(player_startup_sys))
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(player_input_sys.timed().label(PlayerInputLabel))
+                .with_system(player_move_sys.timed().after(PlayerInputLabel))
+                .with_system(player_shoot_sys.timed().after(PlayerInputLabel))
+                //.with_system(player_score_sys)
+                .with_system(player_hit_sys.timed()));
     }
 }
\ No newline at end of file
@@ -0,0 +1,302 @@
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::{BoxedFuture, HashMap};
+use serde::Deserialize;
+use crate::common::{CollisionBox, Health, Lives, Shooter};
+use crate::enemy::FirePattern;
+
+const LIBRARY_PATH: &str = "prefabs/entities.prefabs.ron";
+
+/// Component values for one kind of entity. Every section is optional. A missing section keeps
+/// the value from `base`, or else the spawning code's built-in default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Prefab {
+    /// Prefab this one extends
+    pub base: Option<String>,
+    pub sprite: Option<SpriteSpec>,
+    pub health: Option<i32>,
+    pub lives: Option<u32>,
+    pub collision_box: Option<(f32, f32)>,
+    pub shooter: Option<ShooterSpec>,
+    pub weapon: Option<WeaponSpec>,
+    pub projectile: Option<ProjectileSpec>,
+    pub ai: Option<AiSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpriteSpec {
+    pub size: (f32, f32),
+    /// Red, green and blue from 0 to 1
+    pub color: (f32, f32, f32),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShooterSpec {
+    pub magazine_size: i32,
+    /// Chance of firing each frame. Only used by enemies.
+    pub fire_rate: f32,
+    pub reload_time: f32,
+}
+
+/// What a shooter fires
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeaponSpec {
+    /// Name of the projectile prefab spawned for each shot
+    pub projectile: String,
+    pub damage: i32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectileSpec {
+    pub speed_multiplier: f32,
+    /// Turn rate in radians per second of a projectile which steers towards its target
+    #[serde(default)]
+    pub homing: Option<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AiSpec {
+    pub move_speed: f32,
+    /// Directions fired in each volley. Defaults to straight down.
+    #[serde(default = "default_fire_pattern")]
+    pub fire_pattern: FirePattern,
+}
+
+fn default_fire_pattern() -> FirePattern {
+    FirePattern::Straight
+}
+
+impl Prefab {
+    /// Fills every section missing from this prefab from `base`
+    fn extend(mut self, base: &Prefab) -> Prefab {
+        self.sprite = self.sprite.or_else(|| base.sprite.clone());
+        self.health = self.health.or(base.health);
+        self.lives = self.lives.or(base.lives);
+        self.collision_box = self.collision_box.or(base.collision_box);
+        self.shooter = self.shooter.or_else(|| base.shooter.clone());
+        self.weapon = self.weapon.or_else(|| base.weapon.clone());
+        self.projectile = self.projectile.or_else(|| base.projectile.clone());
+        self.ai = self.ai.or_else(|| base.ai.clone());
+        self
+    }
+
+    pub fn apply_sprite(&self, sprite: &mut Sprite) {
+        if let Some(spec) = &self.sprite {
+            let (r, g, b) = spec.color;
+            sprite.color = Color::rgb(r, g, b);
+            sprite.custom_size = Some(Vec2::new(spec.size.0, spec.size.1));
+        }
+    }
+
+    pub fn apply_health(&self, health: &mut Health) {
+        if let Some(value) = self.health {
+            *health = Health::new(value);
+        }
+    }
+
+    pub fn apply_lives(&self, lives: &mut Lives) {
+        if let Some(remaining) = self.lives {
+            lives.remaining = remaining;
+        }
+    }
+
+    pub fn apply_collision_box(&self, collision_box: &mut CollisionBox) {
+        if let Some((width, height)) = self.collision_box {
+            collision_box.size = Vec2::new(width, height);
+        }
+    }
+
+    pub fn apply_shooter(&self, shooter: &mut Shooter) {
+        if let Some(spec) = &self.shooter {
+            shooter.ammo_count = spec.magazine_size;
+            shooter.magazine_size = spec.magazine_size;
+            shooter.fire_rate = spec.fire_rate;
+            shooter.reload_timer = Timer::from_seconds(spec.reload_time, false);
+        }
+        self.apply_weapon(shooter);
+    }
+
+    /// Applies only the `weapon` section, leaving the magazine and reload as they are
+    pub fn apply_weapon(&self, shooter: &mut Shooter) {
+        if let Some(weapon) = &self.weapon {
+            shooter.damage = weapon.damage;
+            shooter.projectile = Some(weapon.projectile.clone());
+        }
+    }
+
+    /// Checks values are in range, returning a message for each problem
+    fn validate(&self, name: &str, library: &HashMap<String, Prefab>) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, message: &str| if !ok { errors.push(format!("{}: {}", name, message)) };
+
+        if let Some(sprite) = &self.sprite {
+            check(sprite.size.0 > 0. && sprite.size.1 > 0., "sprite size must be positive");
+            let (r, g, b) = sprite.color;
+            check([r, g, b].iter().all(|c| (0.0..=1.0).contains(c)), "sprite color components must be from 0 to 1");
+        }
+        if let Some(health) = self.health {
+            check(health > 0, "health must be positive");
+        }
+        if let Some((width, height)) = self.collision_box {
+            check(width > 0. && height > 0., "collision box size must be positive");
+        }
+        if let Some(shooter) = &self.shooter {
+            check(shooter.magazine_size > 0, "magazine size must be positive");
+            check((0.0..=1.0).contains(&shooter.fire_rate), "fire rate must be from 0 to 1");
+            check(shooter.reload_time >= 0., "reload time can't be negative");
+        }
+        if let Some(weapon) = &self.weapon {
+            check(weapon.damage >= 0, "weapon damage can't be negative");
+            check(library.contains_key(&weapon.projectile), &format!("weapon projectile '{}' does not exist", weapon.projectile));
+        }
+        if let Some(projectile) = &self.projectile {
+            check(projectile.speed_multiplier > 0., "projectile speed multiplier must be positive");
+            check(projectile.homing.map_or(true, |turn_rate| turn_rate > 0.), "homing turn rate must be positive");
+        }
+        if let Some(ai) = &self.ai {
+            check(ai.move_speed >= 0., "move speed can't be negative");
+            match ai.fire_pattern {
+                FirePattern::Radial { count } | FirePattern::Spiral { count, .. } | FirePattern::Fan { count, .. } => check(count > 0, "fire pattern count must be positive"),
+                FirePattern::Straight | FirePattern::Aimed { .. } => {}
+            }
+        }
+        errors
+    }
+}
+
+/// Every prefab, loaded from a `.prefabs.ron` file. Prefabs are stored with their bases already applied.
+#[derive(Debug, TypeUuid)]
+#[uuid = "3e9b2f61-7c4d-4a8e-b05f-6d1c9a2e7f48"]
+pub struct PrefabLibrary {
+    pub prefabs: HashMap<String, Prefab>,
+}
+
+impl PrefabLibrary {
+    /// Resolves `base` references and validates every prefab
+    fn from_definitions(definitions: HashMap<String, Prefab>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut prefabs = HashMap::default();
+
+        for (name, prefab) in definitions.iter() {
+            let mut resolved = prefab.clone();
+            let mut chain = vec![name.clone()];
+            while let Some(base) = resolved.base.take() {
+                match definitions.get(&base) {
+                    _ if chain.contains(&base) => {
+                        errors.push(format!("{}: base '{}' is circular", name, base));
+                        break
+                    }
+                    Some(base_prefab) => {
+                        resolved = resolved.extend(base_prefab);
+                        resolved.base = base_prefab.base.clone();
+                        chain.push(base);
+                    }
+                    None => {
+                        errors.push(format!("{}: base '{}' does not exist", name, base));
+                        break
+                    }
+                }
+            }
+            prefabs.insert(name.clone(), resolved);
+        }
+
+        for (name, prefab) in prefabs.iter() {
+            errors.extend(prefab.validate(name, &prefabs));
+        }
+
+        if errors.is_empty() {
+            Ok(Self { prefabs })
+        } else {
+            errors.sort();
+            Err(errors)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PrefabLoader;
+
+impl AssetLoader for PrefabLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let definitions: HashMap<String, Prefab> = ron::de::from_bytes(bytes)?;
+            match PrefabLibrary::from_definitions(definitions) {
+                Ok(library) => {
+                    load_context.set_default_asset(LoadedAsset::new(library));
+                    Ok(())
+                }
+                Err(errors) => Err(anyhow::anyhow!("Invalid prefabs in {}:\n  {}", load_context.path().display(), errors.join("\n  "))),
+            }
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["prefabs.ron"]
+    }
+}
+
+/// The prefabs spawn code instantiates from. Updated whenever the library is loaded or
+/// reloaded, so edits apply to everything spawned afterwards. An invalid edit is rejected at
+/// load time and the previous prefabs are kept.
+#[derive(Default)]
+pub struct Prefabs {
+    handle: Handle<PrefabLibrary>,
+    pub(crate) prefabs: HashMap<String, Prefab>,
+}
+
+impl Prefabs {
+    /// The named prefab, or an empty prefab which leaves every built-in default in place
+    pub fn get(&self, name: &str) -> Prefab {
+        match self.prefabs.get(name) {
+            Some(prefab) => prefab.clone(),
+            None => {
+                if !self.prefabs.is_empty() { warn!("No prefab named '{}'", name) }
+                Prefab::default()
+            }
+        }
+    }
+
+    /// The prefab of the projectiles fired by `shooter`
+    pub fn projectile(&self, shooter: &Shooter) -> Prefab {
+        shooter.projectile.as_deref().map_or_else(Prefab::default, |name| self.get(name))
+    }
+}
+
+fn prefab_load_sys(mut prefabs: ResMut<Prefabs>, asset_server: Res<AssetServer>) {
+    prefabs.handle = asset_server.load(LIBRARY_PATH);
+}
+
+fn prefab_reload_sys(
+    mut prefabs: ResMut<Prefabs>,
+    mut library_events: EventReader<AssetEvent<PrefabLibrary>>,
+    libraries: Res<Assets<PrefabLibrary>>) {
+    for event in library_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } if *handle == prefabs.handle => {}
+            _ => continue,
+        }
+        if let Some(library) = libraries.get(&prefabs.handle) {
+            prefabs.prefabs = library.prefabs.clone();
+            info!("Loaded {} prefabs", prefabs.prefabs.len());
+        }
+    }
+}
+
+pub struct PrefabPlugin;
+
+impl Plugin for PrefabPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_asset::<PrefabLibrary>()
+            .init_asset_loader::<PrefabLoader>()
+            .init_resource::<Prefabs>()
+            .add_startup_system(prefab_load_sys)
+            .add_system(prefab_reload_sys);
+    }
+}
@@ -1,54 +1,205 @@
+use std::cmp::Ordering;
 use bevy::prelude::*;
 use crate::common::{Direction, *};
+use crate::manager::GameState;
+use crate::prefab::Prefab;
+use crate::debug::TimedSystemExt;
 
-#[derive(Component)]
+/// Distance per second travelled by a projectile with a `speed_multiplier` of 1
+const PROJECTILE_SPEED: f32 = 600.;
+
+#[derive(Component, Clone)]
 pub struct Projectile {
-    pub direction: Direction,
+    /// Unit vector the projectile travels along
+    pub direction: Vec2,
     pub damage: i32,
     pub speed_multiplier: f32,
+    /// Entity which fired the projectile. May have been removed since.
     pub origin: Option<Entity>,
-    pub whitelist: Vec<Entity>,//todo
+    /// Who fired the projectile, which stays valid after the shooter is removed
+    pub owner: Owner,
+    pub modifiers: ProjectileModifiers,
+    /// Entities already hit by this projectile. Used so piercing projectiles damage each target once.
+    pub hits: Vec<Entity>,
 }
 
 impl Default for Projectile {
     fn default() -> Self {
         Self {
-            direction: Direction::DOWN,
+            direction: Direction::DOWN.vec(),
             damage: 10,
             speed_multiplier: 1.0,
             origin: Option::None,
-            whitelist: Vec::default(),
+            owner: Default::default(),
+            modifiers: Default::default(),
+            hits: Vec::default(),
         }
     }
 }
 
+impl Projectile {
+    /// True if the projectile, fired by `team`, is allowed to hit `entity` on team `target`
+    pub fn can_hit(&self, team: Team, entity: Entity, target: Option<Team>, friendly_fire: FriendlyFire) -> bool {
+        !self.is_spent()
+            && self.origin != Some(entity)
+            && !self.hits.contains(&entity)
+            && friendly_fire.allows(team, target)
+    }
+
+    /// True once the projectile has hit more targets than it can pierce
+    pub fn is_spent(&self) -> bool {
+        self.hits.len() as u32 > self.modifiers.pierce
+    }
+
+    /// Distance per second travelled by this projectile
+    pub fn speed(&self) -> f32 {
+        PROJECTILE_SPEED * self.speed_multiplier
+    }
+
+    /// Bounces the projectile at `position` off any wall of an arena reaching `walls` from the
+    /// centre that it is moving into, using up a ricochet. Returns true if it bounced.
+    pub fn ricochet(&mut self, position: &mut Vec3, walls: Vec2) -> bool {
+        if self.modifiers.ricochet == 0 { return false }
+        let hit_horizontal_wall = (position.y >  walls.y && self.direction.y > 0.)
+                               || (position.y < -walls.y && self.direction.y < 0.);
+        let hit_vertical_wall   = (position.x >  walls.x && self.direction.x > 0.)
+                               || (position.x < -walls.x && self.direction.x < 0.);
+        if !hit_horizontal_wall && !hit_vertical_wall { return false }
+
+        if hit_horizontal_wall { self.direction.y = -self.direction.y }
+        if hit_vertical_wall   { self.direction.x = -self.direction.x }
+        self.modifiers.ricochet -= 1;
+        position.x = position.x.clamp(-walls.x, walls.x);
+        position.y = position.y.clamp(-walls.y, walls.y);
+        true
+    }
+}
+
+/// Who fired a [`Projectile`]. The side it fights for is its [`Team`] component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Owner {
+    /// [`Player::index`](crate::player::Player::index) of the player who fired it, if any
+    pub player: Option<usize>,
+}
+
+impl Owner {
+    pub fn player(index: usize) -> Self {
+        Self { player: Some(index) }
+    }
+
+    pub fn is_player(&self) -> bool {
+        self.player.is_some()
+    }
+}
+
+/// Optional behaviours applied to a [`Projectile`]. The default is a plain projectile which is
+/// removed on its first hit.
+#[derive(Clone, Default)]
+pub struct ProjectileModifiers {
+    /// Number of additional targets the projectile passes through before it is removed
+    pub pierce: u32,
+    /// Number of times the projectile bounces off the arena walls before it is removed
+    pub ricochet: u32,
+    /// Area damage dealt when the projectile is removed by a hit
+    pub explosion: Option<Explosion>,
+    /// Smaller projectiles spawned when the projectile is removed by a hit
+    pub fragments: Option<Fragments>,
+    /// Steers the projectile towards the nearest entity it can hit
+    pub homing: Option<Homing>,
+}
+
+#[derive(Clone)]
+pub struct Explosion {
+    pub radius: f32,
+    /// Damage at the centre of the explosion. Falls off linearly to zero at `radius`.
+    pub damage: i32,
+}
+
+impl Explosion {
+    pub fn damage_at(&self, distance: f32) -> i32 {
+        if distance >= self.radius { return 0 }
+        (self.damage as f32 * (1.0 - distance / self.radius)).round() as i32
+    }
+}
+
+#[derive(Clone)]
+pub struct Fragments {
+    /// Number of fragments, spread evenly around the point of impact
+    pub count: u32,
+    pub damage: i32,
+}
+
+impl Fragments {
+    /// Directions of the fragments of a projectile travelling along `direction`. None of them
+    /// continue straight on, so they don't all hit the target again.
+    pub fn directions(&self, direction: Vec2) -> impl Iterator<Item = Vec2> {
+        let count = self.count;
+        (0..count).map(move |i| rotate(direction, std::f32::consts::TAU * (i as f32 + 0.5) / count as f32))
+    }
+}
+
+#[derive(Clone)]
+pub struct Homing {
+    /// Maximum change of direction in radians per second
+    pub turn_rate: f32,
+}
+
 #[derive(Bundle)]
 pub struct ProjectileBundle {
     pub projectile: Projectile,
+    pub team: Team,
     pub collision_box: CollisionBox,
 
     #[bundle]
     pub sprite: SpriteBundle,
 }
 
-fn projectile_move_sys(mut projectile_transforms: Query<(&mut Projectile, &mut Transform)>) {
-    for ( projectile, mut transforms) in projectile_transforms.iter_mut() {
-        match &projectile.direction {
-            Direction::UP    => { transforms.translation.y = transforms.translation.y + 10.0 }
-            Direction::DOWN  => { transforms.translation.y = transforms.translation.y - 10.0 }
-            Direction::LEFT  => { transforms.translation.x = transforms.translation.x + 10.0 }
-            Direction::RIGHT => { transforms.translation.x = transforms.translation.x - 10.0 }
-        }
+pub(crate) fn projectile_move_sys(mut projectile_transforms: Query<(&Projectile, &mut Transform)>, time: Res<GameTime>) {
+    for (projectile, mut transforms) in projectile_transforms.iter_mut() {
+        let delta = projectile.direction * projectile.speed() * time.delta_seconds();
+        transforms.translation.x = transforms.translation.x + delta.x;
+        transforms.translation.y = transforms.translation.y + delta.y;
     }
+}
 
+/// Turns homing projectiles towards the nearest entity they are able to hit, limited by their turn rate.
+fn projectile_homing_sys(
+    mut projectiles: Query<(&mut Projectile, &Team, &Transform)>,
+    targets: Query<(Entity, &GlobalTransform, Option<&Team>), With<Health>>,
+    friendly_fire: Res<FriendlyFire>,
+    time: Res<GameTime>) {
+    for (mut projectile, &team, transform) in projectiles.iter_mut() {
+        let turn_rate = match &projectile.modifiers.homing {
+            Some(homing) => homing.turn_rate,
+            None => continue,
+        };
+
+        let position = transform.translation.truncate();
+        let target = targets.iter()
+            .filter(|(entity, _, target_team)| projectile.can_hit(team, *entity, target_team.copied(), *friendly_fire))
+            .map(|(_, target_transform, _)| target_transform.translation.truncate())
+            .min_by(|a, b| a.distance_squared(position).partial_cmp(&b.distance_squared(position)).unwrap_or(Ordering::Equal));
+
+        if let Some(target) = target {
+            let desired = (target - position).normalize_or_zero();
+            if desired == Vec2::ZERO { continue }
+            let angle = projectile.direction.angle_between(desired);
+            let max_turn = turn_rate * time.delta_seconds();
+            projectile.direction = rotate(projectile.direction, angle.clamp(-max_turn, max_turn));
+        }
+    }
 }
 
-/// Removes projectiles that move beyond bounds of game area
-fn projectile_remove_sys(mut cmd: Commands, mut projectile_entities: Query<(Entity, &Transform), With<Projectile>>, windows: Res<Windows>){
+/// Removes projectiles that move beyond bounds of game area, or bounces them off the edge of the
+/// window if they have ricochets left.
+fn projectile_remove_sys(mut despawn_queue: ResMut<DespawnQueue>, mut projectile_entities: Query<(Entity, &mut Projectile, &mut Transform)>, windows: Res<Windows>){
     let bounds_top =  windows.get_primary().unwrap().height();
     let bounds_right =  windows.get_primary().unwrap().width();
+    let (wall_top, wall_right) = (bounds_top / 2.0, bounds_right / 2.0);
+
+    for (entity, mut projectile, mut transform) in projectile_entities.iter_mut(){
+        if projectile.ricochet(&mut transform.translation, Vec2::new(wall_right, wall_top)) { continue }
 
-    for (entity, transform) in projectile_entities.iter_mut(){
         let mut remove = false;
         if transform.translation.x >  bounds_top   { remove = true; }
         if transform.translation.x < -bounds_top   { remove = true; }
@@ -57,59 +208,105 @@ fn projectile_remove_sys(mut cmd: Commands, mut projectile_entities: Query<(Enti
 
         if remove {
             debug!("Despawning projectile entity {} at {}", entity.id(), transform.translation);
-            cmd.entity(entity).despawn();
+            despawn_queue.despawn(entity);
         }
     }
 }
 
+/// Sent for every entity damaged by a projectile, including those caught in an explosion.
 pub struct ProjectileHitEvent {
     pub projectile: Entity,
     pub other: Entity,
+    pub origin: Option<Entity>,
+    pub owner: Owner,
+    pub damage: i32,
+    /// Where the hit landed
+    pub position: Vec3,
 }
 
-fn projectile_hit_sys_old(
+/// Turns collisions into [`ProjectileHitEvent`]s and removes projectiles once they are spent,
+/// triggering any explosion or fragments.
+pub(crate) fn projectile_hit_sys(
     mut cmd: Commands,
-    projectiles: Query<(Entity, &Projectile, &Transform)>,
-    mut health_transforms: Query<(Entity, &mut Health, &Transform)>,
-    mut hit_event_writer: EventWriter<ProjectileHitEvent>) {
-    for (entity, projectile, transform) in projectiles.iter() {
-        for (hit_entity, mut hit_health, hit_transform) in health_transforms.iter_mut() {
-
-            // This prevents a projectile hitting it's origin entity
-            match projectile.origin {
-                Some(origin) => if origin.eq(&hit_entity){ continue }
-                None => {}
-            }
+    mut despawn_queue: ResMut<DespawnQueue>,
+    mut hit_events: EventReader<CollisionEvent>,
+    mut projectiles: Query<(&mut Projectile, &Team, &Transform, &Sprite)>,
+    teams: Query<&Team>,
+    targets: Query<(Entity, &GlobalTransform, Option<&Team>), With<Health>>,
+    friendly_fire: Res<FriendlyFire>,
+    mut projectile_hit_writer: EventWriter<ProjectileHitEvent>) {
+    for &CollisionEvent{a, b, ..} in hit_events.iter() {
+        match projectiles.get_mut(a) { // Test if projectile
+            Ok((mut projectile, &team, transform, sprite)) => {
+                if !projectile.can_hit(team, b, teams.get(b).ok().copied(), *friendly_fire) { continue }
+
+                projectile.hits.push(b);
+                projectile_hit_writer.send(ProjectileHitEvent { projectile: a, other: b, origin: projectile.origin, owner: projectile.owner, damage: projectile.damage, position: transform.translation });
+                if !projectile.is_spent() { continue }
 
-            if transform.translation.distance(hit_transform.translation) <= 25. { //todo remove need for constant
-                hit_health.health = hit_health.health - projectile.damage;
-                hit_event_writer.send(ProjectileHitEvent { projectile: entity, other: hit_entity});
-                info!("Removing projectile entity {}: hit entity {} (health: {}, damage_dealt: {})", &entity.id(), &hit_entity.id(), hit_health.health, projectile.damage );
-                cmd.entity(entity).despawn();
+                if let Some(explosion) = &projectile.modifiers.explosion {
+                    for (target, target_transform, target_team) in targets.iter() {
+                        if target == b || projectile.origin == Some(target) || !friendly_fire.allows(team, target_team.copied()) { continue }
+                        let damage = explosion.damage_at(transform.translation.distance(target_transform.translation));
+                        if damage > 0 {
+                            projectile_hit_writer.send(ProjectileHitEvent { projectile: a, other: target, origin: projectile.origin, owner: projectile.owner, damage, position: target_transform.translation });
+                        }
+                    }
+                }
+
+                if let Some(fragments) = &projectile.modifiers.fragments {
+                    for direction in fragments.directions(projectile.direction) {
+                        cmd.spawn_bundle(ProjectileBundle {
+                            projectile: Projectile {
+                                direction,
+                                damage: fragments.damage,
+                                origin: projectile.origin,
+                                owner: projectile.owner,
+                                hits: vec![b],
+                                ..Default::default()
+                            },
+                            team,
+                            collision_box: CollisionBox { size: Vec2::new(5.0, 5.0) },
+                            sprite: SpriteBundle {
+                                sprite: Sprite {
+                                    color: sprite.color,
+                                    custom_size: Some(Vec2::new(5.0, 5.0)),
+                                    ..Default::default()
+                                },
+                                transform: *transform,
+                                ..Default::default()
+                            },
+                        });
+                    }
+                }
+
+                despawn_queue.despawn(a);
             }
+            Err(_) => {}
         }
     }
 }
 
-fn projectile_hit_sys(mut cmd: Commands, mut hit_events: EventReader<CollisionEvent>, projectiles: Query<&Projectile>) {
-    for &CollisionEvent{a, b, ..} in hit_events.iter() {
-        match projectiles.get(a) { // Test if projectile
-            Ok(projectile) => {
-                if projectile.origin.unwrap() != b && !projectile.whitelist.contains(&b) {
-                    cmd.entity(a).despawn();
-                }
+impl ProjectileBundle {
+    /// Replaces the sprite, collision box and speed with any given by `prefab`
+    pub fn with_prefab(mut self, prefab: &Prefab) -> Self {
+        prefab.apply_sprite(&mut self.sprite.sprite);
+        prefab.apply_collision_box(&mut self.collision_box);
+        if let Some(spec) = &prefab.projectile {
+            self.projectile.speed_multiplier = spec.speed_multiplier;
+            if let Some(turn_rate) = spec.homing {
+                self.projectile.modifiers.homing = Some(Homing { turn_rate });
             }
-            Err(_) => {}
         }
+        self
     }
 }
 
-
-
 impl Default for ProjectileBundle {
     fn default() -> Self {
         Self {
             projectile: Default::default(),
+            team: Team::Enemy,
             collision_box: CollisionBox{size: Vec2::new(10.0, 10.0)},
             sprite: SpriteBundle {
                 sprite: Sprite {
@@ -132,10 +329,112 @@ pub struct ProjectilePlugin;
 
 impl Plugin for ProjectilePlugin {
     fn build(&self, app: &mut App) {
-        app .add_system(projectile_move_sys)
-            .add_system(projectile_remove_sys)
-            .add_system(projectile_hit_sys);
-            //.add_system(projectile_hit_sys)
-            //.add_event::<ProjectileHitEvent>();
+        app .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(projectile_move_sys.timed())
+                .with_system(projectile_homing_sys.timed())
+                .with_system(projectile_remove_sys.timed())
+                .with_system(projectile_hit_sys.timed()))
+            .add_event::<ProjectileHitEvent>();
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entities(count: u32) -> Vec<Entity> {
+        let mut world = World::new();
+        (0..count).map(|_| world.spawn().id()).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn can_hit() {
+        let entities = entities(3);
+        let (shooter, target, other) = (entities[0], entities[1], entities[2]);
+        let projectile = Projectile { origin: Some(shooter), ..Default::default() };
+
+        assert!(projectile.can_hit(Team::Player, target, Some(Team::Enemy), FriendlyFire::Off));
+        assert!(projectile.can_hit(Team::Player, target, None, FriendlyFire::Off));
+        assert!(!projectile.can_hit(Team::Player, shooter, Some(Team::Enemy), FriendlyFire::All));
+        assert!(!projectile.can_hit(Team::Player, target, Some(Team::Player), FriendlyFire::Off));
+        assert!(projectile.can_hit(Team::Player, target, Some(Team::Player), FriendlyFire::Versus));
+        assert!(!projectile.can_hit(Team::Enemy, target, Some(Team::Enemy), FriendlyFire::Versus));
+
+        let projectile = Projectile { hits: vec![target], modifiers: ProjectileModifiers { pierce: 1, ..Default::default() }, ..projectile };
+        assert!(!projectile.can_hit(Team::Player, target, Some(Team::Enemy), FriendlyFire::Off));
+        assert!(projectile.can_hit(Team::Player, other, Some(Team::Enemy), FriendlyFire::Off));
+    }
+
+    #[test]
+    fn pierce_count() {
+        let targets = entities(4);
+        let mut projectile = Projectile { modifiers: ProjectileModifiers { pierce: 2, ..Default::default() }, ..Default::default() };
+        for &target in &targets[..3] {
+            assert!(!projectile.is_spent(), "spent after {} hits", projectile.hits.len());
+            assert!(projectile.can_hit(Team::Player, target, Some(Team::Enemy), FriendlyFire::Off));
+            projectile.hits.push(target);
+        }
+        assert!(projectile.is_spent());
+        assert!(!projectile.can_hit(Team::Player, targets[3], Some(Team::Enemy), FriendlyFire::Off));
+
+        let mut plain = Projectile::default();
+        plain.hits.push(targets[0]);
+        assert!(plain.is_spent());
+    }
+
+    #[test]
+    fn ricochet_reflection() {
+        let walls = Vec2::new(400., 300.);
+        let mut projectile = Projectile {
+            direction: Vec2::new(0.6, 0.8),
+            modifiers: ProjectileModifiers { ricochet: 2, ..Default::default() },
+            ..Default::default()
+        };
+
+        let mut position = Vec3::new(0., 0., 0.);
+        assert!(!projectile.ricochet(&mut position, walls));
+
+        let mut position = Vec3::new(100., 310., 0.);
+        assert!(projectile.ricochet(&mut position, walls));
+        assert_eq!(projectile.direction, Vec2::new(0.6, -0.8));
+        assert_eq!(position, Vec3::new(100., 300., 0.));
+        assert_eq!(projectile.modifiers.ricochet, 1);
+
+        // Still beyond the wall it bounced off, but now moving away from it
+        let mut position = Vec3::new(100., 305., 0.);
+        assert!(!projectile.ricochet(&mut position, walls));
+
+        let mut position = Vec3::new(410., -310., 0.);
+        assert!(projectile.ricochet(&mut position, walls));
+        assert_eq!(projectile.direction, Vec2::new(-0.6, 0.8));
+        assert_eq!(position, Vec3::new(400., -300., 0.));
+        assert_eq!(projectile.modifiers.ricochet, 0);
+
+        let mut position = Vec3::new(0., 310., 0.);
+        assert!(!projectile.ricochet(&mut position, walls));
+        assert_eq!(position, Vec3::new(0., 310., 0.));
+    }
+
+    #[test]
+    fn fragment_spread() {
+        let fragments = Fragments { count: 4, damage: 5 };
+        let directions: Vec<Vec2> = fragments.directions(Direction::UP.vec()).collect();
+        assert_eq!(directions.len(), 4);
+
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        let expected = [Vec2::new(-diagonal, diagonal), Vec2::new(-diagonal, -diagonal), Vec2::new(diagonal, -diagonal), Vec2::new(diagonal, diagonal)];
+        for (direction, expected) in directions.iter().zip(expected.iter()) {
+            assert!(direction.abs_diff_eq(*expected, 1e-5), "{} != {}", direction, expected);
+        }
+
+        for count in 1..8 {
+            let fragments = Fragments { count, damage: 5 };
+            let angles: Vec<f32> = fragments.directions(Vec2::X).map(|direction| direction.y.atan2(direction.x).rem_euclid(std::f32::consts::TAU)).collect();
+            let spacing = std::f32::consts::TAU / count as f32;
+            assert!((angles[0] - spacing / 2.).abs() < 1e-4);
+            for pair in angles.windows(2) {
+                assert!((pair[1] - pair[0] - spacing).abs() < 1e-4);
+            }
+            assert!(fragments.directions(Vec2::X).all(|direction| (direction.length() - 1.).abs() < 1e-5));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+use bevy::prelude::*;
+use crate::boss::Boss;
+use crate::common::{GameRng, GameTime};
+use crate::difficulty::DifficultyScaling;
+use crate::manager::{CurrentLevel, EndlessRun, GameState, HighScore, RunResult};
+use crate::settings::Settings;
+use crate::shop::{coin_bundle, Coin};
+use crate::snapshot::WorldSnapshot;
+use crate::stats::RunStats;
+
+/// Gameplay seconds between snapshots
+const CAPTURE_INTERVAL: f32     = 0.05;
+/// Gameplay seconds which can be rewound
+const HISTORY_SECONDS: f32      = 5.;
+/// Rewind plays back this many times faster than the game ran
+const REWIND_SPEED: f32         = 2.;
+pub const MAX_ENERGY: f32       = 1.;
+/// Energy used per second spent rewinding
+const ENERGY_DRAIN: f32         = 0.35;
+/// Energy regained per second of play
+const ENERGY_RECHARGE: f32      = 0.05;
+/// Least energy needed to start rewinding
+pub const MIN_START_ENERGY: f32 = 0.1;
+
+/// Gameplay state at one point in the history, with the scores, run stats and adaptive difficulty
+/// at that time so kills which are rewound don't keep their points or count towards achievements.
+/// The level's wave counter, the endless wave and the random numbers are kept too, so waves
+/// cleared and generated since are undone, as are coins dropped since. Enemies brought back get
+/// their art again from the art plugin.
+struct RewindPoint {
+    snapshot: WorldSnapshot,
+    run_score: i32,
+    high_score: i32,
+    stats: Option<RunStats>,
+    difficulty: Option<DifficultyScaling>,
+    level: Option<CurrentLevel>,
+    endless: Option<EndlessRun>,
+    rng: Option<GameRng>,
+    coins: Vec<(Coin, Vec3)>,
+}
+
+impl RewindPoint {
+    fn capture(world: &mut World) -> Self {
+        let mut coins = world.query::<(&Coin, &Transform)>();
+        let coins = coins.iter(world).map(|(coin, transform)| (coin.clone(), transform.translation)).collect();
+        Self {
+            snapshot: WorldSnapshot::capture(world),
+            run_score: world.get_resource::<RunResult>().map_or(0, |run| run.score),
+            high_score: world.get_resource::<HighScore>().map_or(0, |high_score| high_score.0),
+            stats: world.get_resource::<RunStats>().cloned(),
+            difficulty: world.get_resource::<DifficultyScaling>().cloned(),
+            level: world.get_resource::<CurrentLevel>().cloned(),
+            endless: world.get_resource::<EndlessRun>().cloned(),
+            rng: world.get_resource::<GameRng>().cloned(),
+            coins,
+        }
+    }
+
+    fn restore(&self, world: &mut World) {
+        self.snapshot.restore(world);
+        if let Some(mut run) = world.get_resource_mut::<RunResult>() { run.score = self.run_score }
+        if let Some(mut high_score) = world.get_resource_mut::<HighScore>() { high_score.0 = self.high_score }
+        if let Some(stats) = &self.stats { world.insert_resource(stats.clone()) }
+        if let Some(difficulty) = &self.difficulty { world.insert_resource(difficulty.clone()) }
+        if let Some(level) = &self.level { world.insert_resource(level.clone()) }
+        if let Some(endless) = &self.endless { world.insert_resource(endless.clone()) }
+        if let Some(rng) = &self.rng { world.insert_resource(rng.clone()) }
+
+        let coins: Vec<Entity> = world.query_filtered::<Entity, With<Coin>>().iter(world).collect();
+        for entity in coins {
+            world.despawn(entity);
+        }
+        for (coin, position) in &self.coins {
+            world.spawn().insert_bundle(coin_bundle(*position)).insert(coin.clone());
+        }
+    }
+}
+
+/// Recent history of the run, and the energy available to rewind through it. Bosses aren't
+/// captured by snapshots, so the history is cleared and rewinding unavailable while one is alive.
+pub struct Rewind {
+    /// Oldest first
+    history: VecDeque<RewindPoint>,
+    since_capture: f32,
+    /// Seconds of history to step back by, carried between frames
+    pending: f32,
+    pub energy: f32,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self { history: VecDeque::new(), since_capture: 0., pending: 0., energy: MAX_ENERGY }
+    }
+}
+
+impl Rewind {
+    /// Seconds of history available
+    pub fn available(&self) -> f32 {
+        self.history.len().saturating_sub(1) as f32 * CAPTURE_INTERVAL
+    }
+
+    /// Forgets the history, so nothing before now can be rewound
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// Sent when the player stops rewinding
+pub struct RewindEvent {
+    /// Gameplay seconds undone
+    pub seconds: f32,
+}
+
+/// Seconds rewound since rewinding started
+#[derive(Default)]
+struct RewindProgress(f32);
+
+/// Records the state every [`CAPTURE_INTERVAL`]. Frames where the level's enemies are queued
+/// but not spawned yet are skipped, as restoring one would leave the level without them.
+fn rewind_capture_sys(world: &mut World) {
+    let delta = world.get_resource::<GameTime>().map_or(0., |time| time.delta_seconds());
+    let boss_alive = world.query::<&Boss>().iter(world).next().is_some();
+    let spawn_pending = world.get_resource::<CurrentLevel>().map_or(false, |level| level.pending);
+
+    let mut rewind = match world.remove_resource::<Rewind>() {
+        Some(rewind) => rewind,
+        None => return,
+    };
+    rewind.energy = (rewind.energy + ENERGY_RECHARGE * delta).min(MAX_ENERGY);
+    rewind.since_capture += delta;
+    if boss_alive {
+        rewind.history.clear();
+    } else if !spawn_pending && (rewind.since_capture >= CAPTURE_INTERVAL || rewind.history.is_empty()) {
+        rewind.since_capture = 0.;
+        rewind.history.push_back(RewindPoint::capture(world));
+        if rewind.history.len() as f32 * CAPTURE_INTERVAL > HISTORY_SECONDS {
+            rewind.history.pop_front();
+        }
+    }
+    world.insert_resource(rewind);
+}
+
+/// Starts rewinding while the rewind binding is held, if there is energy and history
+fn rewind_start_sys(
+    keyboard_input: Res<Input<KeyCode>>,
+    settings: Res<Settings>,
+    rewind: Res<Rewind>,
+    mut progress: ResMut<RewindProgress>,
+    mut state: ResMut<State<GameState>>) {
+    if !keyboard_input.pressed(settings.bindings.rewind) { return }
+    if rewind.energy < MIN_START_ENERGY || rewind.available() <= 0. { return }
+
+    progress.0 = 0.;
+    if let Err(err) = state.push(GameState::Rewinding) {
+        warn!("Rewind ignored: {:?}", err);
+    }
+}
+
+/// Steps back through the history while the rewind binding is held, restoring the gameplay state
+/// and scores. Returns to play once released, or out of energy or history.
+fn rewind_sys(world: &mut World) {
+    let delta = world.get_resource::<Time>().map_or(0., |time| time.delta_seconds());
+    let held = match (world.get_resource::<Input<KeyCode>>(), world.get_resource::<Settings>()) {
+        (Some(keyboard_input), Some(settings)) => keyboard_input.pressed(settings.bindings.rewind),
+        _ => false,
+    };
+    let mut rewind = match world.remove_resource::<Rewind>() {
+        Some(rewind) => rewind,
+        None => return,
+    };
+
+    let mut seconds = 0.;
+    if held && rewind.energy > 0. {
+        rewind.energy = (rewind.energy - ENERGY_DRAIN * delta).max(0.);
+        rewind.pending += delta * REWIND_SPEED;
+        while rewind.pending >= CAPTURE_INTERVAL && rewind.history.len() > 1 {
+            rewind.pending -= CAPTURE_INTERVAL;
+            rewind.history.pop_back();
+            seconds += CAPTURE_INTERVAL;
+        }
+    }
+
+    if seconds > 0. {
+        if let Some(point) = rewind.history.back() {
+            point.restore(world);
+        }
+    }
+    let finished = !held || rewind.energy <= 0. || rewind.history.len() <= 1;
+    if finished {
+        rewind.pending = 0.;
+        rewind.since_capture = 0.;
+    }
+    world.insert_resource(rewind);
+
+    let mut progress = world.get_resource_or_insert_with(RewindProgress::default);
+    progress.0 += seconds;
+    if finished {
+        let seconds = std::mem::take(&mut progress.0);
+        if let Some(mut rewind_events) = world.get_resource_mut::<Events<RewindEvent>>() {
+            rewind_events.send(RewindEvent { seconds });
+        }
+        if let Some(mut state) = world.get_resource_mut::<State<GameState>>() {
+            if let Err(err) = state.pop() {
+                warn!("Failed to stop rewinding: {:?}", err);
+            }
+        }
+    }
+}
+
+fn rewind_reset_sys(mut cmd: Commands) {
+    cmd.insert_resource(Rewind::default());
+}
+
+pub struct RewindPlugin;
+
+impl Plugin for RewindPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<Rewind>()
+            .init_resource::<RewindProgress>()
+            .add_event::<RewindEvent>()
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(rewind_capture_sys.exclusive_system())
+                .with_system(rewind_start_sys))
+            .add_system_set(SystemSet::on_update(GameState::Rewinding)
+                .with_system(rewind_sys.exclusive_system()))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(rewind_reset_sys));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use crate::enemy::{Enemy, EnemyBundle};
+    use crate::level::endless_wave;
+    use crate::player::{Player, PlayerBundle};
+    use crate::settings::Difficulty;
+    use super::*;
+
+    #[test]
+    fn rewound_kill_is_undone() {
+        let mut world = World::new();
+        let mut rng = GameRng::seeded(7);
+        world.insert_resource(EndlessRun { wave: endless_wave(1, &mut rng.rng), pending_shop: false });
+        world.insert_resource(rng);
+        world.insert_resource(RunResult::default());
+        world.insert_resource(HighScore::default());
+        world.insert_resource(RunStats::new(Difficulty::Normal));
+        world.insert_resource(DifficultyScaling::default());
+        world.insert_resource(CurrentLevel { handle: Default::default(), wave: 1, spawned: true, boss_spawned: true, pending: false });
+        let player = world.spawn().insert_bundle(PlayerBundle::default()).id();
+        let enemy = world.spawn().insert_bundle(EnemyBundle::default()).id();
+        let score = world.get::<Player>(player).unwrap().score;
+        let point = RewindPoint::capture(&mut world);
+        // The random number the captured state produces next, which rewinding must repeat
+        let next_random = world.get_resource_mut::<GameRng>().unwrap().rng.gen::<u64>();
+        point.restore(&mut world);
+
+        // The enemy is destroyed, paying out and dropping a coin which is still falling when the
+        // game is rewound. That clears the wave, so the next is generated.
+        world.despawn(enemy);
+        let mut player_state = world.get_mut::<Player>(player).unwrap();
+        player_state.score += 10;
+        player_state.credits += 5;
+        world.get_resource_mut::<RunResult>().unwrap().score += 10;
+        world.get_resource_mut::<HighScore>().unwrap().0 += 10;
+        world.get_resource_mut::<RunStats>().unwrap().add_kill("enemy_grunt");
+        world.spawn().insert_bundle(coin_bundle(Vec3::ZERO)).insert(Coin { value: 5 });
+        let mut level = world.get_resource_mut::<CurrentLevel>().unwrap();
+        level.wave += 1;
+        let mut rng = world.get_resource_mut::<GameRng>().unwrap();
+        let next_wave = endless_wave(2, &mut rng.rng);
+        world.insert_resource(EndlessRun { wave: next_wave, pending_shop: true });
+
+        point.restore(&mut world);
+        let players: Vec<Player> = world.query::<&Player>().iter(&world).cloned().collect();
+        assert_eq!(players.len(), 1);
+        assert_eq!((players[0].score, players[0].credits), (score, 0));
+        assert_eq!(world.get_resource::<RunResult>().unwrap().score, 0);
+        assert_eq!(world.get_resource::<HighScore>().unwrap().0, 0);
+        assert_eq!(world.get_resource::<RunStats>().unwrap().total_kills(), 0);
+        assert_eq!(world.get_resource::<CurrentLevel>().unwrap().wave, 1);
+        assert!(!world.get_resource::<EndlessRun>().unwrap().pending_shop);
+        assert_eq!(world.get_resource_mut::<GameRng>().unwrap().rng.gen::<u64>(), next_random);
+        assert_eq!(world.query::<&Enemy>().iter(&world).count(), 1);
+        assert_eq!(world.query::<&Coin>().iter(&world).count(), 0);
+    }
+}
@@ -0,0 +1,528 @@
+use std::fs;
+use std::path::PathBuf;
+use bevy::prelude::*;
+use bevy::window::WindowMode;
+use serde::{Deserialize, Serialize, Serializer};
+use serde::de::DeserializeOwned;
+use toml::value::Table;
+
+const SETTINGS_FILE: &str = "settings.toml";
+const VOLUME_STEP: f32 = 0.1;
+
+/// Keys which can be bound to an [`Action`]. Names in the settings file match the `KeyCode` variant names.
+pub const BINDABLE_KEYS: &[KeyCode] = &[
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G, KeyCode::H, KeyCode::I,
+    KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N, KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R,
+    KeyCode::S, KeyCode::T, KeyCode::U, KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+    KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right,
+    KeyCode::Space, KeyCode::Return, KeyCode::Escape, KeyCode::Tab, KeyCode::Back,
+    KeyCode::LShift, KeyCode::RShift, KeyCode::LControl, KeyCode::RControl, KeyCode::LAlt, KeyCode::RAlt,
+];
+
+/// Directory holding the settings file and other local game data, e.g. `~/.config/assault` on Linux
+pub fn config_dir() -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Application Support"))
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|base| base.join("assault"))
+}
+
+pub fn key_name(key: KeyCode) -> String {
+    format!("{:?}", key)
+}
+
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    BINDABLE_KEYS.iter().copied().find(|key| key_name(*key).eq_ignore_ascii_case(name))
+}
+
+fn serialize_key<S: Serializer>(key: &KeyCode, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&key_name(*key))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+    Insane,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 4] = [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard, Difficulty::Insane];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayMode {
+    Windowed,
+    BorderlessFullscreen,
+    Fullscreen,
+}
+
+impl DisplayMode {
+    pub const ALL: [DisplayMode; 3] = [DisplayMode::Windowed, DisplayMode::BorderlessFullscreen, DisplayMode::Fullscreen];
+
+    fn window_mode(&self) -> WindowMode {
+        match self {
+            DisplayMode::Windowed             => WindowMode::Windowed,
+            DisplayMode::BorderlessFullscreen => WindowMode::BorderlessFullscreen,
+            DisplayMode::Fullscreen           => WindowMode::Fullscreen { use_size: false },
+        }
+    }
+}
+
+/// Gameplay actions which can be bound to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    Fire,
+    Reload,
+    Boost,
+    Slow,
+    Rewind,
+    Pause,
+}
+
+impl Action {
+    pub const ALL: [Action; 8] = [Action::MoveLeft, Action::MoveRight, Action::Fire, Action::Reload, Action::Boost, Action::Slow, Action::Rewind, Action::Pause];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::MoveLeft  => "MOVE LEFT",
+            Action::MoveRight => "MOVE RIGHT",
+            Action::Fire      => "FIRE",
+            Action::Reload    => "RELOAD",
+            Action::Boost     => "BOOST",
+            Action::Slow      => "SLOW",
+            Action::Rewind    => "REWIND",
+            Action::Pause     => "PAUSE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioSettings {
+    pub master: f32,
+    pub music: f32,
+    pub effects: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self { master: 0.8, music: 0.6, effects: 1.0 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyBindings {
+    #[serde(serialize_with = "serialize_key")] pub move_left: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub move_right: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub fire: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub reload: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub boost: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub slow: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub rewind: KeyCode,
+    #[serde(serialize_with = "serialize_key")] pub pause: KeyCode,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            move_left: KeyCode::A,
+            move_right: KeyCode::D,
+            fire: KeyCode::Space,
+            reload: KeyCode::R,
+            boost: KeyCode::LShift,
+            slow: KeyCode::LControl,
+            rewind: KeyCode::Q,
+            pause: KeyCode::Escape,
+        }
+    }
+}
+
+impl KeyBindings {
+    pub fn get(&self, action: Action) -> KeyCode {
+        match action {
+            Action::MoveLeft  => self.move_left,
+            Action::MoveRight => self.move_right,
+            Action::Fire      => self.fire,
+            Action::Reload    => self.reload,
+            Action::Boost     => self.boost,
+            Action::Slow      => self.slow,
+            Action::Rewind    => self.rewind,
+            Action::Pause     => self.pause,
+        }
+    }
+
+    /// Binds `key` to `action`. An action already bound to `key` swaps to the key `action` had,
+    /// so no two actions share a key. Returns the action which was swapped, if any.
+    pub fn set(&mut self, action: Action, key: KeyCode) -> Option<Action> {
+        let previous = self.get(action);
+        let swapped = Action::ALL.iter().copied().find(|other| *other != action && self.get(*other) == key);
+        if let Some(other) = swapped {
+            *self.binding_mut(other) = previous;
+        }
+        *self.binding_mut(action) = key;
+        swapped
+    }
+
+    /// The first pair of actions bound to the same key
+    pub fn conflict(&self) -> Option<(Action, Action)> {
+        Action::ALL.iter().enumerate()
+            .flat_map(|(i, a)| Action::ALL[i + 1..].iter().map(move |b| (*a, *b)))
+            .find(|(a, b)| self.get(*a) == self.get(*b))
+    }
+
+    fn binding_mut(&mut self, action: Action) -> &mut KeyCode {
+        match action {
+            Action::MoveLeft  => &mut self.move_left,
+            Action::MoveRight => &mut self.move_right,
+            Action::Fire      => &mut self.fire,
+            Action::Reload    => &mut self.reload,
+            Action::Boost     => &mut self.boost,
+            Action::Slow      => &mut self.slow,
+            Action::Rewind    => &mut self.rewind,
+            Action::Pause     => &mut self.pause,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DisplaySettings {
+    pub mode: DisplayMode,
+    pub vsync: bool,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self { mode: DisplayMode::Windowed, vsync: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessibilitySettings {
+    pub screen_shake: bool,
+    pub hit_stop: bool,
+    pub damage_flash: bool,
+}
+
+impl Default for AccessibilitySettings {
+    fn default() -> Self {
+        Self { screen_shake: true, hit_stop: true, damage_flash: true }
+    }
+}
+
+/// User settings, persisted to `settings.toml` in [`config_dir`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Settings {
+    pub difficulty: Difficulty,
+    /// Adjusts difficulty during a run based on how well the player is doing
+    pub adaptive_difficulty: bool,
+    pub audio: AudioSettings,
+    pub bindings: KeyBindings,
+    pub display: DisplaySettings,
+    pub accessibility: AccessibilitySettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            difficulty: Difficulty::Normal,
+            adaptive_difficulty: false,
+            audio: Default::default(),
+            bindings: Default::default(),
+            display: Default::default(),
+            accessibility: Default::default(),
+        }
+    }
+}
+
+/// Reads `key` from `table` into `value`. Invalid values are left at their default and recorded as a warning.
+fn read_field<T: DeserializeOwned>(table: &Table, key: &str, value: &mut T, warnings: &mut Vec<String>) {
+    if let Some(raw) = table.get(key) {
+        match raw.clone().try_into::<T>() {
+            Ok(parsed) => *value = parsed,
+            Err(err) => warnings.push(format!("'{}' is invalid ({}), using default", key, err)),
+        }
+    }
+}
+
+fn read_volume(table: &Table, key: &str, value: &mut f32, warnings: &mut Vec<String>) {
+    read_field(table, key, value, warnings);
+    if !(0.0..=1.0).contains(value) {
+        warnings.push(format!("'{}' must be between 0 and 1, clamping {}", key, value));
+        *value = value.clamp(0.0, 1.0);
+    }
+}
+
+fn read_key(table: &Table, key: &str, value: &mut KeyCode, warnings: &mut Vec<String>) {
+    let mut name = key_name(*value);
+    read_field(table, key, &mut name, warnings);
+    match key_from_name(&name) {
+        Some(parsed) => *value = parsed,
+        None => warnings.push(format!("'{}' has unknown key '{}', using {:?}", key, name, value)),
+    }
+}
+
+fn section(table: &Table, key: &str) -> Table {
+    table.get(key).and_then(|value| value.as_table()).cloned().unwrap_or_default()
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(SETTINGS_FILE))
+    }
+
+    /// Parses settings from TOML. Missing or invalid values fall back to their defaults and are
+    /// reported in the returned warnings rather than rejecting the whole file.
+    pub fn from_toml(source: &str) -> (Settings, Vec<String>) {
+        let mut settings = Settings::default();
+        let mut warnings = Vec::new();
+
+        let root = match source.parse::<toml::Value>() {
+            Ok(toml::Value::Table(root)) => root,
+            Ok(_) => { warnings.push(String::from("settings file is not a table, using defaults")); return (settings, warnings) }
+            Err(err) => { warnings.push(format!("settings file could not be parsed ({}), using defaults", err)); return (settings, warnings) }
+        };
+
+        read_field(&root, "difficulty", &mut settings.difficulty, &mut warnings);
+        read_field(&root, "adaptive_difficulty", &mut settings.adaptive_difficulty, &mut warnings);
+
+        let audio = section(&root, "audio");
+        read_volume(&audio, "master", &mut settings.audio.master, &mut warnings);
+        read_volume(&audio, "music", &mut settings.audio.music, &mut warnings);
+        read_volume(&audio, "effects", &mut settings.audio.effects, &mut warnings);
+
+        let bindings = section(&root, "bindings");
+        let b = &mut settings.bindings;
+        read_key(&bindings, "move_left", &mut b.move_left, &mut warnings);
+        read_key(&bindings, "move_right", &mut b.move_right, &mut warnings);
+        read_key(&bindings, "fire", &mut b.fire, &mut warnings);
+        read_key(&bindings, "reload", &mut b.reload, &mut warnings);
+        read_key(&bindings, "boost", &mut b.boost, &mut warnings);
+        read_key(&bindings, "slow", &mut b.slow, &mut warnings);
+        read_key(&bindings, "rewind", &mut b.rewind, &mut warnings);
+        read_key(&bindings, "pause", &mut b.pause, &mut warnings);
+        if let Some((first, second)) = b.conflict() {
+            warnings.push(format!("{} and {} are bound to the same key, using default bindings", first.name(), second.name()));
+            *b = KeyBindings::default();
+        }
+
+        let display = section(&root, "display");
+        read_field(&display, "mode", &mut settings.display.mode, &mut warnings);
+        read_field(&display, "vsync", &mut settings.display.vsync, &mut warnings);
+
+        let accessibility = section(&root, "accessibility");
+        read_field(&accessibility, "screen_shake", &mut settings.accessibility.screen_shake, &mut warnings);
+        read_field(&accessibility, "hit_stop", &mut settings.accessibility.hit_stop, &mut warnings);
+        read_field(&accessibility, "damage_flash", &mut settings.accessibility.damage_flash, &mut warnings);
+
+        (settings, warnings)
+    }
+
+    /// Loads settings from the config directory, using defaults if there is no settings file yet
+    pub fn load() -> Settings {
+        let path = match Settings::path() {
+            Some(path) => path,
+            None => { warn!("No config directory found, using default settings"); return Settings::default() }
+        };
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(_) => { info!("No settings file at {}, using defaults", path.display()); return Settings::default() }
+        };
+
+        let (settings, warnings) = Settings::from_toml(&source);
+        for warning in warnings {
+            warn!("{}: {}", path.display(), warning);
+        }
+        settings
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Settings::path().ok_or_else(|| String::from("no config directory"))?;
+        let source = toml::to_string_pretty(self).map_err(|err| err.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        fs::write(&path, source).map_err(|err| err.to_string())?;
+        info!("Saved settings to {}", path.display());
+        Ok(())
+    }
+
+    /// Steps the value of `option` forwards or backwards. Booleans are toggled.
+    pub fn adjust(&mut self, option: SettingOption, step: i32) {
+        fn cycle<T: Copy + PartialEq>(all: &[T], current: T, step: i32) -> T {
+            let index = all.iter().position(|value| *value == current).unwrap_or(0) as i32;
+            all[(index + step).rem_euclid(all.len() as i32) as usize]
+        }
+        fn volume(value: &mut f32, step: i32) {
+            *value = ((*value + VOLUME_STEP * step as f32) * 10.0).round().clamp(0.0, 10.0) / 10.0;
+        }
+
+        match option {
+            SettingOption::Difficulty    => self.difficulty = cycle(&Difficulty::ALL, self.difficulty, step),
+            SettingOption::Adaptive      => self.adaptive_difficulty = !self.adaptive_difficulty,
+            SettingOption::MasterVolume  => volume(&mut self.audio.master, step),
+            SettingOption::MusicVolume   => volume(&mut self.audio.music, step),
+            SettingOption::EffectsVolume => volume(&mut self.audio.effects, step),
+            SettingOption::DisplayMode   => self.display.mode = cycle(&DisplayMode::ALL, self.display.mode, step),
+            SettingOption::VSync         => self.display.vsync = !self.display.vsync,
+            SettingOption::ScreenShake   => self.accessibility.screen_shake = !self.accessibility.screen_shake,
+            SettingOption::HitStop       => self.accessibility.hit_stop = !self.accessibility.hit_stop,
+            SettingOption::DamageFlash   => self.accessibility.damage_flash = !self.accessibility.damage_flash,
+            SettingOption::Binding(_)    => {}
+        }
+    }
+
+    /// Text shown for `option` in the settings menu
+    pub fn label(&self, option: SettingOption) -> String {
+        let on_off = |value: bool| if value { "ON" } else { "OFF" };
+        let percent = |value: f32| format!("{}%", (value * 100.0).round() as i32);
+        match option {
+            SettingOption::Difficulty    => format!("DIFFICULTY {:?}", self.difficulty).to_uppercase(),
+            SettingOption::Adaptive      => format!("ADAPTIVE DIFFICULTY {}", on_off(self.adaptive_difficulty)),
+            SettingOption::MasterVolume  => format!("MASTER VOLUME {}", percent(self.audio.master)),
+            SettingOption::MusicVolume   => format!("MUSIC VOLUME {}", percent(self.audio.music)),
+            SettingOption::EffectsVolume => format!("EFFECTS VOLUME {}", percent(self.audio.effects)),
+            SettingOption::DisplayMode   => format!("DISPLAY {:?}", self.display.mode).to_uppercase(),
+            SettingOption::VSync         => format!("VSYNC {}", on_off(self.display.vsync)),
+            SettingOption::ScreenShake   => format!("SCREEN SHAKE {}", on_off(self.accessibility.screen_shake)),
+            SettingOption::HitStop       => format!("HIT STOP {}", on_off(self.accessibility.hit_stop)),
+            SettingOption::DamageFlash   => format!("DAMAGE FLASH {}", on_off(self.accessibility.damage_flash)),
+            SettingOption::Binding(action) => format!("{} {}", action.name(), key_name(self.bindings.get(action)).to_uppercase()),
+        }
+    }
+}
+
+/// A value which can be changed from the settings menu
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingOption {
+    Difficulty,
+    Adaptive,
+    MasterVolume,
+    MusicVolume,
+    EffectsVolume,
+    DisplayMode,
+    VSync,
+    ScreenShake,
+    HitStop,
+    DamageFlash,
+    Binding(Action),
+}
+
+impl SettingOption {
+    /// Options in the order they are shown in the settings menu
+    pub fn all() -> Vec<SettingOption> {
+        let mut options = vec![
+            SettingOption::Difficulty,
+            SettingOption::Adaptive,
+            SettingOption::MasterVolume,
+            SettingOption::MusicVolume,
+            SettingOption::EffectsVolume,
+            SettingOption::DisplayMode,
+            SettingOption::VSync,
+            SettingOption::ScreenShake,
+            SettingOption::HitStop,
+            SettingOption::DamageFlash,
+        ];
+        options.extend(Action::ALL.iter().map(|action| SettingOption::Binding(*action)));
+        options
+    }
+}
+
+fn settings_display_sys(settings: Res<Settings>, mut windows: ResMut<Windows>) {
+    if !settings.is_changed() { return }
+    if let Some(window) = windows.get_primary_mut() {
+        window.set_mode(settings.display.mode.window_mode());
+        window.set_vsync(settings.display.vsync);
+    }
+}
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app .insert_resource(Settings::load())
+            .add_system(settings_display_sys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let (settings, warnings) = Settings::from_toml("");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(settings.difficulty, Difficulty::Normal);
+        assert_eq!(settings.bindings.fire, KeyCode::Space);
+    }
+
+    #[test]
+    fn invalid_fields_fall_back_one_by_one() {
+        let source = r#"
+            difficulty = "Hard"
+            adaptive_difficulty = "yes"
+
+            [audio]
+            master = 2.0
+            music = 0.3
+
+            [bindings]
+            fire = "F"
+            reload = "NotAKey"
+
+            [display]
+            mode = 5
+            vsync = false
+        "#;
+        let (settings, warnings) = Settings::from_toml(source);
+
+        assert_eq!(settings.difficulty, Difficulty::Hard);
+        assert!(!settings.adaptive_difficulty);
+        assert_eq!(settings.audio.master, 1.0);
+        assert_eq!(settings.audio.music, 0.3);
+        assert_eq!(settings.audio.effects, AudioSettings::default().effects);
+        assert_eq!(settings.bindings.fire, KeyCode::F);
+        assert_eq!(settings.bindings.reload, KeyCode::R);
+        assert_eq!(settings.display.mode, DisplayMode::Windowed);
+        assert!(!settings.display.vsync);
+        assert_eq!(warnings.len(), 4, "{:?}", warnings);
+    }
+
+    #[test]
+    fn unparsable_file_uses_defaults() {
+        let (settings, warnings) = Settings::from_toml("difficulty = ");
+        assert_eq!(settings.difficulty, Difficulty::Normal);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_bindings_are_rejected() {
+        let (settings, warnings) = Settings::from_toml("[bindings]\nfire = \"R\"\n");
+        assert_eq!(settings.bindings.fire, KeyCode::Space);
+        assert_eq!(settings.bindings.reload, KeyCode::R);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    }
+
+    #[test]
+    fn binding_a_used_key_swaps() {
+        let mut bindings = KeyBindings::default();
+        assert_eq!(bindings.set(Action::Fire, KeyCode::R), Some(Action::Reload));
+        assert_eq!(bindings.fire, KeyCode::R);
+        assert_eq!(bindings.reload, KeyCode::Space);
+        assert_eq!(bindings.conflict(), None);
+
+        assert_eq!(bindings.set(Action::Rewind, KeyCode::Escape), Some(Action::Pause));
+        assert_eq!(bindings.rewind, KeyCode::Escape);
+        assert_eq!(bindings.pause, KeyCode::Q);
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::BTreeSet;
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::sprite::collide_aabb::collide;
+use bevy::utils::{BoxedFuture, HashMap};
+use serde::Deserialize;
+use crate::boss::BossDefeatedEvent;
+use crate::common::{CollisionBox, DeathEvent, DespawnQueue, GameTime, Health, Shooter, Victim};
+use crate::manager::{CurrentLevel, EndlessRun, GameState, RunMode};
+use crate::player::Player;
+use crate::prefab::Prefabs;
+use crate::projectile::{Explosion, Fragments, Homing};
+use crate::rewind::Rewind;
+use crate::sound::{Sound, SoundRequest};
+
+const DEFINITIONS_PATH: &str = "shop/default.shop.ron";
+/// Distance per second dropped credits fall
+const COIN_FALL_SPEED: f32   = 150.;
+const COIN_SIZE: f32         = 12.;
+/// [`Player::index`] of the player the shop sells to. Shops only open in single player runs.
+const SHOP_PLAYER: usize     = 0;
+
+/// An upgrade for sale, as defined in a `.shop.ron` file
+#[derive(Debug, Clone, Deserialize)]
+pub struct Upgrade {
+    /// Stable name purchases are counted under
+    pub id: String,
+    pub name: String,
+    /// Price of the first purchase
+    pub cost: u32,
+    /// Added to the price for each purchase already made
+    #[serde(default)]
+    pub cost_step: u32,
+    /// Times the upgrade can be bought in one run
+    #[serde(default = "default_max_level")]
+    pub max_level: u32,
+    pub effect: UpgradeEffect,
+}
+
+fn default_max_level() -> u32 {
+    1
+}
+
+impl Upgrade {
+    /// Price of the purchase made after `level` others
+    pub fn cost(&self, level: u32) -> u32 {
+        self.cost + self.cost_step * level
+    }
+}
+
+/// What buying an [`Upgrade`] does to the player's components. Effects stack with each purchase.
+#[derive(Debug, Clone, Deserialize)]
+pub enum UpgradeEffect {
+    /// Adds to [`Health::max_health`], healing by the same amount
+    MaxHealth(i32),
+    /// Multiplies the [`Shooter`] reload time. Players fire once per press rather than at
+    /// [`Shooter::fire_rate`], so this is what sets their rate of fire.
+    ReloadTime(f32),
+    /// Adds to [`Shooter::magazine_size`], filling the magazine by the same amount
+    MagazineSize(i32),
+    /// Multiplies [`Player::move_speed`]
+    MoveSpeed(f32),
+    /// Switches to the `weapon` section of the named prefab
+    Weapon(String),
+    Pierce(u32),
+    Ricochet(u32),
+    Homing { turn_rate: f32 },
+    Explosion { radius: f32, damage: i32 },
+    Fragments { count: u32, damage: i32 },
+}
+
+impl UpgradeEffect {
+    pub fn apply(&self, player: &mut Player, health: &mut Health, shooter: &mut Shooter, prefabs: &Prefabs) {
+        match self {
+            UpgradeEffect::MaxHealth(amount) => {
+                health.max_health += amount;
+                health.health += amount;
+            }
+            UpgradeEffect::ReloadTime(factor) => {
+                let duration = shooter.reload_timer.duration().mul_f32(*factor);
+                shooter.reload_timer.set_duration(duration);
+            }
+            UpgradeEffect::MagazineSize(amount) => {
+                shooter.magazine_size += amount;
+                shooter.ammo_count += amount;
+            }
+            UpgradeEffect::MoveSpeed(factor) => player.move_speed *= factor,
+            UpgradeEffect::Weapon(name) => prefabs.get(name).apply_weapon(shooter),
+            UpgradeEffect::Pierce(count) => shooter.modifiers.pierce += count,
+            UpgradeEffect::Ricochet(count) => shooter.modifiers.ricochet += count,
+            UpgradeEffect::Homing { turn_rate } => shooter.modifiers.homing = Some(Homing { turn_rate: *turn_rate }),
+            UpgradeEffect::Explosion { radius, damage } => shooter.modifiers.explosion = Some(Explosion { radius: *radius, damage: *damage }),
+            UpgradeEffect::Fragments { count, damage } => shooter.modifiers.fragments = Some(Fragments { count: *count, damage: *damage }),
+        }
+    }
+}
+
+/// Upgrades for sale and the credits enemies drop, loaded from a `.shop.ron` file
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "5a8c2e71-d94b-4f06-a3e1-8b7f20c6d915"]
+pub struct ShopDefinition {
+    /// Credits dropped by each enemy destroyed
+    pub enemy_drop: u32,
+    /// Credits dropped by each boss defeated
+    pub boss_drop: u32,
+    pub upgrades: Vec<Upgrade>,
+}
+
+impl ShopDefinition {
+    /// Checks values are in range, returning a message for each problem
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut ids = BTreeSet::new();
+        for upgrade in self.upgrades.iter() {
+            let mut check = |ok: bool, message: &str| if !ok { errors.push(format!("{}: {}", upgrade.id, message)) };
+            check(ids.insert(upgrade.id.as_str()), "id is used more than once");
+            check(upgrade.max_level > 0, "max level must be positive");
+            match upgrade.effect {
+                UpgradeEffect::ReloadTime(factor) | UpgradeEffect::MoveSpeed(factor) => check(factor > 0., "multiplier must be positive"),
+                UpgradeEffect::MagazineSize(amount) => check(amount > 0, "magazine size increase must be positive"),
+                UpgradeEffect::Homing { turn_rate } => check(turn_rate > 0., "turn rate must be positive"),
+                UpgradeEffect::Explosion { radius, .. } => check(radius > 0., "explosion radius must be positive"),
+                _ => {}
+            }
+        }
+        errors
+    }
+}
+
+#[derive(Default)]
+pub struct ShopLoader;
+
+impl AssetLoader for ShopLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let definition: ShopDefinition = ron::de::from_bytes(bytes)?;
+            let errors = definition.validate();
+            if !errors.is_empty() {
+                return Err(anyhow::anyhow!("Invalid shop in {}:\n  {}", load_context.path().display(), errors.join("\n  ")));
+            }
+            load_context.set_default_asset(LoadedAsset::new(definition));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["shop.ron"]
+    }
+}
+
+/// The loaded shop and the upgrades bought this run
+#[derive(Default)]
+pub struct Shop {
+    handle: Handle<ShopDefinition>,
+    pub enemy_drop: u32,
+    pub boss_drop: u32,
+    pub upgrades: Vec<Upgrade>,
+    /// Times each upgrade has been bought this run, by id
+    pub levels: HashMap<String, u32>,
+}
+
+impl Shop {
+    pub fn level(&self, upgrade: &Upgrade) -> u32 {
+        self.levels.get(&upgrade.id).copied().unwrap_or(0)
+    }
+
+    /// Price of the next purchase of `upgrade`, or `None` once it can't be bought again
+    pub fn price(&self, upgrade: &Upgrade) -> Option<u32> {
+        let level = self.level(upgrade);
+        (level < upgrade.max_level).then(|| upgrade.cost(level))
+    }
+}
+
+/// Asks the shop to sell the upgrade at this index of [`Shop::upgrades`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PurchaseRequest(pub usize);
+
+/// Credits dropped by a destroyed enemy, collected by touching them
+#[derive(Component, Clone)]
+pub struct Coin {
+    pub value: u32,
+}
+
+fn shop_load_sys(mut shop: ResMut<Shop>, asset_server: Res<AssetServer>) {
+    shop.handle = asset_server.load(DEFINITIONS_PATH);
+}
+
+fn shop_reload_sys(
+    mut shop: ResMut<Shop>,
+    mut definition_events: EventReader<AssetEvent<ShopDefinition>>,
+    definitions: Res<Assets<ShopDefinition>>) {
+    for event in definition_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } if *handle == shop.handle => {}
+            _ => continue,
+        }
+        if let Some(definition) = definitions.get(&shop.handle) {
+            shop.enemy_drop = definition.enemy_drop;
+            shop.boss_drop = definition.boss_drop;
+            shop.upgrades = definition.upgrades.clone();
+            info!("Loaded {} upgrades", shop.upgrades.len());
+        }
+    }
+}
+
+fn shop_reset_sys(mut shop: ResMut<Shop>) {
+    shop.levels.clear();
+}
+
+/// Sprite of a [`Coin`] at `position`
+pub fn coin_bundle(position: Vec3) -> SpriteBundle {
+    SpriteBundle {
+        sprite: Sprite {
+            color: Color::GOLD,
+            custom_size: Some(Vec2::new(COIN_SIZE, COIN_SIZE)),
+            ..Default::default()
+        },
+        transform: Transform::from_translation(position),
+        ..Default::default()
+    }
+}
+
+fn spawn_coin(cmd: &mut Commands, value: u32, position: Vec3) {
+    if value == 0 { return }
+    cmd.spawn_bundle(coin_bundle(position)).insert(Coin { value });
+}
+
+/// Drops credits where enemies and bosses are destroyed. Only endless runs have a shop to spend
+/// them in, so only they drop any.
+fn coin_drop_sys(
+    mut cmd: Commands,
+    mut death_events: EventReader<DeathEvent>,
+    mut boss_defeated_events: EventReader<BossDefeatedEvent>,
+    shop: Res<Shop>,
+    mode: Res<RunMode>) {
+    if *mode == RunMode::Campaign { return }
+    for death in death_events.iter() {
+        if let Victim::Enemy(_) = death.victim {
+            spawn_coin(&mut cmd, shop.enemy_drop, death.position);
+        }
+    }
+    for defeated in boss_defeated_events.iter() {
+        spawn_coin(&mut cmd, shop.boss_drop, defeated.position);
+    }
+}
+
+/// Moves coins down the screen, removing those which fall off the bottom
+fn coin_move_sys(
+    mut despawn_queue: ResMut<DespawnQueue>,
+    mut coins: Query<(Entity, &mut Transform), With<Coin>>,
+    windows: Res<Windows>,
+    time: Res<GameTime>) {
+    let bottom = -windows.get_primary().unwrap().height() / 2.0 - COIN_SIZE;
+    for (entity, mut transform) in coins.iter_mut() {
+        transform.translation.y -= COIN_FALL_SPEED * time.delta_seconds();
+        if transform.translation.y < bottom {
+            despawn_queue.despawn(entity);
+        }
+    }
+}
+
+/// Gives coins to the players touching them. Coins have no [`CollisionBox`] so projectiles pass
+/// through them.
+fn coin_collect_sys(
+    mut despawn_queue: ResMut<DespawnQueue>,
+    mut sound_requests: EventWriter<SoundRequest>,
+    coins: Query<(Entity, &Coin, &Transform)>,
+    mut players: Query<(&mut Player, &Transform, &CollisionBox)>) {
+    for (entity, coin, coin_transform) in coins.iter() {
+        if despawn_queue.contains(entity) { continue }
+        let collector = players.iter_mut().find(|(_, transform, collision_box)| {
+            collide(coin_transform.translation, Vec2::new(COIN_SIZE, COIN_SIZE), transform.translation, collision_box.size).is_some()
+        });
+        if let Some((mut player, _, _)) = collector {
+            player.credits += coin.value;
+            despawn_queue.despawn(entity);
+            sound_requests.send(SoundRequest(Sound::Pickup));
+        }
+    }
+}
+
+/// Opens the shop once a shop wave is cleared, trying again each frame until it opens since the
+/// next wave is held back until it closes
+fn shop_open_sys(endless: Option<Res<EndlessRun>>, level: Res<CurrentLevel>, mut state: ResMut<State<GameState>>) {
+    if !endless.map_or(false, |endless| endless.pending_shop) { return }
+    info!("Opening the shop before wave {}", level.wave);
+    if let Err(err) = state.push(GameState::Shop) {
+        warn!("Shop not opened yet: {:?}", err);
+    }
+}
+
+/// Sells requested upgrades the player can afford, applying them to the player's components
+fn shop_purchase_sys(
+    mut shop: ResMut<Shop>,
+    mut purchase_requests: EventReader<PurchaseRequest>,
+    mut sound_requests: EventWriter<SoundRequest>,
+    mut players: Query<(&mut Player, &mut Health, &mut Shooter)>,
+    prefabs: Res<Prefabs>) {
+    for &PurchaseRequest(index) in purchase_requests.iter() {
+        let upgrade = match shop.upgrades.get(index) {
+            Some(upgrade) => upgrade.clone(),
+            None => continue,
+        };
+        let price = match shop.price(&upgrade) {
+            Some(price) => price,
+            None => continue,
+        };
+        let (mut player, mut health, mut shooter) = match players.iter_mut().find(|(player, _, _)| player.index == SHOP_PLAYER) {
+            Some(player) => player,
+            None => continue,
+        };
+        if player.credits < price {
+            debug!("Can't afford {}: {} of {} credits", upgrade.name, player.credits, price);
+            continue
+        }
+
+        player.credits -= price;
+        upgrade.effect.apply(&mut player, &mut health, &mut shooter, &prefabs);
+        *shop.levels.entry(upgrade.id.clone()).or_default() += 1;
+        info!("Bought {} for {} credits", upgrade.name, price);
+        sound_requests.send(SoundRequest(Sound::Pickup));
+    }
+}
+
+/// Purchases can't be undone, so the history from before them can't be rewound into
+fn shop_close_sys(mut rewind: ResMut<Rewind>) {
+    rewind.clear_history();
+}
+
+fn coin_cleanup_sys(mut despawn_queue: ResMut<DespawnQueue>, coins: Query<Entity, With<Coin>>) {
+    for entity in coins.iter() {
+        despawn_queue.despawn(entity);
+    }
+}
+
+pub struct ShopPlugin;
+
+impl Plugin for ShopPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_asset::<ShopDefinition>()
+            .init_asset_loader::<ShopLoader>()
+            .init_resource::<Shop>()
+            .add_event::<PurchaseRequest>()
+            .add_startup_system(shop_load_sys)
+            .add_system(shop_reload_sys)
+            .add_system_set(SystemSet::on_enter(GameState::Playing)
+                .with_system(shop_reset_sys))
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(coin_drop_sys)
+                .with_system(coin_move_sys)
+                .with_system(coin_collect_sys)
+                .with_system(shop_open_sys))
+            .add_system_set(SystemSet::on_update(GameState::Shop)
+                .with_system(shop_purchase_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Shop)
+                .with_system(shop_close_sys))
+            .add_system_set(SystemSet::on_exit(GameState::Playing)
+                .with_system(coin_cleanup_sys));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::PlayerBundle;
+    use crate::prefab::{Prefab, WeaponSpec};
+    use super::*;
+
+    fn upgrade(id: &str, cost: u32, cost_step: u32, max_level: u32, effect: UpgradeEffect) -> Upgrade {
+        Upgrade { id: id.to_string(), name: id.to_string(), cost, cost_step, max_level, effect }
+    }
+
+    fn world(upgrades: Vec<Upgrade>, credits: u32) -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(Shop { upgrades, ..Default::default() });
+        world.insert_resource(Events::<PurchaseRequest>::default());
+        world.insert_resource(Events::<SoundRequest>::default());
+        let mut prefabs = Prefabs::default();
+        prefabs.prefabs.insert("cannon".to_string(), Prefab {
+            weapon: Some(WeaponSpec { projectile: "shell".to_string(), damage: 50 }),
+            ..Default::default()
+        });
+        world.insert_resource(prefabs);
+        let player = world.spawn().insert_bundle(PlayerBundle { player: Player { credits, ..Default::default() }, ..Default::default() }).id();
+        (world, player)
+    }
+
+    fn purchase(world: &mut World, index: usize) {
+        world.get_resource_mut::<Events<PurchaseRequest>>().unwrap().send(PurchaseRequest(index));
+        SystemStage::single_threaded().with_system(shop_purchase_sys).run(world);
+    }
+
+    fn credits(world: &World, player: Entity) -> u32 {
+        world.get::<Player>(player).unwrap().credits
+    }
+
+    #[test]
+    fn affordability() {
+        let (mut world, player) = world(vec![upgrade("armour", 30, 0, 3, UpgradeEffect::MaxHealth(10))], 40);
+        let max_health = world.get::<Health>(player).unwrap().max_health;
+
+        purchase(&mut world, 0);
+        assert_eq!(credits(&world, player), 10);
+        assert_eq!(world.get::<Health>(player).unwrap().max_health, max_health + 10);
+
+        // Ten credits left isn't enough for a second purchase
+        purchase(&mut world, 0);
+        assert_eq!(credits(&world, player), 10);
+        assert_eq!(world.get::<Health>(player).unwrap().max_health, max_health + 10);
+        assert_eq!(world.get_resource::<Shop>().unwrap().levels.get("armour"), Some(&1));
+        let sounds = world.get_resource::<Events<SoundRequest>>().unwrap();
+        assert_eq!(sounds.get_reader().iter(sounds).count(), 1);
+
+        // Requests for upgrades which don't exist are ignored
+        purchase(&mut world, 5);
+        assert_eq!(credits(&world, player), 10);
+    }
+
+    #[test]
+    fn price_scaling() {
+        let (mut world, player) = world(vec![upgrade("speed", 10, 15, 3, UpgradeEffect::MoveSpeed(1.1))], 1000);
+        let shop = world.get_resource::<Shop>().unwrap();
+        assert_eq!(shop.price(&shop.upgrades[0]), Some(10));
+
+        for &expected in [10, 25, 40].iter() {
+            let before = credits(&world, player);
+            purchase(&mut world, 0);
+            assert_eq!(before - credits(&world, player), expected);
+        }
+
+        // Bought up to its max level, so it's no longer for sale
+        let shop = world.get_resource::<Shop>().unwrap();
+        assert_eq!(shop.level(&shop.upgrades[0]), 3);
+        assert_eq!(shop.price(&shop.upgrades[0]), None);
+        purchase(&mut world, 0);
+        assert_eq!(credits(&world, player), 1000 - 75);
+    }
+
+    #[test]
+    fn effects() {
+        let upgrades = vec![
+            upgrade("health", 0, 0, 1, UpgradeEffect::MaxHealth(25)),
+            upgrade("reload", 0, 0, 1, UpgradeEffect::ReloadTime(0.5)),
+            upgrade("magazine", 0, 0, 1, UpgradeEffect::MagazineSize(4)),
+            upgrade("speed", 0, 0, 1, UpgradeEffect::MoveSpeed(1.5)),
+            upgrade("weapon", 0, 0, 1, UpgradeEffect::Weapon("cannon".to_string())),
+            upgrade("pierce", 0, 0, 1, UpgradeEffect::Pierce(2)),
+            upgrade("ricochet", 0, 0, 1, UpgradeEffect::Ricochet(3)),
+            upgrade("homing", 0, 0, 1, UpgradeEffect::Homing { turn_rate: 2. }),
+            upgrade("explosion", 0, 0, 1, UpgradeEffect::Explosion { radius: 40., damage: 20 }),
+            upgrade("fragments", 0, 0, 1, UpgradeEffect::Fragments { count: 5, damage: 8 }),
+        ];
+        let count = upgrades.len();
+        let (mut world, player) = world(upgrades, 0);
+        let health = world.get::<Health>(player).unwrap().clone();
+        let shooter = world.get::<Shooter>(player).unwrap().clone();
+        let move_speed = world.get::<Player>(player).unwrap().move_speed;
+
+        for index in 0..count {
+            purchase(&mut world, index);
+        }
+
+        let new_health = world.get::<Health>(player).unwrap();
+        assert_eq!(new_health.max_health, health.max_health + 25);
+        assert_eq!(new_health.health, health.health + 25);
+
+        let new_shooter = world.get::<Shooter>(player).unwrap();
+        assert_eq!(new_shooter.reload_timer.duration(), shooter.reload_timer.duration().mul_f32(0.5));
+        assert_eq!(new_shooter.magazine_size, shooter.magazine_size + 4);
+        assert_eq!(new_shooter.ammo_count, shooter.ammo_count + 4);
+        assert_eq!(new_shooter.damage, 50);
+        assert_eq!(new_shooter.projectile.as_deref(), Some("shell"));
+        assert_eq!(new_shooter.modifiers.pierce, 2);
+        assert_eq!(new_shooter.modifiers.ricochet, 3);
+        assert_eq!(new_shooter.modifiers.homing.as_ref().map(|homing| homing.turn_rate), Some(2.));
+        let explosion = new_shooter.modifiers.explosion.as_ref().unwrap();
+        assert_eq!((explosion.radius, explosion.damage), (40., 20));
+        let fragments = new_shooter.modifiers.fragments.as_ref().unwrap();
+        assert_eq!((fragments.count, fragments.damage), (5, 8));
+
+        assert_eq!(world.get::<Player>(player).unwrap().move_speed, move_speed * 1.5);
+        assert_eq!(world.get_resource::<Shop>().unwrap().levels.len(), count);
+    }
+}
@@ -0,0 +1,348 @@
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use crate::common::{CollisionBox, Health, Lives, Shooter, Team, Velocity};
+use crate::console::{parse, ConsoleCommandsExt};
+use crate::enemy::{Enemy, EnemyBundle};
+use crate::netplay::NetPlayer;
+use crate::particle::ParticleEmitter;
+use crate::player::{GodMode, Player, PlayerBundle, PlayerInput};
+use crate::projectile::{Projectile, ProjectileBundle};
+
+const BENCH_ITERATIONS: u32     = 100;
+const DEFAULT_BENCH_SIZE: usize = 200;
+/// Budget a snapshot and restore are compared against in the benchmark, one 60 Hz frame
+const FRAME_BUDGET: Duration    = Duration::from_micros(16_667);
+
+/// Reference to an entity from inside a snapshot. Entities the snapshot captured are stored by
+/// index, so references follow them if they are re-spawned by [`WorldSnapshot::restore`].
+#[derive(Debug, Clone, Copy)]
+enum EntityRef {
+    Captured(u32),
+    /// An entity outside the snapshot, which is left as it is
+    External(Entity),
+}
+
+impl EntityRef {
+    fn capture(entity: Entity, indices: &HashMap<Entity, u32>) -> Self {
+        match indices.get(&entity) {
+            Some(index) => EntityRef::Captured(*index),
+            None => EntityRef::External(entity),
+        }
+    }
+
+    fn restore(&self, entities: &[Entity]) -> Entity {
+        match *self {
+            EntityRef::Captured(index) => entities[index as usize],
+            EntityRef::External(entity) => entity,
+        }
+    }
+}
+
+/// A [`Projectile`] with its entity references taken out so they can be remapped
+struct ProjectileRecord {
+    projectile: Projectile,
+    origin: Option<EntityRef>,
+    hits: Vec<EntityRef>,
+}
+
+/// Values of one component type, with the index of the captured entity each belongs to. Entities
+/// only take space in the columns of components they have.
+type Column<T> = Vec<(u32, T)>;
+
+/// The gameplay state of every player, enemy and projectile, which can be restored later
+pub struct WorldSnapshot {
+    /// Entity each index was captured from
+    entities: Vec<Entity>,
+    /// Transform, sprite and texture of each entity, by index. Every captured entity has all three.
+    transforms: Vec<Transform>,
+    sprites: Vec<Sprite>,
+    textures: Vec<Handle<Image>>,
+    players: Column<Player>,
+    inputs: Column<PlayerInput>,
+    enemies: Column<Enemy>,
+    teams: Column<Team>,
+    healths: Column<Health>,
+    lives: Column<Lives>,
+    shooters: Column<Shooter>,
+    velocities: Column<Velocity>,
+    collision_boxes: Column<CollisionBox>,
+    emitters: Column<ParticleEmitter>,
+    god_modes: Column<GodMode>,
+    net_players: Column<NetPlayer>,
+    projectiles: Column<ProjectileRecord>,
+}
+
+type Gameplay = Or<(With<Player>, With<Enemy>, With<Projectile>)>;
+
+fn capture_column<T: Component + Clone>(world: &mut World, indices: &HashMap<Entity, u32>) -> Column<T> {
+    let mut query = world.query::<(Entity, &T)>();
+    query.iter(world)
+        .filter_map(|(entity, value)| indices.get(&entity).map(|index| (*index, value.clone())))
+        .collect()
+}
+
+fn restore_column<T: Component + Clone>(world: &mut World, column: &Column<T>, entities: &[Entity]) {
+    for (index, value) in column.iter() {
+        world.entity_mut(entities[*index as usize]).insert(value.clone());
+    }
+}
+
+/// As [`restore_column`], also removing the component from captured entities which didn't have it
+fn restore_toggled_column<T: Component + Clone>(world: &mut World, column: &Column<T>, entities: &[Entity]) {
+    let with: HashSet<u32> = column.iter().map(|(index, _)| *index).collect();
+    for (index, entity) in entities.iter().enumerate() {
+        if !with.contains(&(index as u32)) {
+            world.entity_mut(*entity).remove::<T>();
+        }
+    }
+    restore_column(world, column, entities);
+}
+
+impl WorldSnapshot {
+    pub fn capture(world: &mut World) -> Self {
+        let mut query = world.query_filtered::<(Entity, &Transform, &Sprite, &Handle<Image>), Gameplay>();
+        let mut entities = Vec::new();
+        let mut transforms = Vec::new();
+        let mut sprites = Vec::new();
+        let mut textures = Vec::new();
+        for (entity, transform, sprite, texture) in query.iter(world) {
+            entities.push(entity);
+            transforms.push(*transform);
+            sprites.push(sprite.clone());
+            textures.push(texture.clone());
+        }
+        let indices: HashMap<Entity, u32> = entities.iter().enumerate().map(|(index, entity)| (*entity, index as u32)).collect();
+
+        let mut query = world.query::<(Entity, &Projectile)>();
+        let projectiles = query.iter(world)
+            .filter_map(|(entity, projectile)| indices.get(&entity).map(|index| (*index, projectile)))
+            .map(|(index, projectile)| {
+                let mut projectile = projectile.clone();
+                let origin = projectile.origin.take().map(|origin| EntityRef::capture(origin, &indices));
+                let hits = projectile.hits.drain(..).map(|entity| EntityRef::capture(entity, &indices)).collect();
+                (index, ProjectileRecord { projectile, origin, hits })
+            })
+            .collect();
+
+        Self {
+            players: capture_column(world, &indices),
+            inputs: capture_column(world, &indices),
+            enemies: capture_column(world, &indices),
+            teams: capture_column(world, &indices),
+            healths: capture_column(world, &indices),
+            lives: capture_column(world, &indices),
+            shooters: capture_column(world, &indices),
+            velocities: capture_column(world, &indices),
+            collision_boxes: capture_column(world, &indices),
+            emitters: capture_column(world, &indices),
+            god_modes: capture_column(world, &indices),
+            net_players: capture_column(world, &indices),
+            projectiles,
+            entities,
+            transforms,
+            sprites,
+            textures,
+        }
+    }
+
+    /// Puts every player, enemy and projectile back as captured. Entities still alive are updated
+    /// in place, so anything attached to them is kept. Those since removed are spawned again, and
+    /// those spawned since are removed. Re-spawned entities get their art back from the art plugin
+    /// like any new entity. Returns the entity now holding each captured entity's state.
+    pub fn restore(&self, world: &mut World) -> HashMap<Entity, Entity> {
+        let captured: HashSet<Entity> = self.entities.iter().copied().collect();
+        let spawned_since: Vec<Entity> = world.query_filtered::<Entity, Gameplay>().iter(world)
+            .filter(|entity| !captured.contains(entity))
+            .collect();
+        for entity in spawned_since {
+            world.entity_mut(entity).despawn_recursive();
+        }
+
+        let entities: Vec<Entity> = self.entities.iter().zip(self.sprites.iter().zip(self.textures.iter()))
+            .map(|(entity, (sprite, texture))| match world.get_entity(*entity) {
+                Some(_) => *entity,
+                None => world.spawn().insert_bundle(SpriteBundle { sprite: sprite.clone(), texture: texture.clone(), ..Default::default() }).id(),
+            })
+            .collect();
+        for (entity, transform) in entities.iter().zip(self.transforms.iter()) {
+            world.entity_mut(*entity).insert_bundle((*transform, GlobalTransform::from(*transform)));
+        }
+
+        restore_column(world, &self.players, &entities);
+        restore_column(world, &self.inputs, &entities);
+        restore_column(world, &self.enemies, &entities);
+        restore_column(world, &self.teams, &entities);
+        restore_column(world, &self.healths, &entities);
+        restore_column(world, &self.lives, &entities);
+        restore_column(world, &self.shooters, &entities);
+        restore_column(world, &self.velocities, &entities);
+        restore_column(world, &self.collision_boxes, &entities);
+        restore_column(world, &self.emitters, &entities);
+        restore_toggled_column(world, &self.god_modes, &entities);
+        restore_column(world, &self.net_players, &entities);
+        for (index, record) in self.projectiles.iter() {
+            let projectile = Projectile {
+                origin: record.origin.map(|origin| origin.restore(&entities)),
+                hits: record.hits.iter().map(|entity| entity.restore(&entities)).collect(),
+                ..record.projectile.clone()
+            };
+            world.entity_mut(entities[*index as usize]).insert(projectile);
+        }
+
+        self.entities.iter().copied().zip(entities.into_iter()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Approximate memory used, in bytes
+    pub fn size(&self) -> usize {
+        fn column<T>(column: &Column<T>) -> usize { column.len() * size_of::<(u32, T)>() }
+        let references: usize = self.projectiles.iter()
+            .map(|(_, record)| record.hits.len() * size_of::<EntityRef>())
+            .sum();
+        self.entities.len() * (size_of::<Entity>() + size_of::<Transform>() + size_of::<Sprite>() + size_of::<Handle<Image>>())
+            + column(&self.players) + column(&self.inputs) + column(&self.enemies) + column(&self.teams) + column(&self.healths)
+            + column(&self.lives) + column(&self.shooters) + column(&self.velocities)
+            + column(&self.collision_boxes) + column(&self.emitters) + column(&self.god_modes) + column(&self.net_players)
+            + column(&self.projectiles) + references
+    }
+}
+
+/// Snapshot taken with the `snapshot save` console command
+#[derive(Default)]
+pub struct SavedSnapshot(pub Option<WorldSnapshot>);
+
+/// A player, and `count` enemies each with a projectile in flight which has hit the player
+fn bench_world(count: usize) -> World {
+    let mut world = World::new();
+    let player = world.spawn().insert_bundle(PlayerBundle::default()).id();
+    let enemies: Vec<Entity> = (0..count)
+        .map(|_| world.spawn().insert_bundle(EnemyBundle::default()).id())
+        .collect();
+    for enemy in enemies.iter() {
+        world.spawn().insert_bundle(ProjectileBundle {
+            projectile: Projectile { origin: Some(*enemy), hits: vec![player], ..Default::default() },
+            ..Default::default()
+        });
+    }
+    world
+}
+
+/// Average time to capture a snapshot and to restore it after every projectile has been removed,
+/// and the snapshot's size
+fn bench_times(count: usize) -> (Duration, Duration, usize) {
+    let mut world = bench_world(count);
+    let (mut capture_time, mut restore_time) = (Duration::ZERO, Duration::ZERO);
+    let mut size = 0;
+    for _ in 0..BENCH_ITERATIONS {
+        let start = Instant::now();
+        let snapshot = WorldSnapshot::capture(&mut world);
+        capture_time += start.elapsed();
+        size = snapshot.size();
+
+        let projectiles: Vec<Entity> = world.query_filtered::<Entity, With<Projectile>>().iter(&world).collect();
+        for entity in projectiles {
+            world.despawn(entity);
+        }
+
+        let start = Instant::now();
+        snapshot.restore(&mut world);
+        restore_time += start.elapsed();
+    }
+
+    (capture_time / BENCH_ITERATIONS, restore_time / BENCH_ITERATIONS, size)
+}
+
+fn bench(count: usize) -> String {
+    let (capture_time, restore_time, size) = bench_times(count);
+    let budget = (capture_time + restore_time).as_secs_f32() / FRAME_BUDGET.as_secs_f32() * 100.;
+    format!(
+        "{} entities, {} KiB: capture {:?}, restore {:?}, {:.1}% of a 60 Hz frame",
+        count * 2 + 1, size / 1024, capture_time, restore_time, budget)
+}
+
+fn snapshot_command(world: &mut World, args: &[&str]) -> Result<String, String> {
+    match args.get(0).copied() {
+        Some("save") => {
+            let snapshot = WorldSnapshot::capture(world);
+            let message = format!("Saved {} entities", snapshot.len());
+            world.get_resource_or_insert_with(SavedSnapshot::default).0 = Some(snapshot);
+            Ok(message)
+        }
+        Some("load") => {
+            let saved = world.get_resource_mut::<SavedSnapshot>().and_then(|mut saved| saved.0.take()).ok_or("Nothing saved")?;
+            saved.restore(world);
+            let message = format!("Restored {} entities", saved.len());
+            world.get_resource_or_insert_with(SavedSnapshot::default).0 = Some(saved);
+            Ok(message)
+        }
+        Some("bench") => {
+            let count = if args.len() > 1 { parse(args, 1, "entity count")? } else { DEFAULT_BENCH_SIZE };
+            Ok(bench(count))
+        }
+        _ => Err(String::from("Expected save, load or bench")),
+    }
+}
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<SavedSnapshot>()
+            .add_console_command("snapshot <save|load|bench> [count]", "Save or restore gameplay state, or time it", snapshot_command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::asset::HandleId;
+    use crate::particle::Effect;
+
+    #[test]
+    fn removed_entities_come_back_whole() {
+        let mut world = World::new();
+        let texture = Handle::<Image>::weak(HandleId::random::<Image>());
+        let player = world.spawn()
+            .insert_bundle(PlayerBundle::default())
+            .insert(texture.clone())
+            .insert_bundle((GodMode, NetPlayer(1)))
+            .id();
+        let snapshot = WorldSnapshot::capture(&mut world);
+
+        world.entity_mut(player).despawn();
+        let restored = snapshot.restore(&mut world)[&player];
+        assert_ne!(restored, player);
+        let entity = world.entity(restored);
+        assert!(entity.contains::<Player>());
+        assert!(entity.contains::<GodMode>());
+        assert!(entity.contains::<ParticleEmitter>());
+        assert_eq!(entity.get::<NetPlayer>(), Some(&NetPlayer(1)));
+        assert_eq!(entity.get::<Handle<Image>>(), Some(&texture));
+    }
+
+    #[test]
+    fn components_added_since_are_removed() {
+        let mut world = World::new();
+        let player = world.spawn().insert_bundle(PlayerBundle::default()).id();
+        let snapshot = WorldSnapshot::capture(&mut world);
+
+        world.entity_mut(player).insert(GodMode).insert(ParticleEmitter::new(Effect::Thruster, Vec2::X, Vec2::ZERO));
+        snapshot.restore(&mut world);
+        assert!(world.get::<GodMode>(player).is_none());
+        assert_eq!(world.get::<ParticleEmitter>(player).unwrap().direction, -Vec2::Y);
+    }
+
+    /// Run with `cargo test --release -- --ignored`, as debug builds are far slower
+    #[test]
+    #[ignore]
+    fn capture_and_restore_fit_in_a_frame() {
+        let (capture_time, restore_time, _) = bench_times(DEFAULT_BENCH_SIZE);
+        assert!(capture_time + restore_time < FRAME_BUDGET / 4,
+            "capture {:?} and restore {:?} take over a quarter of a frame", capture_time, restore_time);
+    }
+}
@@ -0,0 +1,341 @@
+use std::io::Cursor;
+use bevy::asset::{AssetServerSettings, FileAssetIo};
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use crate::boss::{Boss, BossDefeatedEvent, HitZone};
+use crate::common::{DeathEvent, Victim};
+use crate::enemy::Enemy;
+use crate::manager::{CurrentLevel, GameState};
+use crate::player::Player;
+use crate::projectile::{Projectile, ProjectileHitEvent};
+use crate::settings::Settings;
+
+/// Sound effects played in response to gameplay events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sound {
+    PlayerFire,
+    EnemyFire,
+    Hit,
+    EnemyDeath,
+    PlayerDamage,
+    Pickup,
+    WaveStart,
+    GameOver,
+}
+
+impl Sound {
+    pub const ALL: [Sound; 8] = [Sound::PlayerFire, Sound::EnemyFire, Sound::Hit, Sound::EnemyDeath, Sound::PlayerDamage, Sound::Pickup, Sound::WaveStart, Sound::GameOver];
+
+    fn path(&self) -> &'static str {
+        match self {
+            Sound::PlayerFire   => "sounds/player_fire.ogg",
+            Sound::EnemyFire    => "sounds/enemy_fire.ogg",
+            Sound::Hit          => "sounds/hit.ogg",
+            Sound::EnemyDeath   => "sounds/enemy_death.ogg",
+            Sound::PlayerDamage => "sounds/player_damage.ogg",
+            Sound::Pickup       => "sounds/pickup.ogg",
+            Sound::WaveStart    => "sounds/wave_start.ogg",
+            Sound::GameOver     => "sounds/game_over.ogg",
+        }
+    }
+}
+
+/// Looped background tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Music {
+    Menu,
+    Level,
+    Boss,
+}
+
+impl Music {
+    pub const ALL: [Music; 3] = [Music::Menu, Music::Level, Music::Boss];
+
+    fn path(&self) -> &'static str {
+        match self {
+            Music::Menu  => "music/menu.ogg",
+            Music::Level => "music/level.ogg",
+            Music::Boss  => "music/boss.ogg",
+        }
+    }
+}
+
+/// Asks for a sound effect to be played. Gameplay code only sends these, so which sounds a
+/// situation produces can be checked without an audio device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoundRequest(pub Sound);
+
+/// Track which should currently be playing, or `None` for silence
+#[derive(Default, PartialEq)]
+pub struct MusicTrack(pub Option<Music>);
+
+struct AudioHandles {
+    sounds: HashMap<Sound, Handle<AudioSource>>,
+    music: HashMap<Music, Handle<AudioSource>>,
+}
+
+/// Audio device output. Only present if a device could be opened.
+struct AudioOutput {
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+    music: Option<(Music, Sink)>,
+}
+
+/// Loads the sounds and music tracks. Any missing from the asset folder are left out and stay
+/// silent, rather than failing to load.
+fn audio_load_sys(mut cmd: Commands, asset_server: Res<AssetServer>, asset_settings: Res<AssetServerSettings>) {
+    let folder = FileAssetIo::get_root_path().join(&asset_settings.asset_folder);
+    let present = |path: &str| {
+        let present = folder.join(path).is_file();
+        if !present { info!("No {} asset, it will not be played", path) }
+        present
+    };
+    cmd.insert_resource(AudioHandles {
+        sounds: Sound::ALL.iter().filter(|sound| present(sound.path())).map(|sound| (*sound, asset_server.load(sound.path()))).collect(),
+        music: Music::ALL.iter().filter(|music| present(music.path())).map(|music| (*music, asset_server.load(music.path()))).collect(),
+    });
+}
+
+/// Requests sounds for projectiles fired, hits, deaths and new waves. Each sound is requested at
+/// most once per frame so volleys don't stack.
+fn gameplay_sound_sys(
+    mut last_wave: Local<u32>,
+    mut sound_requests: EventWriter<SoundRequest>,
+    fired: Query<&Projectile, Added<Projectile>>,
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    mut death_events: EventReader<DeathEvent>,
+    mut boss_defeated_events: EventReader<BossDefeatedEvent>,
+    players: Query<Entity, With<Player>>,
+    enemies: Query<Entity, Or<(With<Enemy>, With<HitZone>)>>,
+    level: Res<CurrentLevel>) {
+    let mut sounds = HashSet::default();
+    let is_player = |entity: Entity| players.get(entity).is_ok();
+
+    for projectile in fired.iter() {
+        sounds.insert(if projectile.owner.is_player() { Sound::PlayerFire } else { Sound::EnemyFire });
+    }
+    for hit in hit_events.iter() {
+        if is_player(hit.other) { sounds.insert(Sound::PlayerDamage); }
+        else if enemies.get(hit.other).is_ok() { sounds.insert(Sound::Hit); }
+    }
+    for death in death_events.iter() {
+        if let Victim::Enemy(_) = death.victim { sounds.insert(Sound::EnemyDeath); }
+    }
+    if boss_defeated_events.iter().next().is_some() {
+        sounds.insert(Sound::EnemyDeath);
+    }
+    if level.is_added() || level.wave != *last_wave {
+        *last_wave = level.wave;
+        sounds.insert(Sound::WaveStart);
+    }
+
+    for sound in sounds {
+        sound_requests.send(SoundRequest(sound));
+    }
+}
+
+fn game_over_sound_sys(mut sound_requests: EventWriter<SoundRequest>) {
+    sound_requests.send(SoundRequest(Sound::GameOver));
+}
+
+/// Picks the background track for the current state
+fn music_select_sys(state: Res<State<GameState>>, bosses: Query<&Boss>, mut track: ResMut<MusicTrack>) {
+    let music = match state.current() {
+        GameState::MainMenu | GameState::LevelSelect | GameState::HighScores | GameState::Settings => Some(Music::Menu),
+        GameState::Playing if !bosses.is_empty() => Some(Music::Boss),
+        GameState::Playing | GameState::Online => Some(Music::Level),
+        GameState::Paused | GameState::Rewinding | GameState::Shop => return,
+        GameState::GameOver => None,
+    };
+    if track.0 != music {
+        track.0 = music;
+    }
+}
+
+fn decode(source: &AudioSource) -> Option<Decoder<Cursor<AudioSource>>> {
+    Decoder::new(Cursor::new(source.clone())).ok()
+}
+
+/// Plays requested sounds and the current music track on the audio device
+fn audio_playback_sys(
+    output: Option<NonSendMut<AudioOutput>>,
+    mut sound_requests: EventReader<SoundRequest>,
+    track: Res<MusicTrack>,
+    settings: Res<Settings>,
+    handles: Res<AudioHandles>,
+    sources: Res<Assets<AudioSource>>) {
+    let mut output = match output {
+        Some(output) => output,
+        None => return,
+    };
+    let effects_volume = settings.audio.master * settings.audio.effects;
+    let music_volume = settings.audio.master * settings.audio.music;
+
+    for SoundRequest(sound) in sound_requests.iter() {
+        if effects_volume <= 0.0 { continue }
+        let decoder = handles.sounds.get(sound).and_then(|handle| sources.get(handle)).and_then(decode);
+        if let Some(decoder) = decoder {
+            if let Err(err) = output.handle.play_raw(decoder.convert_samples().amplify(effects_volume)) {
+                warn!("Could not play {:?}: {}", sound, err);
+            }
+        }
+    }
+
+    let playing = output.music.as_ref().map(|(music, _)| *music);
+    if playing != track.0 {
+        if let Some((_, sink)) = output.music.take() {
+            sink.stop();
+        }
+        if let Some(music) = track.0 {
+            // The track may still be loading, in which case this is retried next frame
+            let decoder = handles.music.get(&music).and_then(|handle| sources.get(handle)).and_then(decode);
+            if let (Some(decoder), Ok(sink)) = (decoder, Sink::try_new(&output.handle)) {
+                sink.set_volume(music_volume);
+                sink.append(decoder.repeat_infinite());
+                output.music = Some((music, sink));
+            }
+        }
+    } else if settings.is_changed() {
+        if let Some((_, sink)) = &output.music {
+            sink.set_volume(music_volume);
+        }
+    }
+}
+
+pub struct SoundPlugin;
+
+impl Plugin for SoundPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_event::<SoundRequest>()
+            .init_resource::<MusicTrack>()
+            .add_startup_system(audio_load_sys)
+            .add_system(music_select_sys)
+            .add_system(audio_playback_sys)
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(gameplay_sound_sys))
+            .add_system_set(SystemSet::on_enter(GameState::GameOver)
+                .with_system(game_over_sound_sys));
+
+        match OutputStream::try_default() {
+            Ok((stream, handle)) => { app.insert_non_send_resource(AudioOutput { _stream: stream, handle, music: None }); }
+            Err(err) => warn!("No audio device, sound is disabled: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boss::BossKind;
+    use crate::enemy::EnemyKind;
+    use crate::projectile::Owner;
+
+    fn world() -> (World, SystemStage) {
+        let mut world = World::new();
+        world.insert_resource(CurrentLevel { handle: Default::default(), wave: 1, spawned: true, boss_spawned: false, pending: false });
+        world.insert_resource(Events::<SoundRequest>::default());
+        world.insert_resource(Events::<ProjectileHitEvent>::default());
+        world.insert_resource(Events::<DeathEvent>::default());
+        world.insert_resource(Events::<BossDefeatedEvent>::default());
+        let mut stage = SystemStage::single_threaded().with_system(gameplay_sound_sys);
+        // The level resource counts as added on the first run
+        expect(run(&mut world, &mut stage), &[Sound::WaveStart]);
+        (world, stage)
+    }
+
+    /// Runs the sound system once, returning the sounds requested
+    fn run(world: &mut World, stage: &mut SystemStage) -> Vec<Sound> {
+        stage.run(world);
+        world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().update();
+        world.get_resource_mut::<Events<DeathEvent>>().unwrap().update();
+        world.get_resource_mut::<Events<BossDefeatedEvent>>().unwrap().update();
+        let mut requests = world.get_resource_mut::<Events<SoundRequest>>().unwrap();
+        let sounds = requests.drain().map(|request| request.0).collect();
+        sounds
+    }
+
+    /// Checks each expected sound was requested exactly once, in any order
+    fn expect(sounds: Vec<Sound>, expected: &[Sound]) {
+        assert_eq!(sounds.len(), expected.len(), "expected {:?}, got {:?}", expected, sounds);
+        assert!(expected.iter().all(|sound| sounds.contains(sound)), "expected {:?}, got {:?}", expected, sounds);
+    }
+
+    fn hit(world: &mut World, other: Entity, owner: Owner) {
+        world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().send(ProjectileHitEvent {
+            projectile: other, other, origin: None, owner, damage: 10, position: Vec3::ZERO,
+        });
+    }
+
+    fn death(world: &mut World, entity: Entity, victim: Victim) {
+        world.get_resource_mut::<Events<DeathEvent>>().unwrap().send(DeathEvent { entity, victim, position: Vec3::ZERO, life_lost: false });
+    }
+
+    #[test]
+    fn fired_projectiles() {
+        let (mut world, mut stage) = world();
+        world.spawn().insert(Projectile { owner: Owner::player(0), ..Default::default() });
+        world.spawn().insert(Projectile::default());
+        world.spawn().insert(Projectile::default());
+        expect(run(&mut world, &mut stage), &[Sound::PlayerFire, Sound::EnemyFire]);
+        expect(run(&mut world, &mut stage), &[]);
+    }
+
+    #[test]
+    fn hits() {
+        let (mut world, mut stage) = world();
+        let player = world.spawn().insert(Player::default()).id();
+        let enemy = world.spawn().insert(Enemy::default()).id();
+        let other = world.spawn().insert(Enemy::default()).id();
+
+        hit(&mut world, enemy, Owner::player(0));
+        hit(&mut world, other, Owner::player(0));
+        expect(run(&mut world, &mut stage), &[Sound::Hit]);
+
+        hit(&mut world, player, Owner::default());
+        expect(run(&mut world, &mut stage), &[Sound::PlayerDamage]);
+    }
+
+    #[test]
+    fn deaths() {
+        let (mut world, mut stage) = world();
+        let player = world.spawn().insert(Player::default()).id();
+        let enemy = world.spawn().insert(Enemy::default()).id();
+
+        death(&mut world, player, Victim::Player);
+        expect(run(&mut world, &mut stage), &[]);
+
+        death(&mut world, enemy, Victim::Enemy(EnemyKind::Grunt));
+        expect(run(&mut world, &mut stage), &[Sound::EnemyDeath]);
+
+        // The enemy is counted even if it is gone by the time the event is read
+        world.despawn(enemy);
+        death(&mut world, enemy, Victim::Enemy(EnemyKind::Grunt));
+        expect(run(&mut world, &mut stage), &[Sound::EnemyDeath]);
+
+        world.get_resource_mut::<Events<BossDefeatedEvent>>().unwrap().send(BossDefeatedEvent {
+            boss: enemy, kind: BossKind::ALL[0], position: Vec3::ZERO,
+        });
+        expect(run(&mut world, &mut stage), &[Sound::EnemyDeath]);
+    }
+
+    #[test]
+    fn new_wave() {
+        let (mut world, mut stage) = world();
+        world.get_resource_mut::<CurrentLevel>().unwrap().pending = true;
+        expect(run(&mut world, &mut stage), &[]);
+
+        let mut level = world.get_resource_mut::<CurrentLevel>().unwrap();
+        level.wave = 2;
+        level.pending = false;
+        expect(run(&mut world, &mut stage), &[Sound::WaveStart]);
+
+        world.get_resource_mut::<CurrentLevel>().unwrap().boss_spawned = true;
+        expect(run(&mut world, &mut stage), &[]);
+
+        // A new level starts over at its first wave
+        world.remove_resource::<CurrentLevel>();
+        world.insert_resource(CurrentLevel { handle: Default::default(), wave: 1, spawned: false, boss_spawned: false, pending: false });
+        expect(run(&mut world, &mut stage), &[Sound::WaveStart]);
+    }
+}
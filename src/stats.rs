@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use serde_json::json;
+use crate::boss::{BossDefeatedEvent, HitZone};
+use crate::common::{DeathEvent, GameTime, Victim};
+use crate::enemy::Enemy;
+use crate::manager::{CurrentLevel, GameState, RunResult};
+use crate::player::{GodMode, Player};
+use crate::projectile::{Projectile, ProjectileHitEvent};
+use crate::settings::{config_dir, Difficulty, Settings};
+
+const STATS_FILE: &str = "stats.jsonl";
+/// Most gameplay seconds between kills for them to count towards the same combo
+const COMBO_WINDOW: f32 = 2.;
+
+/// Statistics gathered over the current or most recent run. Waves follow [`CurrentLevel::wave`],
+/// so a campaign level counts its enemies and its boss fight as one wave each.
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    pub difficulty: Difficulty,
+    /// Projectiles fired by players
+    pub shots: u32,
+    /// Player projectiles which hit an enemy or boss hit zone. Piercing and explosive shots count
+    /// once however many they hit.
+    pub hits: u32,
+    /// Damage dealt to players, not counting any taken with god mode on
+    pub damage_taken: i32,
+    /// Kills by enemy kind or boss name, such as `enemy_grunt`
+    pub kills: BTreeMap<String, u32>,
+    /// Gameplay seconds spent on each wave, in order. The last wave is added when the run ends.
+    pub wave_times: Vec<f32>,
+    /// Waves finished without a player taking damage
+    pub flawless_waves: u32,
+    /// Most kills made in a row, each within [`COMBO_WINDOW`] of the last, without taking damage
+    pub max_combo: u32,
+    combo: u32,
+    since_kill: f32,
+    wave: u32,
+    wave_time: f32,
+    wave_damage: i32,
+    hit_projectiles: HashSet<Entity>,
+}
+
+impl RunStats {
+    pub fn new(difficulty: Difficulty) -> Self {
+        Self {
+            difficulty,
+            shots: 0,
+            hits: 0,
+            damage_taken: 0,
+            kills: BTreeMap::new(),
+            wave_times: Vec::new(),
+            flawless_waves: 0,
+            max_combo: 0,
+            combo: 0,
+            since_kill: 0.,
+            wave: 0,
+            wave_time: 0.,
+            wave_damage: 0,
+            hit_projectiles: HashSet::default(),
+        }
+    }
+
+    /// Fraction of shots which hit, from 0 to 1
+    pub fn accuracy(&self) -> f32 {
+        if self.shots == 0 { return 0. }
+        self.hits as f32 / self.shots as f32
+    }
+
+    /// Counts a hit by `projectile`, unless it has already hit something
+    pub fn add_hit(&mut self, projectile: Entity) {
+        if self.hit_projectiles.insert(projectile) {
+            self.hits += 1;
+        }
+    }
+
+    pub fn total_kills(&self) -> u32 {
+        self.kills.values().sum()
+    }
+
+    /// Gameplay seconds spent over every finished wave
+    pub fn total_time(&self) -> f32 {
+        self.wave_times.iter().sum()
+    }
+
+    pub fn add_kill(&mut self, name: &str) {
+        *self.kills.entry(String::from(name)).or_default() += 1;
+        self.combo = if self.since_kill <= COMBO_WINDOW { self.combo + 1 } else { 1 };
+        self.since_kill = 0.;
+        self.max_combo = self.max_combo.max(self.combo);
+    }
+
+    pub fn add_damage(&mut self, damage: i32) {
+        self.damage_taken += damage;
+        self.wave_damage += damage;
+        self.combo = 0;
+    }
+
+    /// Advances the wave and combo timers, starting a new wave timer when `wave` changes
+    pub fn tick(&mut self, delta: f32, wave: u32) {
+        if wave != self.wave {
+            self.finish_wave();
+            self.wave = wave;
+        }
+        self.wave_time += delta;
+        self.since_kill += delta;
+    }
+
+    /// Records the time spent on the current wave, and whether it was flawless, if it has started
+    pub fn finish_wave(&mut self) {
+        if self.wave > 0 {
+            self.wave_times.push(std::mem::take(&mut self.wave_time));
+            if std::mem::take(&mut self.wave_damage) == 0 {
+                self.flawless_waves += 1;
+            }
+        }
+        self.wave = 0;
+    }
+
+    /// One line of JSON describing the run, for the stats file
+    pub fn to_json(&self, run: &RunResult) -> String {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        json!({
+            "timestamp": timestamp,
+            "difficulty": format!("{:?}", self.difficulty),
+            "score": run.score,
+            "wave": run.wave,
+            "completed": run.completed,
+            "shots": self.shots,
+            "hits": self.hits,
+            "accuracy": self.accuracy(),
+            "damage_taken": self.damage_taken,
+            "kills": self.kills,
+            "wave_times": self.wave_times,
+            "flawless_waves": self.flawless_waves,
+            "max_combo": self.max_combo,
+        }).to_string()
+    }
+
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(STATS_FILE))
+    }
+
+    /// Appends the run to the stats file in [`config_dir`]
+    pub fn append(&self, run: &RunResult) -> Result<(), String> {
+        let path = RunStats::path().ok_or_else(|| String::from("no config directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).map_err(|err| err.to_string())?;
+        writeln!(file, "{}", self.to_json(run)).map_err(|err| err.to_string())?;
+        info!("Appended run stats to {}", path.display());
+        Ok(())
+    }
+}
+
+impl Default for RunStats {
+    fn default() -> Self {
+        RunStats::new(Difficulty::Normal)
+    }
+}
+
+/// Runs when entering [`GameState::GameOver`], once the run's stats are final
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct StatsReportLabel;
+
+fn stats_reset_sys(mut cmd: Commands, settings: Res<Settings>) {
+    cmd.insert_resource(RunStats::new(settings.difficulty));
+}
+
+/// Counts shots, hits, damage and kills from gameplay events
+pub(crate) fn stats_collect_sys(
+    mut stats: ResMut<RunStats>,
+    time: Res<GameTime>,
+    level: Res<CurrentLevel>,
+    fired: Query<&Projectile, Added<Projectile>>,
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    mut death_events: EventReader<DeathEvent>,
+    mut boss_defeated_events: EventReader<BossDefeatedEvent>,
+    players: Query<Option<&GodMode>, With<Player>>,
+    enemies: Query<(), With<Enemy>>,
+    zones: Query<(), With<HitZone>>) {
+    stats.tick(time.delta_seconds(), level.wave);
+    stats.shots += fired.iter().filter(|projectile| projectile.owner.is_player()).count() as u32;
+
+    for hit in hit_events.iter() {
+        if hit.owner.is_player() && (enemies.get(hit.other).is_ok() || zones.get(hit.other).is_ok()) {
+            stats.add_hit(hit.projectile);
+        }
+        if let Ok(None) = players.get(hit.other) {
+            stats.add_damage(hit.damage);
+        }
+    }
+    for death in death_events.iter() {
+        if let Victim::Enemy(kind) = death.victim {
+            stats.add_kill(kind.name());
+        }
+    }
+    for defeated in boss_defeated_events.iter() {
+        stats.add_kill(&format!("boss_{:?}", defeated.kind).to_lowercase());
+    }
+}
+
+/// Finishes the run's stats and appends them to the stats file
+fn stats_report_sys(mut stats: ResMut<RunStats>, run: Res<RunResult>) {
+    stats.finish_wave();
+    info!(
+        "Run stats: {} shots, {:.0}% accuracy, {} damage taken, {} kills, max combo {}",
+        stats.shots, stats.accuracy() * 100., stats.damage_taken, stats.total_kills(), stats.max_combo);
+    if let Err(err) = stats.append(&run) {
+        warn!("Could not save run stats: {}", err);
+    }
+}
+
+pub struct StatsPlugin;
+
+impl Plugin for StatsPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<RunStats>()
+            .add_system_set(SystemSet::on_enter(GameState::Playing)
+                .with_system(stats_reset_sys))
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(stats_collect_sys))
+            .add_system_set(SystemSet::on_enter(GameState::GameOver)
+                .with_system(stats_report_sys.label(StatsReportLabel)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{despawn_queue_sys, health_event_sys, DespawnQueue, Health};
+    use crate::enemy::{EnemyBundle, EnemyKind};
+    use crate::projectile::Owner;
+    use super::*;
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.insert_resource(RunStats::default());
+        world.insert_resource(GameTime::default());
+        world.insert_resource(CurrentLevel { handle: Default::default(), wave: 1, spawned: true, boss_spawned: false, pending: false });
+        world.insert_resource(DespawnQueue::default());
+        world.insert_resource(Events::<ProjectileHitEvent>::default());
+        world.insert_resource(Events::<DeathEvent>::default());
+        world.insert_resource(Events::<BossDefeatedEvent>::default());
+        world
+    }
+
+    fn hit(world: &mut World, projectile: Entity, other: Entity) {
+        world.get_resource_mut::<Events<ProjectileHitEvent>>().unwrap().send(ProjectileHitEvent {
+            projectile, other, origin: None, owner: Owner::player(0), damage: 10, position: Vec3::ZERO,
+        });
+    }
+
+    #[test]
+    fn hits_count_projectiles() {
+        let mut world = world();
+        let enemies: Vec<Entity> = (0..3).map(|_| world.spawn().insert_bundle(EnemyBundle::default()).id()).collect();
+        let piercing = world.spawn().insert(Projectile { owner: Owner::player(0), ..Default::default() }).id();
+        let plain = world.spawn().insert(Projectile { owner: Owner::player(0), ..Default::default() }).id();
+
+        for enemy in &enemies {
+            hit(&mut world, piercing, *enemy);
+        }
+        hit(&mut world, plain, enemies[0]);
+        SystemStage::single_threaded().with_system(stats_collect_sys).run(&mut world);
+
+        let stats = world.get_resource::<RunStats>().unwrap();
+        assert_eq!((stats.shots, stats.hits), (2, 2));
+        assert_eq!(stats.accuracy(), 1.);
+    }
+
+    #[test]
+    fn kill_read_after_despawn() {
+        let mut world = world();
+        let enemy = world.spawn().insert_bundle(EnemyBundle { enemy: Enemy { kind: EnemyKind::Sniper, ..Default::default() }, ..Default::default() }).id();
+        world.get_mut::<Health>(enemy).unwrap().health = 0;
+
+        SystemStage::single_threaded().with_system(health_event_sys).run(&mut world);
+        despawn_queue_sys(&mut world);
+        assert!(world.get_entity(enemy).is_none());
+
+        SystemStage::single_threaded().with_system(stats_collect_sys).run(&mut world);
+        let stats = world.get_resource::<RunStats>().unwrap();
+        assert_eq!(stats.kills.get("enemy_sniper"), Some(&1));
+    }
+}